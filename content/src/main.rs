@@ -10,7 +10,7 @@ use rustcraft_modlib::{
     world::{
         block::{data::AddBlock, BlockRegistryPlugin},
         generation::{WorldGenPlugin, WorldGenExtensionFns, noise::SimpleNoiseLayer2D},
-        chunk::{events::LoadChunkMessage, ChunkedWorldPlugin, meshing::{MESHING_PASSES}},
+        chunk::{ChunkedWorldPlugin, meshing::{MESHING_PASSES}, region::WorldSave, loader::ChunkLoader},
     },
     debug::DebugMenuPlugin, noise_rs::Perlin
 };
@@ -31,6 +31,7 @@ fn main() {
     app.add_plugin(BlockRegistryPlugin);
     app.add_plugin(ChunkedWorldPlugin);
     app.add_plugin(WorldGenPlugin);
+    app.insert_resource(WorldSave::new("world"));
 
     app.add_block(blocks::defs::water());
     app.add_block(blocks::defs::dirt());
@@ -88,7 +89,6 @@ fn wireframe_toggle_system(
 
 fn funny_startup_system(
     mut commands: Commands,
-    mut event_writer: EventWriter<LoadChunkMessage>,
 ) {
     const HALF_SIZE: f32 = 10.0;
     commands.spawn(DirectionalLightBundle {
@@ -117,12 +117,6 @@ fn funny_startup_system(
     commands.spawn((
         Camera3dBundle::default(),
         FlyCam,
+        ChunkLoader::new(12.0),
     ));
-    for x in -16..16 {
-        for y in -4..4 {
-            for z in -16..16 {
-                event_writer.send(LoadChunkMessage(IVec3 { x, y, z }));
-            }
-        }
-    }
 }
\ No newline at end of file
@@ -1,4 +1,4 @@
-use bevy::{prelude::IVec3, math::DVec3};
+use bevy::math::DVec3;
 use rustcraft_modlib::world::{
     generation::{
         generator::{
@@ -8,8 +8,9 @@ use rustcraft_modlib::world::{
     },
     chunk::{
         Chunk,
-        CHUNK_SIZE, CHUNK_SIZE_I32,
+        CHUNK_SIZE,
     },
+    position::ChunkPos,
     block::{
         BlockId,
         Block, registry::{BLOCK_REGISTRY, BlockRegistryInternal},
@@ -35,20 +36,22 @@ impl WorldGeneratorPass for BaseTerrainPass {
         }
     }
 
-    fn chunk_pass(&self, pos: IVec3, blocks: &BlockRegistryInternal, worldgen_data: &WorldGenerationInternal, chunk: &mut Chunk) {
+    fn chunk_pass(&self, pos: ChunkPos, blocks: &BlockRegistryInternal, worldgen_data: &WorldGenerationInternal, chunk: &mut Chunk) {
         // Block types
-        let water = Block::Generic(blocks.get_by_string_id("rustcraft_water").unwrap().0);
-        let grass = Block::Generic(blocks.get_by_string_id("rustcraft_grass").unwrap().0);
-        let dirt = Block::Generic(blocks.get_by_string_id("rustcraft_dirt").unwrap().0);
-        let stone = Block::Generic(blocks.get_by_string_id("rustcraft_stone").unwrap().0);
+        let water = Block::Generic(blocks.get_default_state_by_string_id("rustcraft_water").unwrap());
+        let grass = Block::Generic(blocks.get_default_state_by_string_id("rustcraft_grass").unwrap());
+        let dirt = Block::Generic(blocks.get_default_state_by_string_id("rustcraft_dirt").unwrap());
+        let stone = Block::Generic(blocks.get_default_state_by_string_id("rustcraft_stone").unwrap());
+
+        let origin = pos.origin();
 
         for x in 0..CHUNK_SIZE {
         for y in 0..CHUNK_SIZE {
         for z in 0..CHUNK_SIZE {
             let dvec = DVec3 {
-                x: ((pos.x * CHUNK_SIZE_I32) + x as i32) as f64,
-                z: ((pos.y * CHUNK_SIZE_I32) + y as i32) as f64,
-                y: ((pos.z * CHUNK_SIZE_I32) + z as i32) as f64,
+                x: (origin.x + x as i32) as f64,
+                z: (origin.y + y as i32) as f64,
+                y: (origin.z + z as i32) as f64,
             };
             
             let height = worldgen_data.get_noise_layer(NOISE_LAYER_HEIGHT).unwrap().get_value(dvec).round();
@@ -5,4 +5,5 @@ pub use rand;
 
 pub mod world;
 pub mod debug;
-pub mod attributes;
\ No newline at end of file
+pub mod attributes;
+pub mod sync;
\ No newline at end of file
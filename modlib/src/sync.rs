@@ -0,0 +1,65 @@
+//! Helpers for the `RwLock`s around the global registries, and other locks shared with tasks.
+
+use std::sync::{Mutex, MutexGuard, RwLock, RwLockReadGuard, RwLockWriteGuard, PoisonError};
+
+/// Locks an `RwLock` even if it's poisoned.
+///
+/// A lock is poisoned when a thread panics while holding it for writing. The registries are only written to while
+/// adding content at startup, and a panicking pass in a generation or meshing task must not take every later task
+/// down with it, so the registries keep being used as they are instead of panicking again.
+pub trait RecoverPoison<T> {
+    fn read_or_recover(&self) -> RwLockReadGuard<'_, T>;
+    fn write_or_recover(&self) -> RwLockWriteGuard<'_, T>;
+}
+
+impl<T> RecoverPoison<T> for RwLock<T> {
+    fn read_or_recover(&self) -> RwLockReadGuard<'_, T> {
+        self.read().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn write_or_recover(&self) -> RwLockWriteGuard<'_, T> {
+        self.write().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+/// Locks a `Mutex` even if it's poisoned, for the same reasons as `RecoverPoison`.
+pub trait RecoverMutexPoison<T> {
+    fn lock_or_recover(&self) -> MutexGuard<'_, T>;
+}
+
+impl<T> RecoverMutexPoison<T> for Mutex<T> {
+    fn lock_or_recover(&self) -> MutexGuard<'_, T> {
+        self.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{sync::Arc, thread};
+    use super::*;
+
+    #[test]
+    fn poisoned_locks_can_still_be_used() {
+        let lock = Arc::new(RwLock::new(1));
+        let poisoner = lock.clone();
+        let _ = thread::spawn(move || {
+            let _guard = poisoner.write().unwrap();
+            panic!("poisoning the lock");
+        }).join();
+
+        assert!(lock.is_poisoned());
+        *lock.write_or_recover() += 1;
+        assert_eq!(*lock.read_or_recover(), 2);
+
+        let mutex = Arc::new(Mutex::new(1));
+        let poisoner = mutex.clone();
+        let _ = thread::spawn(move || {
+            let _guard = poisoner.lock().unwrap();
+            panic!("poisoning the mutex");
+        }).join();
+
+        assert!(mutex.is_poisoned());
+        *mutex.lock_or_recover() += 1;
+        assert_eq!(*mutex.lock_or_recover(), 2);
+    }
+}
@@ -2,7 +2,7 @@ use std::{ops::Range, collections::BTreeMap, hash::Hash};
 use bevy::prelude::{App, ResMut, Color};
 use crate::{world::chunk::meshing::MeshingVisibility, attributes::{AttributeKind, AttributeValue}};
 
-use super::registry::Blocks;
+use super::{registry::Blocks, state::BlockProperty};
 
 /// Storage for BlockAttributes.
 #[derive(Clone)]
@@ -18,6 +18,12 @@ pub struct BlockData {
     pub string_identifier: &'static str,
     pub block_visibility: MeshingVisibility,
     attributes: BTreeMap<u32, AttributeValue>,
+    /// The properties of this block's states, in the order they're encoded in a `BlockStateId`.
+    properties: Vec<BlockProperty>,
+    /// Which heightmaps the block counts towards, as `HeightmapKind` flags. Worked out by the block registry when the block is added.
+    heightmap_flags: u8,
+    /// How much block light the block emits. Worked out by the block registry when the block is added.
+    light_emission: u8,
 }
 
 impl BlockData {
@@ -36,12 +42,22 @@ impl BlockData {
     /// Marker for the `LiquidMesher` to know to draw this block.
     pub const ATTRIBUTE_USE_LIQUID_MESHER: BlockAttribute =
         BlockAttribute::new("engine_use_liquid_mesher", 4, AttributeKind::None);
+    /// Whether the block stops movement, which decides if it counts towards `HeightmapKind::MotionBlocking`.
+    /// Without this attribute, every block except air and liquids blocks movement.
+    pub const ATTRIBUTE_MOTION_BLOCKING: BlockAttribute =
+        BlockAttribute::new("engine_motion_blocking", 5, AttributeKind::Boolean);
+    /// How much block light the block emits, from 0 to `MAX_LIGHT`. Higher values are clamped.
+    pub const ATTRIBUTE_LIGHT_EMISSION: BlockAttribute =
+        BlockAttribute::new("engine_light_emission", 6, AttributeKind::Uint16);
 
     pub fn new(string_identifier: &'static str, block_visibility: MeshingVisibility) -> Self {
         Self {
             string_identifier,
             block_visibility,
             attributes: BTreeMap::new(),
+            properties: vec![],
+            heightmap_flags: 0,
+            light_emission: 0,
         }
     }
 
@@ -64,6 +80,37 @@ impl BlockData {
         self.attributes.insert(attribute.id, value);
     }
 
+    /// Adds a property to this block's states. Panics if the block already has a property with the same name.
+    pub fn add_property(&mut self, property: BlockProperty) {
+        if self.properties.iter().any(|existing| existing.name == property.name) {
+            panic!("Failed to add property. {} already has a property named {}", self.string_identifier, property.name);
+        }
+
+        self.properties.push(property);
+    }
+
+    pub fn get_properties(&self) -> &[BlockProperty] {
+        &self.properties
+    }
+
+    /// Which heightmaps the block counts towards, as `HeightmapKind` flags.
+    pub(crate) fn get_heightmap_flags(&self) -> u8 {
+        self.heightmap_flags
+    }
+
+    pub(crate) fn set_heightmap_flags(&mut self, flags: u8) {
+        self.heightmap_flags = flags;
+    }
+
+    /// How much block light the block emits, from 0 to `MAX_LIGHT`.
+    pub(crate) fn get_light_emission(&self) -> u8 {
+        self.light_emission
+    }
+
+    pub(crate) fn set_light_emission(&mut self, emission: u8) {
+        self.light_emission = emission;
+    }
+
     #[must_use]
     pub(crate) fn get_attribute(&self, attribute: BlockAttribute) -> Option<&AttributeValue> {
         self.attributes.get(&attribute.id)
@@ -100,7 +147,7 @@ impl AddBlock for App {
     /// Adds a new block type. Shorthand for
     /// 
     /// ```rs
-    /// BLOCK_REGISTRY.write().unwrap().add_block_type()
+    /// BLOCK_REGISTRY.write_or_recover().add_block_type()
     /// ```
     fn add_block(&mut self, block: BlockData) -> &mut Self {
         self.add_startup_system(move |mut registry: ResMut<Blocks>| {
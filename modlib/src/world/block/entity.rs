@@ -1,8 +1,57 @@
-use bevy::prelude::Component;
-use crate::world::chunk::meshing::MeshingVisibility;
+use bevy::{prelude::{Component, Commands, Query, Res, DespawnRecursiveExt, DetectChanges}, utils::{HashMap, HashSet}};
+use crate::world::{chunk::{Chunk, BlockEntityUpdate, registry::Chunks}, position::BlockPos};
 
 use super::BlockId;
 
 /// Allows blocks to be stored in a chunk. Stores a `BlockId` for rapid block-to-block comparisons and asynchronous access.
 #[derive(Component)]
-pub struct BlockComponent(pub BlockId);
\ No newline at end of file
+pub struct BlockComponent(pub BlockId);
+
+/// The position of a block entity in the world. Added automatically when the entity is placed in a chunk.
+#[derive(Component, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BlockPosition(pub BlockPos);
+
+/// Gives newly placed block entities a `BlockPosition`, and despawns block entities that were replaced by another block.
+/// Block entities can be moved to another chunk, so they're only despawned once the chunk they were last placed in,
+/// found from their `BlockPosition`, doesn't store them anymore.
+pub(crate) fn block_entity_lifecycle_system(
+    mut commands: Commands,
+    chunk_registry: Res<Chunks>,
+    mut chunks: Query<&mut Chunk>,
+    positions: Query<&BlockPosition>,
+) {
+    // `BlockPosition`s are only inserted once the commands are applied, so positions from this frame are kept here
+    let mut placed = HashMap::new();
+    let mut removed = HashSet::new();
+    for mut chunk in chunks.iter_mut() {
+        if !chunk.is_changed() { continue; }
+        // Taking the updates mustn't mark the chunk as changed again
+        for update in chunk.bypass_change_detection().take_block_entity_updates() {
+            match update {
+                BlockEntityUpdate::Placed(entity, position) => {
+                    if let Some(mut entity) = commands.get_entity(entity) {
+                        entity.insert(BlockPosition(position));
+                    }
+                    placed.insert(entity, position);
+                },
+                BlockEntityUpdate::Removed(entity) => {
+                    removed.insert(entity);
+                },
+            }
+        }
+    }
+
+    for entity in removed {
+        let position = placed.get(&entity).copied()
+            .or_else(|| positions.get(entity).ok().map(|position| position.0));
+        let stored = position
+            .and_then(|position| chunk_registry.get(position.chunk()).entity())
+            .and_then(|chunk| chunks.get(chunk).ok())
+            .is_some_and(|chunk| chunk.get_block_entities().any(|other| other == entity));
+        if stored { continue; }
+
+        if let Some(entity) = commands.get_entity(entity) {
+            entity.despawn_recursive();
+        }
+    }
+}
@@ -1,11 +1,12 @@
 //! Block types and attributes.
 
 use bevy::prelude::*;
-use self::registry::Blocks;
+use self::{registry::Blocks, state::BlockStateId};
 
 pub mod entity;
 pub mod data;
 pub mod registry;
+pub mod state;
 
 /// Block registry implementation.
 pub struct BlockRegistryPlugin;
@@ -15,14 +16,14 @@ impl Plugin for BlockRegistryPlugin {
     }
 }
 
-#[derive(Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 pub enum Block {
-    Generic(BlockId),
+    Generic(BlockStateId),
     Entity(Entity),
 }
 
 impl Block {
-    pub const EMPTY: Block = Block::Generic(BlockId::EMPTY);
+    pub const EMPTY: Block = Block::Generic(BlockStateId::EMPTY);
 }
 
 /// A kind of block. Used in the block registry to get its associated data.
@@ -1,8 +1,8 @@
 use std::{collections::BTreeMap, sync::{Arc, RwLock}};
 use bevy::{prelude::*, render::once_cell::sync::Lazy};
-use crate::{attributes::AttributeValue, world::chunk::meshing::MeshingVisibility};
+use crate::{attributes::AttributeValue, sync::RecoverPoison, world::chunk::{meshing::MeshingVisibility, heightmap::heightmap_flags, light::light_emission_of}};
 
-use super::{BlockId, data::BlockData};
+use super::{BlockId, data::BlockData, state::{BlockStateId, PropertyValue, state_count, encode_state, decode_state}};
 
 pub static BLOCK_REGISTRY: Lazy<Arc<RwLock<BlockRegistryInternal>>> = Lazy::new(||{Arc::new(RwLock::new(BlockRegistryInternal::new()))});
 
@@ -11,11 +11,15 @@ pub struct Blocks(Arc<RwLock<BlockRegistryInternal>>);
 
 impl Blocks {
     pub fn add_block_type(&self, block: BlockData) {
-        self.0.write().unwrap().add_block_type(block);
+        self.0.write_or_recover().add_block_type(block);
     }
 
     pub fn get_by_string_id(&self, id: &str) -> Option<(BlockId, BlockData)> {
-        self.0.read().unwrap().get_by_string_id(id)
+        self.0.read_or_recover().get_by_string_id(id)
+    }
+
+    pub fn get_default_state_by_string_id(&self, id: &str) -> Option<BlockStateId> {
+        self.0.read_or_recover().get_default_state_by_string_id(id)
     }
 }
 
@@ -29,6 +33,14 @@ pub struct BlockRegistryInternal {
     last_idx: u32,
     data_map: BTreeMap<BlockId, BlockData>,
     name_map: BTreeMap<String, BlockId>,
+    /// The block type of every state, indexed by `BlockStateId`.
+    state_blocks: Vec<BlockId>,
+    /// The default state of every block type, indexed by `BlockId`.
+    default_states: Vec<BlockStateId>,
+    /// The texture layer of each side of every block type, indexed by `BlockId`. See `get_texture_layers`.
+    texture_layers: Vec<[u32; 6]>,
+    /// The image name of every texture layer after the first.
+    texture_names: Vec<&'static str>,
 }
 
 impl BlockRegistryInternal {
@@ -37,6 +49,10 @@ impl BlockRegistryInternal {
             last_idx: 0,
             data_map: BTreeMap::new(),
             name_map: BTreeMap::new(),
+            state_blocks: vec![],
+            default_states: vec![],
+            texture_layers: vec![],
+            texture_names: vec![],
         };
 
         // Add empty block.
@@ -48,7 +64,7 @@ impl BlockRegistryInternal {
         new
     }
 
-    pub fn add_block_type(&mut self, block: BlockData) {
+    pub fn add_block_type(&mut self, mut block: BlockData) {
         // Check for collisions
         for (_key, value) in self.data_map.iter() {
             if value.string_identifier == block.string_identifier {
@@ -57,6 +73,12 @@ impl BlockRegistryInternal {
         }
 
         let id = BlockId(self.last_idx as u16);
+        block.set_heightmap_flags(heightmap_flags(&block, id == BlockId::EMPTY));
+        block.set_light_emission(light_emission_of(&block));
+        let states = state_count(block.get_properties())
+            .filter(|count| self.state_blocks.len() + *count as usize <= BlockStateId::UNLOADED.0 as usize)
+            .unwrap_or_else(|| panic!("Ran out of block state ids while adding \"{}\"", block.string_identifier));
+        let default_state = BlockStateId(self.state_blocks.len() as u16);
         match block.get_attribute(BlockData::ATTRIBUTE_DISPLAY_NAME) {
             Some(name) => {
                 info!("Added block {} ({:?}) under id {:?}", block.string_identifier, name, id);
@@ -66,6 +88,13 @@ impl BlockRegistryInternal {
             },
         }
 
+        self.state_blocks.extend((0..states).map(|_| id));
+        self.default_states.push(default_state);
+        let texture_layers = match block.get_attribute(BlockData::ATTRIBUTE_SOLID_TEXTURE_SIDES) {
+            Some(AttributeValue::StaticStrX6(sides)) => sides.map(|name| self.texture_layer(name)),
+            _ => [0; 6],
+        };
+        self.texture_layers.push(texture_layers);
         self.name_map.insert(block.string_identifier.to_owned(), id);
         self.data_map.insert(id, block);
         self.last_idx += 1;
@@ -84,7 +113,135 @@ impl BlockRegistryInternal {
         }
     }
 
+    pub fn get_default_state_by_string_id(&self, id: &str) -> Option<BlockStateId> {
+        self.get_default_state(*self.name_map.get(id)?)
+    }
+
+    /// The state of a block type where every property has its first value.
+    pub fn get_default_state(&self, id: BlockId) -> Option<BlockStateId> {
+        self.default_states.get(id.0 as usize).copied()
+    }
+
+    /// The block type a state belongs to.
+    pub fn get_state_block(&self, state: BlockStateId) -> Option<BlockId> {
+        self.state_blocks.get(state.0 as usize).copied()
+    }
+
+    /// The data of the block type a state belongs to.
+    pub fn get_by_state(&self, state: BlockStateId) -> Option<&BlockData> {
+        self.get_by_numerical_id(self.get_state_block(state)?)
+    }
+
+    /// Gets the value of one property of a block state.
+    pub fn get_property(&self, state: BlockStateId, name: &str) -> Option<PropertyValue> {
+        let (block, offset) = self.get_state_offset(state)?;
+        let properties = block.get_properties();
+        let position = properties.iter().position(|property| property.name == name)?;
+        Some(properties[position].value_at(decode_state(properties, offset)[position]))
+    }
+
+    /// Gets the value of every property of a block state, in the order they were added to the block.
+    pub fn get_properties(&self, state: BlockStateId) -> Option<Vec<(&'static str, PropertyValue)>> {
+        let (block, offset) = self.get_state_offset(state)?;
+        let properties = block.get_properties();
+        Some(properties.iter()
+            .zip(decode_state(properties, offset))
+            .map(|(property, index)| (property.name, property.value_at(index)))
+            .collect())
+    }
+
+    /// The state with one property changed, keeping the values of all other properties.
+    /// Returns `None` if the block doesn't have the property, or the property can't have the value.
+    pub fn with_property(&self, state: BlockStateId, name: &str, value: PropertyValue) -> Option<BlockStateId> {
+        let (block, offset) = self.get_state_offset(state)?;
+        let properties = block.get_properties();
+        let position = properties.iter().position(|property| property.name == name)?;
+        let mut indices = decode_state(properties, offset);
+        indices[position] = properties[position].index_of(value)?;
+        Some(BlockStateId(state.0 - offset + encode_state(properties, indices.into_iter())))
+    }
+
+    /// The texture layer of each side of a block state, in the order of `BlockData::ATTRIBUTE_SOLID_TEXTURE_SIDES`.
+    /// Layer 0 is plain white, and is used for blocks without textures.
+    pub fn get_texture_layers(&self, state: BlockStateId) -> [u32; 6] {
+        self.get_state_block(state)
+            .and_then(|id| self.texture_layers.get(id.0 as usize))
+            .copied()
+            .unwrap_or([0; 6])
+    }
+
+    /// The image name of every texture layer, starting from layer 1. Blocks that share an image share its layer.
+    pub fn get_texture_names(&self) -> &[&'static str] {
+        &self.texture_names
+    }
+
+    /// The layer of an image, giving it a new one if no block used it yet.
+    fn texture_layer(&mut self, name: &'static str) -> u32 {
+        let index = match self.texture_names.iter().position(|other| *other == name) {
+            Some(index) => index,
+            None => {
+                self.texture_names.push(name);
+                self.texture_names.len() - 1
+            },
+        };
+        index as u32 + 1
+    }
+
+    /// The data of a state's block type, and how far the state is from the block's default state.
+    fn get_state_offset(&self, state: BlockStateId) -> Option<(&BlockData, u16)> {
+        let id = self.get_state_block(state)?;
+        Some((self.get_by_numerical_id(id)?, state.0 - self.get_default_state(id)?.0))
+    }
+
     pub fn len(&self) -> usize {
         self.data_map.len()
     }
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use crate::world::block::state::BlockProperty;
+    use super::*;
+
+    fn door_registry() -> (BlockRegistryInternal, BlockStateId) {
+        let mut registry = BlockRegistryInternal::new();
+        let mut door = BlockData::new("door", MeshingVisibility::Opaque);
+        door.add_property(BlockProperty::enumeration("facing", &["north", "east", "south", "west"]));
+        door.add_property(BlockProperty::bool("open"));
+        registry.add_block_type(door);
+        let door = registry.get_default_state_by_string_id("door").unwrap();
+        (registry, door)
+    }
+
+    #[test]
+    fn properties_are_read_from_states() {
+        let (registry, door) = door_registry();
+        assert_eq!(registry.get_property(door, "facing"), Some(PropertyValue::Enum("north")));
+        assert_eq!(registry.get_property(door, "open"), Some(PropertyValue::Bool(false)));
+        assert_eq!(registry.get_property(door, "age"), None);
+        assert_eq!(registry.get_property(BlockStateId::EMPTY, "open"), None);
+        assert_eq!(registry.get_property(BlockStateId(door.0 + 8), "open"), None);
+
+        let last = BlockStateId(door.0 + 7);
+        assert_eq!(registry.get_state_block(last), registry.get_state_block(door));
+        assert_eq!(registry.get_properties(last), Some(vec![
+            ("facing", PropertyValue::Enum("west")),
+            ("open", PropertyValue::Bool(true)),
+        ]));
+    }
+
+    #[test]
+    fn changing_a_property_keeps_the_others() {
+        let (registry, door) = door_registry();
+        let east = registry.with_property(door, "facing", PropertyValue::Enum("east")).unwrap();
+        let open = registry.with_property(east, "open", PropertyValue::Bool(true)).unwrap();
+        assert_eq!(registry.get_state_block(open), registry.get_state_block(door));
+        assert_eq!(registry.get_property(open, "facing"), Some(PropertyValue::Enum("east")));
+        assert_eq!(registry.get_property(open, "open"), Some(PropertyValue::Bool(true)));
+        assert_eq!(registry.with_property(open, "open", PropertyValue::Bool(false)), Some(east));
+
+        assert_eq!(registry.with_property(door, "facing", PropertyValue::Enum("up")), None);
+        assert_eq!(registry.with_property(door, "open", PropertyValue::Int(1)), None);
+        assert_eq!(registry.with_property(door, "age", PropertyValue::Int(1)), None);
+        assert_eq!(registry.with_property(BlockStateId::EMPTY, "open", PropertyValue::Bool(true)), None);
+    }
+}
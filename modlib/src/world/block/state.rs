@@ -0,0 +1,157 @@
+//! Block states, which let blocks have properties like orientation or growth stage without becoming entities.
+//!
+//! Every block type declares a schema of `BlockProperty`s in its `BlockData`. The block registry gives each
+//! combination of property values its own `BlockStateId`, and chunks store those instead of plain `BlockId`s.
+//! All states of a block type get consecutive ids, starting with the default state, where every property
+//! has its first value.
+
+use std::fmt::Display;
+
+/// A block type together with a value for each of its properties.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct BlockStateId(pub u16);
+
+impl BlockStateId {
+    /// The only state of `engine_air`.
+    pub const EMPTY: BlockStateId = BlockStateId(0);
+    /// Stands in for neighbours that aren't loaded while meshing, and is always opaque to the meshers.
+    /// It's outside the ids the block registry hands out, so it has no block type and is never placed in chunks.
+    pub const UNLOADED: BlockStateId = BlockStateId(u16::MAX);
+}
+
+/// The values a `BlockProperty` can have.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BlockPropertyKind {
+    /// `false` or `true`, starting with `false`.
+    Bool,
+    /// Any integer from `min` to `max`, inclusive, starting with `min`.
+    Int { min: i32, max: i32 },
+    /// One of a list of names, starting with the first one.
+    Enum(&'static [&'static str]),
+}
+
+/// A property of a block, like `facing` or `age`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BlockProperty {
+    pub name: &'static str,
+    pub kind: BlockPropertyKind,
+}
+
+impl BlockProperty {
+    pub const fn bool(name: &'static str) -> Self {
+        Self { name, kind: BlockPropertyKind::Bool }
+    }
+
+    /// Creates an integer property. Panics if `min` is larger than `max`.
+    pub const fn int(name: &'static str, min: i32, max: i32) -> Self {
+        assert!(min <= max, "integer property has an empty range");
+        Self { name, kind: BlockPropertyKind::Int { min, max } }
+    }
+
+    /// Creates an enum property. Panics if there are no values.
+    pub const fn enumeration(name: &'static str, values: &'static [&'static str]) -> Self {
+        assert!(!values.is_empty(), "enum property has no values");
+        Self { name, kind: BlockPropertyKind::Enum(values) }
+    }
+
+    /// How many different values this property has.
+    pub fn value_count(&self) -> usize {
+        match self.kind {
+            BlockPropertyKind::Bool => 2,
+            BlockPropertyKind::Int { min, max } => (max as i64 - min as i64 + 1) as usize,
+            BlockPropertyKind::Enum(values) => values.len(),
+        }
+    }
+
+    /// The position of a value in this property's list of values, if the property can have it.
+    pub fn index_of(&self, value: PropertyValue) -> Option<usize> {
+        match (self.kind, value) {
+            (BlockPropertyKind::Bool, PropertyValue::Bool(value)) => Some(value as usize),
+            (BlockPropertyKind::Int { min, max }, PropertyValue::Int(value)) if (min..=max).contains(&value) => {
+                Some((value as i64 - min as i64) as usize)
+            },
+            (BlockPropertyKind::Enum(values), PropertyValue::Enum(value)) => values.iter().position(|name| *name == value),
+            _ => None,
+        }
+    }
+
+    /// The value at a position in this property's list of values. Panics if `index` is out of range.
+    pub fn value_at(&self, index: usize) -> PropertyValue {
+        assert!(index < self.value_count(), "property value index out of range");
+        match self.kind {
+            BlockPropertyKind::Bool => PropertyValue::Bool(index == 1),
+            BlockPropertyKind::Int { min, .. } => PropertyValue::Int(min + index as i32),
+            BlockPropertyKind::Enum(values) => PropertyValue::Enum(values[index]),
+        }
+    }
+}
+
+/// The value of a `BlockProperty` in a block state.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PropertyValue {
+    Bool(bool),
+    Int(i32),
+    Enum(&'static str),
+}
+
+impl Display for PropertyValue {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PropertyValue::Bool(value) => write!(f, "{value}"),
+            PropertyValue::Int(value) => write!(f, "{value}"),
+            PropertyValue::Enum(value) => write!(f, "{value}"),
+        }
+    }
+}
+
+/// How many states a block with these properties has. Returns `None` if it doesn't fit in a `u16`.
+pub(crate) fn state_count(properties: &[BlockProperty]) -> Option<u16> {
+    properties.iter().try_fold(1u16, |count, property| {
+        count.checked_mul(u16::try_from(property.value_count()).ok()?)
+    })
+}
+
+/// Encodes property value indices as an offset from the block's default state. The last property changes fastest.
+pub(crate) fn encode_state(properties: &[BlockProperty], indices: impl Iterator<Item = usize>) -> u16 {
+    properties.iter().zip(indices).fold(0, |offset, (property, index)| {
+        offset * property.value_count() as u16 + index as u16
+    })
+}
+
+/// Decodes an offset from the block's default state into the index of each property's value.
+pub(crate) fn decode_state(properties: &[BlockProperty], mut offset: u16) -> Vec<usize> {
+    let mut indices = vec![0; properties.len()];
+    for (index, property) in indices.iter_mut().zip(properties).rev() {
+        let count = property.value_count() as u16;
+        *index = (offset % count) as usize;
+        offset /= count;
+    }
+    indices
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn states_round_trip() {
+        let properties = [
+            BlockProperty::enumeration("facing", &["north", "east", "south", "west"]),
+            BlockProperty::bool("open"),
+            BlockProperty::int("age", 0, 6),
+        ];
+        assert_eq!(state_count(&properties), Some(4 * 2 * 7));
+        assert_eq!(state_count(&[BlockProperty::int("huge", 0, 300), BlockProperty::int("huger", 0, 300)]), None);
+
+        for offset in 0..state_count(&properties).unwrap() {
+            let indices = decode_state(&properties, offset);
+            assert_eq!(encode_state(&properties, indices.into_iter()), offset);
+        }
+
+        let age = properties[2];
+        assert_eq!(age.index_of(PropertyValue::Int(4)), Some(4));
+        assert_eq!(age.index_of(PropertyValue::Int(7)), None);
+        assert_eq!(age.index_of(PropertyValue::Bool(true)), None);
+        assert_eq!(properties[0].value_at(2), PropertyValue::Enum("south"));
+    }
+}
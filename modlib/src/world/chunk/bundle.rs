@@ -1,8 +1,8 @@
-use bevy::prelude::{Bundle, PbrBundle};
-use super::Chunk;
+use bevy::prelude::{Bundle, MaterialMeshBundle};
+use super::{Chunk, meshing::texture::ChunkMaterial};
 
 #[derive(Bundle)]
 pub struct ChunkBundle {
     pub chunk: Chunk,
-    pub pbr: PbrBundle,
+    pub mesh: MaterialMeshBundle<ChunkMaterial>,
 }
\ No newline at end of file
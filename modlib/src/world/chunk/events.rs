@@ -1,22 +1,130 @@
-use bevy::prelude::{Entity, IVec3, EventWriter, Changed, Query};
+use bevy::prelude::{Entity, EventWriter, Changed, Query, ResMut, DetectChanges};
+use crate::{sync::RecoverPoison, world::{block::{Block, registry::BLOCK_REGISTRY}, position::{BlockPos, ChunkPos}}};
 
-use super::Chunk;
+use super::{Chunk, failure::ChunkFailure, registry::{Chunks, ChunkState}};
 
 /// Raise to unload a chunk
 pub struct UnloadChunkMessage(pub Entity);
 
 
 /// Raise to load a chunk
-pub struct LoadChunkMessage(pub IVec3);
+pub struct LoadChunkMessage(pub ChunkPos);
+
+/// Raise to retry a chunk whose generation or meshing task failed. Does nothing if the chunk hasn't failed.
+pub struct RetryChunkMessage(pub ChunkPos);
 
 /// Raised when a chunk is modified
-pub struct ChunkModifiedEvent(pub IVec3);
+pub struct ChunkModifiedEvent(pub ChunkPos);
+
+/// What caused a block to change.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BlockChangeCause {
+    /// World generation, such as a structure placed into an already loaded chunk.
+    Generation,
+    /// A player placing or breaking a block.
+    Player,
+    /// Any other system or mod. `Chunk::set_block` uses this.
+    Script,
+}
+
+/// A single block that changed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BlockChange {
+    pub position: BlockPos,
+    pub old: Block,
+    pub new: Block,
+}
+
+/// Raised when blocks change.
+///
+/// Changes are batched, so there's one event for every chunk and cause each frame, no matter how many blocks changed.
+/// Changes are in the order they were made, and the same block can appear more than once.
+pub struct BlockChangedEvent {
+    pub chunk: ChunkPos,
+    pub cause: BlockChangeCause,
+    pub changes: Vec<BlockChange>,
+}
+
+/// Raised whenever a chunk moves from one `ChunkState` to another.
+pub struct ChunkStateChangedEvent {
+    pub position: ChunkPos,
+    pub from: ChunkState,
+    pub to: ChunkState,
+}
+
+/// Raised when a chunk has been generated or loaded from disk, and its blocks can be accessed.
+pub struct ChunkGeneratedEvent {
+    pub position: ChunkPos,
+    pub entity: Entity,
+}
+
+/// Raised when a chunk gets its first mesh, or is found not to need one. Isn't raised again when the chunk is remeshed.
+pub struct ChunkMeshedEvent {
+    pub position: ChunkPos,
+    pub entity: Entity,
+}
+
+/// Raised when a generation or meshing task for a chunk fails. The same failure is added to the chunk's entity.
+pub struct ChunkFailedEvent {
+    pub position: ChunkPos,
+    pub entity: Entity,
+    pub failure: ChunkFailure,
+}
+
+/// Raised when a chunk starts unloading. The chunk's entity is still around until the next frame's `PostUpdate` stage.
+pub struct ChunkUnloadingEvent {
+    pub position: ChunkPos,
+    pub entity: Entity,
+}
 
 pub(crate) fn chunk_change_system(
     query: Query<&Chunk, Changed<Chunk>>,
     mut events: EventWriter<ChunkModifiedEvent>,
 ) {
     for chunk in query.iter() {
-        events.send(ChunkModifiedEvent(chunk.position.into()));
+        events.send(ChunkModifiedEvent(chunk.position));
+    }
+}
+
+/// Sends `BlockChangedEvent`s for the block changes recorded by chunks, and brings their heightmaps up to date.
+pub(crate) fn block_change_event_system(
+    mut query: Query<&mut Chunk, Changed<Chunk>>,
+    mut events: EventWriter<BlockChangedEvent>,
+) {
+    let registry = BLOCK_REGISTRY.read_or_recover();
+    for mut chunk in query.iter_mut() {
+        // Taking the changes mustn't mark the chunk as changed again
+        let chunk = chunk.bypass_change_detection();
+        chunk.update_heightmaps(&registry);
+        let changes = chunk.take_block_changes();
+        if changes.is_empty() { continue; }
+
+        let mut batches: Vec<BlockChangedEvent> = vec![];
+        for (cause, change) in changes {
+            match batches.iter_mut().find(|event| event.cause == cause) {
+                Some(event) => event.changes.push(change),
+                None => batches.push(BlockChangedEvent { chunk: chunk.position, cause, changes: vec![change] }),
+            }
+        }
+        events.send_batch(batches);
+    }
+}
+
+/// Sends lifecycle events for every chunk state transition since the last time it ran.
+pub(crate) fn chunk_lifecycle_event_system(
+    mut chunk_registry: ResMut<Chunks>,
+    mut state_events: EventWriter<ChunkStateChangedEvent>,
+    mut generated_events: EventWriter<ChunkGeneratedEvent>,
+    mut meshed_events: EventWriter<ChunkMeshedEvent>,
+    mut unloading_events: EventWriter<ChunkUnloadingEvent>,
+) {
+    for (position, from, to) in chunk_registry.drain_transitions() {
+        match to {
+            ChunkState::Generated(entity) => generated_events.send(ChunkGeneratedEvent { position, entity }),
+            ChunkState::Ready(entity) => meshed_events.send(ChunkMeshedEvent { position, entity }),
+            ChunkState::Unloading(entity) => unloading_events.send(ChunkUnloadingEvent { position, entity }),
+            _ => {},
+        }
+        state_events.send(ChunkStateChangedEvent { position, from, to });
     }
-}
\ No newline at end of file
+}
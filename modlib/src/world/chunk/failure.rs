@@ -0,0 +1,110 @@
+//! Failures of chunk generation and meshing tasks.
+//!
+//! Every pass runs through `catch_pass`, so a pass that panics only fails the chunk it was working on. The chunk gets a
+//! `ChunkFailure` saying which pass failed and why, and stays that way until it's retried with a `RetryChunkMessage`
+//! or unloaded. Chunks that fail before their first mesh move to `ChunkState::Failed`, while chunks that fail to be
+//! remeshed stay `Ready` and keep their old mesh.
+
+use std::{any::Any, fmt::Display, panic::{catch_unwind, AssertUnwindSafe}};
+use bevy::prelude::*;
+use super::{events::RetryChunkMessage, meshing::RemeshChunkMarker, loader::Unloading, registry::{Chunks, ChunkState}};
+
+/// The kind of task that failed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChunkTaskKind {
+    Generation,
+    Meshing,
+}
+
+/// Added to a chunk's entity when a generation or meshing task for it failed.
+#[derive(Component, Debug, Clone, PartialEq, Eq)]
+pub struct ChunkFailure {
+    pub task: ChunkTaskKind,
+    /// The name of the pass that failed.
+    pub pass: &'static str,
+    pub error: String,
+}
+
+impl Display for ChunkFailure {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let task = match self.task {
+            ChunkTaskKind::Generation => "generation",
+            ChunkTaskKind::Meshing => "meshing",
+        };
+        write!(f, "{task} pass {} failed: {}", self.pass, self.error)
+    }
+}
+
+impl std::error::Error for ChunkFailure {}
+
+/// Runs a single pass of a chunk task, turning a panic into a `ChunkFailure`.
+pub(crate) fn catch_pass<T>(task: ChunkTaskKind, pass: &'static str, f: impl FnOnce() -> T) -> Result<T, ChunkFailure> {
+    // Whatever the pass was writing to is thrown away when it fails, so it doesn't matter if it was left half done
+    catch_unwind(AssertUnwindSafe(f)).map_err(|payload| ChunkFailure {
+        task,
+        pass,
+        error: panic_message(payload.as_ref()),
+    })
+}
+
+/// Gets the message out of a panic payload, which is a `&str` or `String` for panics with a message.
+fn panic_message(payload: &(dyn Any + Send)) -> String {
+    if let Some(message) = payload.downcast_ref::<&str>() {
+        message.to_string()
+    } else if let Some(message) = payload.downcast_ref::<String>() {
+        message.clone()
+    } else {
+        "panicked without a message".to_string()
+    }
+}
+
+/// Retries failed chunks in response to `RetryChunkMessage`.
+///
+/// Chunks that failed to generate are queued for generation again, and chunks that failed to mesh are remeshed.
+pub(crate) fn chunk_retry_system(
+    mut commands: Commands,
+    mut events: EventReader<RetryChunkMessage>,
+    mut chunk_registry: ResMut<Chunks>,
+    failed: Query<&ChunkFailure, Without<Unloading>>,
+) {
+    for event in events.iter() {
+        let state = chunk_registry.get(event.0);
+        let entity = match state.entity() {
+            Some(entity) => entity,
+            None => continue,
+        };
+        let failure = match failed.get(entity) {
+            Ok(failure) => failure,
+            Err(_) => continue,
+        };
+
+        match failure.task {
+            ChunkTaskKind::Generation => {
+                // The chunk's `BeingGenerated` is still there, so it only needs to be queued again
+                chunk_registry.transition_or_warn(event.0, ChunkState::Queued(entity));
+                commands.entity(entity).remove::<ChunkFailure>();
+            },
+            ChunkTaskKind::Meshing => {
+                if state == ChunkState::Failed(entity) {
+                    chunk_registry.transition_or_warn(event.0, ChunkState::Generated(entity));
+                }
+                commands.entity(entity).remove::<ChunkFailure>().insert(RemeshChunkMarker);
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn panics_become_failures() {
+        assert_eq!(catch_pass(ChunkTaskKind::Generation, "fine", || 5), Ok(5));
+
+        let failure = catch_pass(ChunkTaskKind::Meshing, "broken", || -> u32 { panic!("missing block {}", 7) }).unwrap_err();
+        assert_eq!(failure.pass, "broken");
+        assert_eq!(failure.error, "missing block 7");
+        assert_eq!(failure.to_string(), "meshing pass broken failed: missing block 7");
+    }
+}
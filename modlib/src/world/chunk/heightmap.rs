@@ -0,0 +1,191 @@
+//! Per-column heightmaps.
+//!
+//! Whether a block counts towards a heightmap depends on its `BlockData`, which needs the block registry. Chunks can't
+//! lock the registry while blocks are being set, since world generation already holds it, so `Chunk::set_block` only
+//! remembers which blocks changed. The heightmaps are brought up to date by `Chunk::update_heightmaps`, which runs
+//! after generation and once per frame for every chunk that changed.
+//!
+//! Reading heights takes the registry instead, so `Chunk::get_height` and `Chunk::get_heightmaps` can include changes
+//! that haven't been applied yet, and never return heights from before an edit.
+
+use crate::{world::block::{Block, state::BlockStateId, data::BlockData, registry::BlockRegistryInternal}, attributes::AttributeValue};
+use super::{CHUNK_SIZE, meshing::MeshingVisibility, storage::PalettedStorage, ChunkBlockInternal};
+
+/// The blocks a heightmap tracks.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HeightmapKind {
+    /// Any block that isn't air. Block entities count as well.
+    AnyBlock,
+    /// Blocks with `MeshingVisibility::Opaque`.
+    Opaque,
+    /// Blocks that stop movement. See `BlockData::ATTRIBUTE_MOTION_BLOCKING`.
+    MotionBlocking,
+}
+
+impl HeightmapKind {
+    pub const ALL: [HeightmapKind; 3] = [HeightmapKind::AnyBlock, HeightmapKind::Opaque, HeightmapKind::MotionBlocking];
+
+    pub(crate) fn flag(&self) -> u8 {
+        1 << (*self as u8)
+    }
+
+    fn index(&self) -> usize {
+        *self as usize
+    }
+
+    /// Checks if a block counts towards this kind of heightmap.
+    pub fn matches(&self, registry: &BlockRegistryInternal, block: Block) -> bool {
+        match block {
+            Block::Generic(state) => self.matches_state(registry, state),
+            Block::Entity(_) => self.matches_entity(),
+        }
+    }
+
+    pub(crate) fn matches_internal(&self, registry: &BlockRegistryInternal, block: ChunkBlockInternal) -> bool {
+        match block {
+            ChunkBlockInternal::Generic(state) => self.matches_state(registry, state),
+            ChunkBlockInternal::Entity(_) => self.matches_entity(),
+        }
+    }
+
+    fn matches_state(&self, registry: &BlockRegistryInternal, state: BlockStateId) -> bool {
+        registry.get_by_state(state).is_some_and(|block| block.get_heightmap_flags() & self.flag() != 0)
+    }
+
+    fn matches_entity(&self) -> bool {
+        // Block entities have their own shapes, so they only count as a block that blocks movement
+        matches!(self, HeightmapKind::AnyBlock | HeightmapKind::MotionBlocking)
+    }
+}
+
+/// Which heightmaps a block counts towards, as `HeightmapKind` flags. Called by the block registry for each new block type.
+pub(crate) fn heightmap_flags(block: &BlockData, is_air: bool) -> u8 {
+    let mut flags = 0;
+    if !is_air {
+        flags |= HeightmapKind::AnyBlock.flag();
+    }
+    if block.block_visibility == MeshingVisibility::Opaque {
+        flags |= HeightmapKind::Opaque.flag();
+    }
+    let motion_blocking = match block.get_attribute(BlockData::ATTRIBUTE_MOTION_BLOCKING) {
+        Some(AttributeValue::Boolean(value)) => *value,
+        _ => !is_air && block.get_attribute(BlockData::ATTRIBUTE_USE_LIQUID_MESHER).is_none(),
+    };
+    if motion_blocking {
+        flags |= HeightmapKind::MotionBlocking.flag();
+    }
+
+    flags
+}
+
+/// The highest block of each kind in every column of a chunk.
+#[derive(Clone)]
+pub struct Heightmaps {
+    /// One height for every column and kind, where `0` means there is no block in the column, and `y + 1` means the highest block is at `y`.
+    heights: [[u8; CHUNK_SIZE * CHUNK_SIZE]; HeightmapKind::ALL.len()],
+}
+
+impl Heightmaps {
+    /// Heightmaps for a chunk without any blocks.
+    pub(crate) fn empty() -> Self {
+        Self { heights: [[0; CHUNK_SIZE * CHUNK_SIZE]; HeightmapKind::ALL.len()] }
+    }
+
+    /// Builds heightmaps by scanning every column of a chunk's blocks.
+    pub(crate) fn compute(blocks: &PalettedStorage, registry: &BlockRegistryInternal) -> Self {
+        let mut heightmaps = Self::empty();
+
+        // Uniform chunks have the same height in every column
+        if let Some(value) = blocks.uniform_value() {
+            for kind in HeightmapKind::ALL {
+                let height = if kind.matches_internal(registry, value) { CHUNK_SIZE as u8 } else { 0 };
+                heightmaps.heights[kind.index()] = [height; CHUNK_SIZE * CHUNK_SIZE];
+            }
+            return heightmaps;
+        }
+
+        for x in 0..CHUNK_SIZE {
+            for z in 0..CHUNK_SIZE {
+                for kind in HeightmapKind::ALL {
+                    let height = (0..CHUNK_SIZE).rev()
+                        .find(|y| kind.matches_internal(registry, blocks.get(x, *y, z)))
+                        .map_or(0, |y| y as u8 + 1);
+                    heightmaps.heights[kind.index()][column(x, z)] = height;
+                }
+            }
+        }
+
+        heightmaps
+    }
+
+    /// The local y coordinate of the highest block of this kind in a column, if there is one.
+    pub fn get(&self, kind: HeightmapKind, x: usize, z: usize) -> Option<usize> {
+        match self.heights[kind.index()][column(x, z)] {
+            0 => None,
+            height => Some(height as usize - 1),
+        }
+    }
+
+    /// Updates the heightmaps after the block at `x`, `y`, `z` in `blocks` has been changed.
+    /// Only scans the column when its highest block was removed.
+    pub(crate) fn update(&mut self, blocks: &PalettedStorage, registry: &BlockRegistryInternal, x: usize, y: usize, z: usize) {
+        let to = blocks.get(x, y, z);
+        for kind in HeightmapKind::ALL {
+            let height = &mut self.heights[kind.index()][column(x, z)];
+            if kind.matches_internal(registry, to) {
+                *height = (*height).max(y as u8 + 1);
+            } else if *height as usize == y + 1 {
+                *height = (0..y).rev()
+                    .find(|y| kind.matches_internal(registry, blocks.get(x, *y, z)))
+                    .map_or(0, |y| y as u8 + 1);
+            }
+        }
+    }
+}
+
+fn column(x: usize, z: usize) -> usize {
+    x * CHUNK_SIZE + z
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::world::{block::{Block, data::BlockData, registry::BlockRegistryInternal}, chunk::{Chunk, meshing::MeshingVisibility}, position::ChunkPos};
+    use super::*;
+
+    #[test]
+    fn heightmaps_follow_set_block() {
+        let mut registry = BlockRegistryInternal::new();
+        registry.add_block_type(BlockData::new("test_stone", MeshingVisibility::Opaque));
+        registry.add_block_type(BlockData::new("test_glass", MeshingVisibility::Translucent));
+        let stone = registry.get_default_state_by_string_id("test_stone").unwrap();
+        let glass = registry.get_default_state_by_string_id("test_glass").unwrap();
+
+        let mut chunk = Chunk::new(ChunkPos::new(0, 0, 0));
+        assert_eq!(chunk.get_height(&registry, HeightmapKind::AnyBlock, 2, 3), None);
+
+        // Heights include changes straight away, before the heightmaps are updated
+        chunk.set_block(2, 4, 3, Block::Generic(stone));
+        chunk.set_block(2, 9, 3, Block::Generic(glass));
+        assert_eq!(chunk.get_height(&registry, HeightmapKind::AnyBlock, 2, 3), Some(9));
+        assert_eq!(chunk.get_heightmaps(&registry).get(HeightmapKind::Opaque, 2, 3), Some(4));
+        chunk.update_heightmaps(&registry);
+        assert_eq!(chunk.get_height(&registry, HeightmapKind::AnyBlock, 2, 3), Some(9));
+        assert_eq!(chunk.get_height(&registry, HeightmapKind::Opaque, 2, 3), Some(4));
+        assert_eq!(chunk.get_height(&registry, HeightmapKind::MotionBlocking, 2, 3), Some(9));
+
+        chunk.set_block(2, 9, 3, Block::EMPTY);
+        assert_eq!(chunk.get_height(&registry, HeightmapKind::AnyBlock, 2, 3), Some(4));
+        chunk.update_heightmaps(&registry);
+        assert_eq!(chunk.get_height(&registry, HeightmapKind::AnyBlock, 2, 3), Some(4));
+        chunk.set_block(2, 4, 3, Block::EMPTY);
+        chunk.update_heightmaps(&registry);
+        assert_eq!(chunk.get_height(&registry, HeightmapKind::AnyBlock, 2, 3), None);
+
+        chunk.set_block(2, 6, 3, Block::Generic(stone));
+        chunk.update_heightmaps(&registry);
+        let recomputed = Heightmaps::compute(chunk.get_internal_storage(), &registry);
+        for kind in HeightmapKind::ALL {
+            assert_eq!(recomputed.get(kind, 2, 3), chunk.get_height(&registry, kind, 2, 3));
+        }
+    }
+}
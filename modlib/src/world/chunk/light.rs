@@ -0,0 +1,544 @@
+//! Skylight and block light.
+//!
+//! Every chunk stores two light levels from `0` to `MAX_LIGHT` for each block, packed two to a byte.
+//! When a chunk is lit and every block in it has the same level of a kind of light, like deep underground or out in the
+//! open sky, only that level is stored, until a block's level changes.
+//! Skylight shines straight down from above without losing any strength until it hits an opaque block,
+//! and block light comes from blocks with `BlockData::ATTRIBUTE_LIGHT_EMISSION`.
+//! Both spread to neighbouring blocks with a flood fill, losing one level per block.
+//!
+//! Freshly generated chunks are lit by themselves in the generation task, assuming there's open sky above them.
+//! `chunk_light_system` then fixes up the light along their borders once they're loaded, and updates light
+//! incrementally whenever blocks change. Light changes don't mark chunks as changed. Instead, chunks whose light
+//! changed are remeshed, along with the neighbours of chunks whose light changed along the border they share.
+
+use std::{collections::VecDeque, sync::Arc};
+use bevy::{prelude::*, utils::{HashMap, HashSet}};
+use crate::{sync::RecoverPoison, world::{block::{Block, data::BlockData, registry::{BlockRegistryInternal, BLOCK_REGISTRY}}, position::{BlockPos, ChunkPos, LocalPos}}, attributes::AttributeValue};
+use super::{
+    Chunk, ChunkBlockInternal, CHUNK_SIZE,
+    events::{BlockChangedEvent, ChunkGeneratedEvent},
+    heightmap::HeightmapKind,
+    meshing::RemeshChunkMarker,
+    registry::Chunks,
+    storage::CHUNK_VOLUME,
+};
+
+/// The brightest a block can be lit.
+pub const MAX_LIGHT: u8 = 15;
+
+/// The two kinds of light stored for every block.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum LightKind {
+    Sky,
+    Block,
+}
+
+impl LightKind {
+    pub const ALL: [LightKind; 2] = [LightKind::Sky, LightKind::Block];
+}
+
+/// Light levels for every block in a chunk.
+#[derive(Clone)]
+pub struct LightStorage {
+    sky: LightLevels,
+    block: LightLevels,
+}
+
+/// The levels of one kind of light in a chunk.
+#[derive(Clone)]
+enum LightLevels {
+    /// Every block has the same level.
+    Uniform(u8),
+    /// A level for each block, packed two to a byte.
+    Nibbles(Box<[u8]>),
+}
+
+impl LightStorage {
+    /// A chunk with no light at all.
+    pub(crate) fn dark() -> Self {
+        Self {
+            sky: LightLevels::Uniform(0),
+            block: LightLevels::Uniform(0),
+        }
+    }
+
+    /// Gets the light level of a block.
+    pub fn get(&self, kind: LightKind, x: usize, y: usize, z: usize) -> u8 {
+        match self.levels(kind) {
+            LightLevels::Uniform(level) => *level,
+            LightLevels::Nibbles(nibbles) => {
+                let index = flatten(x, y, z);
+                let byte = nibbles[index / 2];
+                if index % 2 == 0 { byte & 0xF } else { byte >> 4 }
+            },
+        }
+    }
+
+    /// Gets both light levels of a block, packed into one byte with skylight in the high four bits.
+    pub fn get_packed(&self, x: usize, y: usize, z: usize) -> u8 {
+        self.get(LightKind::Sky, x, y, z) << 4 | self.get(LightKind::Block, x, y, z)
+    }
+
+    /// Sets the light level of a block. Uniform light is only split into a level for each block once a level differs.
+    pub(crate) fn set(&mut self, kind: LightKind, x: usize, y: usize, z: usize, level: u8) {
+        let levels = match kind {
+            LightKind::Sky => &mut self.sky,
+            LightKind::Block => &mut self.block,
+        };
+        if let LightLevels::Uniform(uniform) = *levels {
+            if uniform == level { return; }
+            *levels = LightLevels::Nibbles(vec![uniform | uniform << 4; CHUNK_VOLUME / 2].into_boxed_slice());
+        }
+        let nibbles = match levels {
+            LightLevels::Nibbles(nibbles) => nibbles,
+            LightLevels::Uniform(_) => unreachable!("uniform light was split up above"),
+        };
+
+        let index = flatten(x, y, z);
+        let byte = &mut nibbles[index / 2];
+        *byte = if index % 2 == 0 { (*byte & 0xF0) | level } else { (*byte & 0x0F) | level << 4 };
+    }
+
+    /// Stores each kind of light as a single level again if every block has the same level of it.
+    pub(crate) fn shrink_to_fit(&mut self) {
+        for levels in [&mut self.sky, &mut self.block] {
+            if let LightLevels::Nibbles(nibbles) = levels {
+                let first = nibbles[0] & 0xF;
+                if nibbles.iter().all(|byte| *byte == first | first << 4) {
+                    *levels = LightLevels::Uniform(first);
+                }
+            }
+        }
+    }
+
+    /// Returns the level of a kind of light if every block in the chunk has the same level of it.
+    pub fn uniform_level(&self, kind: LightKind) -> Option<u8> {
+        match self.levels(kind) {
+            LightLevels::Uniform(level) => Some(*level),
+            LightLevels::Nibbles(_) => None,
+        }
+    }
+
+    fn levels(&self, kind: LightKind) -> &LightLevels {
+        match kind {
+            LightKind::Sky => &self.sky,
+            LightKind::Block => &self.block,
+        }
+    }
+}
+
+fn flatten(x: usize, y: usize, z: usize) -> usize {
+    (x * CHUNK_SIZE + y) * CHUNK_SIZE + z
+}
+
+/// How much light a block type emits, from its `BlockData::ATTRIBUTE_LIGHT_EMISSION`. Called by the block registry for each new block type.
+pub(crate) fn light_emission_of(block: &BlockData) -> u8 {
+    match block.get_attribute(BlockData::ATTRIBUTE_LIGHT_EMISSION) {
+        Some(AttributeValue::Uint16(value)) => (*value).min(MAX_LIGHT as u16) as u8,
+        _ => 0,
+    }
+}
+
+/// How much light a block emits. Block entities don't emit any light.
+pub fn light_emission(registry: &BlockRegistryInternal, block: Block) -> u8 {
+    match block {
+        Block::Generic(state) => registry.get_by_state(state).map_or(0, BlockData::get_light_emission),
+        Block::Entity(_) => 0,
+    }
+}
+
+fn emission(registry: &BlockRegistryInternal, block: ChunkBlockInternal) -> u8 {
+    match block {
+        ChunkBlockInternal::Generic(state) => light_emission(registry, Block::Generic(state)),
+        ChunkBlockInternal::Entity(_) => 0,
+    }
+}
+
+fn is_opaque(registry: &BlockRegistryInternal, block: ChunkBlockInternal) -> bool {
+    HeightmapKind::Opaque.matches_internal(registry, block)
+}
+
+const NEIGHBOURS: [IVec3; 6] = [IVec3::X, IVec3::NEG_X, IVec3::Y, IVec3::NEG_Y, IVec3::Z, IVec3::NEG_Z];
+
+/// Access to light and blocks for the flood fill. Positions that can't be accessed, like unloaded chunks, return `None`.
+trait LightAccess {
+    fn block(&self, pos: BlockPos) -> Option<ChunkBlockInternal>;
+    fn light(&self, pos: BlockPos, kind: LightKind) -> Option<u8>;
+    fn set_light(&mut self, pos: BlockPos, kind: LightKind, level: u8);
+}
+
+/// Flood fill for one kind of light, using the usual pair of queues for adding and removing light.
+struct LightPropagation<'r> {
+    kind: LightKind,
+    registry: &'r BlockRegistryInternal,
+    /// Blocks whose light should spread to their neighbours.
+    increase: VecDeque<BlockPos>,
+    /// Blocks that were darkened, with the light they had before.
+    decrease: VecDeque<(BlockPos, u8)>,
+}
+
+impl<'r> LightPropagation<'r> {
+    fn new(kind: LightKind, registry: &'r BlockRegistryInternal) -> Self {
+        Self { kind, registry, increase: VecDeque::new(), decrease: VecDeque::new() }
+    }
+
+    /// The light a block passes on to a neighbour. Skylight at full strength doesn't weaken on its way down.
+    fn spread(&self, level: u8, offset: IVec3) -> u8 {
+        if self.kind == LightKind::Sky && level == MAX_LIGHT && offset == IVec3::NEG_Y {
+            MAX_LIGHT
+        } else {
+            level.saturating_sub(1)
+        }
+    }
+
+    /// Darkens a block, and everything lit by it once the flood fill runs.
+    fn remove(&mut self, access: &mut impl LightAccess, pos: BlockPos) {
+        if let Some(level) = access.light(pos, self.kind) {
+            if level == 0 { return; }
+            access.set_light(pos, self.kind, 0);
+            self.decrease.push_back((pos, level));
+        }
+    }
+
+    /// Lights a block, if it isn't already lit as brightly.
+    fn add(&mut self, access: &mut impl LightAccess, pos: BlockPos, level: u8) {
+        if access.light(pos, self.kind).is_some_and(|current| current < level) {
+            access.set_light(pos, self.kind, level);
+            self.increase.push_back(pos);
+        }
+    }
+
+    fn run(&mut self, access: &mut impl LightAccess) {
+        while let Some((pos, level)) = self.decrease.pop_front() {
+            for offset in NEIGHBOURS {
+                let neighbour = pos + offset;
+                let neighbour_level = match access.light(neighbour, self.kind) {
+                    Some(neighbour_level) => neighbour_level,
+                    None => continue,
+                };
+
+                if neighbour_level != 0 && neighbour_level <= self.spread(level, offset) {
+                    // This neighbour might have been lit by the removed light, so remove its light too
+                    access.set_light(neighbour, self.kind, 0);
+                    self.decrease.push_back((neighbour, neighbour_level));
+
+                    if self.kind == LightKind::Block {
+                        let emitted = access.block(neighbour).map_or(0, |block| emission(self.registry, block));
+                        if emitted > 0 {
+                            access.set_light(neighbour, self.kind, emitted);
+                            self.increase.push_back(neighbour);
+                        }
+                    }
+                } else if neighbour_level != 0 {
+                    // This neighbour has light from somewhere else, which can fill the darkened area back in
+                    self.increase.push_back(neighbour);
+                }
+            }
+        }
+
+        while let Some(pos) = self.increase.pop_front() {
+            let level = match access.light(pos, self.kind) {
+                Some(level) if level > 0 => level,
+                _ => continue,
+            };
+
+            for offset in NEIGHBOURS {
+                let neighbour = pos + offset;
+                match access.block(neighbour) {
+                    Some(block) if !is_opaque(self.registry, block) => {},
+                    _ => continue,
+                }
+                self.add(access, neighbour, self.spread(level, offset));
+            }
+        }
+    }
+}
+
+/// Lighting a single chunk, where everything outside of it can't be accessed.
+impl LightAccess for Chunk {
+    fn block(&self, pos: BlockPos) -> Option<ChunkBlockInternal> {
+        let (x, y, z) = self.local_indices(pos)?;
+        Some(self.blocks.get(x, y, z))
+    }
+
+    fn light(&self, pos: BlockPos, kind: LightKind) -> Option<u8> {
+        let (x, y, z) = self.local_indices(pos)?;
+        Some(self.light.get(kind, x, y, z))
+    }
+
+    fn set_light(&mut self, pos: BlockPos, kind: LightKind, level: u8) {
+        if let Some((x, y, z)) = self.local_indices(pos) {
+            Arc::make_mut(&mut self.light).set(kind, x, y, z, level);
+        }
+    }
+}
+
+impl Chunk {
+    fn local_indices(&self, pos: BlockPos) -> Option<(usize, usize, usize)> {
+        let (chunk, local) = pos.split();
+        (chunk == self.position).then(|| local.indices())
+    }
+
+    /// Lights the chunk by itself, as if there was open sky above it and nothing around it.
+    pub(crate) fn init_light(&mut self, registry: &BlockRegistryInternal) {
+        self.light = Arc::new(LightStorage::dark());
+        let origin = self.position.origin();
+        let mut sky = LightPropagation::new(LightKind::Sky, registry);
+        let mut block = LightPropagation::new(LightKind::Block, registry);
+
+        for x in 0..CHUNK_SIZE {
+            for z in 0..CHUNK_SIZE {
+                // Skylight shines straight down until it hits something opaque
+                for y in (0..CHUNK_SIZE).rev() {
+                    if is_opaque(registry, self.blocks.get(x, y, z)) { break; }
+                    sky.add(self, BlockPos::from_parts(self.position, LocalPos::from_indices(x, y, z)), MAX_LIGHT);
+                }
+            }
+        }
+
+        // Uniform chunks can't contain a light source unless every block is one
+        let palette_has_emitters = self.blocks.palette().iter().any(|block| emission(registry, *block) > 0);
+        if palette_has_emitters {
+            for x in 0..CHUNK_SIZE {
+                for y in 0..CHUNK_SIZE {
+                    for z in 0..CHUNK_SIZE {
+                        let emitted = emission(registry, self.blocks.get(x, y, z));
+                        if emitted > 0 {
+                            block.add(self, origin + IVec3::new(x as i32, y as i32, z as i32), emitted);
+                        }
+                    }
+                }
+            }
+        }
+
+        sky.run(self);
+        block.run(self);
+        Arc::make_mut(&mut self.light).shrink_to_fit();
+    }
+}
+
+/// Lighting across every loaded chunk.
+struct WorldLight<'a, 'w, 's, 'q> {
+    chunk_registry: &'a Chunks,
+    chunks: &'a mut Query<'w, 's, &'q mut Chunk>,
+    /// The light every changed block had before it was first changed, so blocks that end up as they were can be told apart.
+    original: HashMap<(BlockPos, LightKind), u8>,
+}
+
+impl WorldLight<'_, '_, '_, '_> {
+    fn entity(&self, chunk: ChunkPos) -> Option<Entity> {
+        self.chunk_registry.get(chunk).loaded_entity()
+    }
+
+    /// The chunks whose meshes are out of date because of light that changed: the chunks the light changed in,
+    /// and their neighbours if it changed along the border they share, since meshes include the light of the blocks around them.
+    fn relit_chunks(&self) -> HashSet<ChunkPos> {
+        let mut relit = HashSet::new();
+        for ((pos, kind), level) in self.original.iter() {
+            if self.light(*pos, *kind) == Some(*level) { continue; }
+            let chunk = pos.chunk();
+            relit.insert(chunk);
+            for offset in NEIGHBOURS {
+                relit.insert((*pos + offset).chunk());
+            }
+        }
+        relit
+    }
+}
+
+impl LightAccess for WorldLight<'_, '_, '_, '_> {
+    fn block(&self, pos: BlockPos) -> Option<ChunkBlockInternal> {
+        let (chunk, local) = pos.split();
+        let (x, y, z) = local.indices();
+        let chunk = self.chunks.get(self.entity(chunk)?).ok()?;
+        Some(chunk.blocks.get(x, y, z))
+    }
+
+    fn light(&self, pos: BlockPos, kind: LightKind) -> Option<u8> {
+        let (chunk, local) = pos.split();
+        let (x, y, z) = local.indices();
+        let chunk = self.chunks.get(self.entity(chunk)?).ok()?;
+        Some(chunk.light.get(kind, x, y, z))
+    }
+
+    fn set_light(&mut self, pos: BlockPos, kind: LightKind, level: u8) {
+        let (chunk, local) = pos.split();
+        let (x, y, z) = local.indices();
+        let entity = match self.entity(chunk) {
+            Some(entity) => entity,
+            None => return,
+        };
+        // Light changes don't mark the chunk as changed, only the chunks that need it are remeshed afterwards
+        if let Ok(mut chunk) = self.chunks.get_mut(entity) {
+            let chunk = chunk.bypass_change_detection();
+            let current = chunk.light.get(kind, x, y, z);
+            if current == level { return; }
+            self.original.entry((pos, kind)).or_insert(current);
+            Arc::make_mut(&mut chunk.light).set(kind, x, y, z, level);
+        }
+    }
+}
+
+/// Fixes up light along the borders of newly loaded chunks, and updates light around blocks that changed.
+pub(crate) fn chunk_light_system(
+    mut commands: Commands,
+    chunk_registry: Res<Chunks>,
+    mut chunks: Query<&mut Chunk>,
+    mut generated_events: EventReader<ChunkGeneratedEvent>,
+    mut block_events: EventReader<BlockChangedEvent>,
+) {
+    let registry = BLOCK_REGISTRY.read_or_recover();
+    let mut world = WorldLight { chunk_registry: &chunk_registry, chunks: &mut chunks, original: HashMap::new() };
+    let mut sky = LightPropagation::new(LightKind::Sky, &registry);
+    let mut block = LightPropagation::new(LightKind::Block, &registry);
+
+    for event in generated_events.iter() {
+        if world.entity(event.position).is_none() { continue; }
+        let origin = event.position.origin();
+        let top = CHUNK_SIZE as i32 - 1;
+
+        for x in 0..CHUNK_SIZE as i32 {
+            for z in 0..CHUNK_SIZE as i32 {
+                // The chunk was lit assuming open sky above it, which isn't true if the chunk above is dark at the bottom
+                let top_of_chunk = origin + IVec3::new(x, top, z);
+                if let (Some(above), Some(MAX_LIGHT)) = (world.light(top_of_chunk + IVec3::Y, LightKind::Sky), world.light(top_of_chunk, LightKind::Sky)) {
+                    if above != MAX_LIGHT { sky.remove(&mut world, top_of_chunk); }
+                }
+
+                // The same goes for the chunk below, if this chunk is dark at the bottom
+                let bottom_of_chunk = origin + IVec3::new(x, 0, z);
+                if let (Some(MAX_LIGHT), Some(level)) = (world.light(bottom_of_chunk - IVec3::Y, LightKind::Sky), world.light(bottom_of_chunk, LightKind::Sky)) {
+                    if level != MAX_LIGHT { sky.remove(&mut world, bottom_of_chunk - IVec3::Y); }
+                }
+            }
+        }
+
+        // Let light flow across every border, in both directions
+        for (axis, offset) in NEIGHBOURS.iter().enumerate() {
+            for a in 0..CHUNK_SIZE as i32 {
+                for b in 0..CHUNK_SIZE as i32 {
+                    let layer = if offset.max_element() > 0 { top } else { 0 };
+                    let inside = origin + match axis / 2 {
+                        0 => IVec3::new(layer, a, b),
+                        1 => IVec3::new(a, layer, b),
+                        _ => IVec3::new(a, b, layer),
+                    };
+                    for pos in [inside, inside + *offset] {
+                        sky.increase.push_back(pos);
+                        block.increase.push_back(pos);
+                    }
+                }
+            }
+        }
+    }
+
+    for event in block_events.iter() {
+        for change in event.changes.iter() {
+            let (old, new) = (change.old, change.new);
+            // Blocks that let through and emit the same light don't change anything
+            let opaque = HeightmapKind::Opaque;
+            if opaque.matches(&registry, old) == opaque.matches(&registry, new) && light_emission(&registry, old) == light_emission(&registry, new) { continue; }
+
+            for propagation in [&mut sky, &mut block] {
+                propagation.remove(&mut world, change.position);
+                for offset in NEIGHBOURS {
+                    propagation.increase.push_back(change.position + offset);
+                }
+            }
+            let emitted = light_emission(&registry, new);
+            if emitted > 0 {
+                block.add(&mut world, change.position, emitted);
+            }
+        }
+    }
+
+    sky.run(&mut world);
+    block.run(&mut world);
+
+    for chunk in world.relit_chunks() {
+        if let Some(entity) = world.entity(chunk) {
+            commands.entity(entity).insert(RemeshChunkMarker);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::world::{chunk::meshing::MeshingVisibility, block::{Block, data::BlockData, registry::BlockRegistryInternal}};
+    use super::*;
+
+    #[test]
+    fn light_spreads_and_is_removed() {
+        let mut registry = BlockRegistryInternal::new();
+        registry.add_block_type(BlockData::new("test_stone", MeshingVisibility::Opaque));
+        registry.add_block_type(BlockData::new_with_attributes("test_lamp", MeshingVisibility::Translucent, vec![
+            (BlockData::ATTRIBUTE_LIGHT_EMISSION, AttributeValue::Uint16(14)),
+        ]));
+        let stone = registry.get_default_state_by_string_id("test_stone").unwrap();
+        let lamp = registry.get_default_state_by_string_id("test_lamp").unwrap();
+
+        // A stone roof at y = 10 with a lamp below it
+        let mut chunk = Chunk::new(ChunkPos::new(0, 0, 0));
+        for x in 0..CHUNK_SIZE {
+            for z in 0..CHUNK_SIZE {
+                chunk.set_block(x, 10, z, Block::Generic(stone));
+            }
+        }
+        chunk.set_block(8, 5, 8, Block::Generic(lamp));
+        chunk.init_light(&registry);
+
+        assert_eq!(chunk.get_sky_light(3, 11, 3), MAX_LIGHT);
+        assert_eq!(chunk.get_sky_light(3, 10, 3), 0);
+        assert_eq!(chunk.get_sky_light(3, 9, 3), 0);
+        assert_eq!(chunk.get_block_light(8, 5, 8), 14);
+        assert_eq!(chunk.get_block_light(8, 5, 11), 11);
+        assert_eq!(chunk.get_block_light(8, 11, 8), 0);
+
+        // Removing the lamp and a hole in the roof
+        chunk.set_block(8, 5, 8, Block::EMPTY);
+        chunk.set_block(3, 10, 3, Block::EMPTY);
+        let mut sky = LightPropagation::new(LightKind::Sky, &registry);
+        let mut block = LightPropagation::new(LightKind::Block, &registry);
+        block.remove(&mut chunk, BlockPos::new(8, 5, 8));
+        sky.increase.push_back(BlockPos::new(3, 11, 3));
+        sky.run(&mut chunk);
+        block.run(&mut chunk);
+
+        assert_eq!(chunk.get_block_light(8, 5, 11), 0);
+        assert_eq!(chunk.get_sky_light(3, 0, 3), MAX_LIGHT);
+        assert_eq!(chunk.get_sky_light(4, 0, 3), MAX_LIGHT - 1);
+    }
+
+    #[test]
+    fn uniform_light_has_no_arrays() {
+        let mut registry = BlockRegistryInternal::new();
+        registry.add_block_type(BlockData::new("test_stone", MeshingVisibility::Opaque));
+        let stone = registry.get_default_state_by_string_id("test_stone").unwrap();
+
+        let mut open = Chunk::new(ChunkPos::new(0, 0, 0));
+        open.init_light(&registry);
+        assert_eq!(open.get_light().uniform_level(LightKind::Sky), Some(MAX_LIGHT));
+        assert_eq!(open.get_light().uniform_level(LightKind::Block), Some(0));
+
+        let mut solid = Chunk::new(ChunkPos::new(0, -1, 0));
+        for x in 0..CHUNK_SIZE {
+            for y in 0..CHUNK_SIZE {
+                for z in 0..CHUNK_SIZE {
+                    solid.set_block(x, y, z, Block::Generic(stone));
+                }
+            }
+        }
+        solid.init_light(&registry);
+        assert_eq!(solid.get_light().uniform_level(LightKind::Sky), Some(0));
+
+        // Light is only split up once a block has a different level
+        let mut light = LightStorage::dark();
+        light.set(LightKind::Sky, 1, 2, 3, 0);
+        assert_eq!(light.uniform_level(LightKind::Sky), Some(0));
+        light.set(LightKind::Sky, 1, 2, 3, 7);
+        assert_eq!(light.uniform_level(LightKind::Sky), None);
+        assert_eq!(light.get(LightKind::Sky, 1, 2, 3), 7);
+        assert_eq!(light.get(LightKind::Sky, 1, 2, 4), 0);
+    }
+}
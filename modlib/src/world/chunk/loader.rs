@@ -1,8 +1,20 @@
-use bevy::prelude::*;
+use bevy::{prelude::*, utils::{HashSet, HashMap}};
+use crate::world::{generation::BeingGenerated, position::ChunkPos};
+use super::{Chunk, events::UnloadChunkMessage, meshing::{RemeshChunkMarker, neighbours::{MissingNeighbourPolicy, MeshedNeighbours, NEIGHBOUR_SIDES, opposite_side}}, registry::{Chunks, ChunkState}, ticket::{ChunkTickets, ChunkTicket, TicketId, TicketKind, TicketLevel}};
 
+pub use super::ticket::UNLOAD_HYSTERESIS;
+
+/// Loads chunks in a sphere around the entity, at `TicketLevel::EntityTicking`. Requires a `GlobalTransform`.
 #[derive(Component)]
 pub struct ChunkLoader {
-    distance: f32,
+    /// The radius of the loaded sphere, in chunks.
+    pub distance: f32,
+}
+
+impl ChunkLoader {
+    pub fn new(distance: f32) -> Self {
+        Self { distance }
+    }
 }
 
 impl Default for ChunkLoader {
@@ -11,4 +23,135 @@ impl Default for ChunkLoader {
             distance: 16.0,
         }
     }
-}
\ No newline at end of file
+}
+
+/// Keeps track of the tickets added for `ChunkLoader`s.
+#[derive(Resource, Default)]
+pub struct ChunkStreaming {
+    /// The chunk each loader was in, its distance, and its ticket, the last time chunks were streamed.
+    loaders: HashMap<Entity, (ChunkPos, f32, TicketId)>,
+}
+
+impl ChunkStreaming {
+    /// Returns `true` if a `ChunkLoader` keeps the chunk loaded.
+    #[deprecated(note = "chunks are kept loaded by tickets, use `ChunkTickets::is_ticketed` instead")]
+    pub fn is_requested(&self, coord: ChunkPos) -> bool {
+        self.loaders.values().any(|(center, distance, _)| {
+            let keep_distance = distance + UNLOAD_HYSTERESIS;
+            (IVec3::from(coord) - IVec3::from(*center)).as_vec3().length_squared() <= keep_distance * keep_distance
+        })
+    }
+}
+
+/// Moves the ticket of each `ChunkLoader` along with it. The chunks themselves are loaded and unloaded by `chunk_ticket_system`.
+/// Only changes tickets when a loader moves into a different chunk, changes its distance, or is added or removed.
+pub(crate) fn chunk_streaming_system(
+    mut streaming: ResMut<ChunkStreaming>,
+    mut tickets: ResMut<ChunkTickets>,
+    loaders: Query<(Entity, &ChunkLoader, &GlobalTransform)>,
+) {
+    let current: HashMap<Entity, (ChunkPos, f32)> = loaders.iter()
+        .map(|(entity, loader, transform)| (entity, (ChunkPos::containing(transform.translation()), loader.distance)))
+        .collect();
+
+    // Removed loaders, and loaders that moved, lose their old ticket
+    streaming.loaders.retain(|entity, (center, distance, ticket)| {
+        if current.get(entity) == Some(&(*center, *distance)) { return true; }
+        tickets.remove(*ticket);
+        false
+    });
+
+    for (entity, (center, distance)) in current {
+        if streaming.loaders.contains_key(&entity) { continue; }
+        let ticket = tickets.add(ChunkTicket::new(TicketKind::Loader(entity), center, distance, TicketLevel::EntityTicking));
+        streaming.loaders.insert(entity, (center, distance, ticket));
+    }
+}
+
+/// Marks a chunk that is being unloaded. The chunk is despawned in the next frame.
+#[derive(Component)]
+pub struct Unloading;
+
+/// Starts unloading chunks in response to `UnloadChunkMessage`.
+///
+/// The chunk is moved to the `Unloading` state, so systems reacting to `ChunkUnloadingEvent` still have a frame to access it,
+/// and is despawned by `chunk_despawn_system` in the next frame.
+pub(crate) fn chunk_unload_system(
+    mut commands: Commands,
+    mut events: EventReader<UnloadChunkMessage>,
+    mut chunk_registry: ResMut<Chunks>,
+    chunks: Query<(Option<&Chunk>, Option<&BeingGenerated>), Without<Unloading>>,
+) {
+    for event in events.iter() {
+        let position = match chunks.get(event.0) {
+            Ok((Some(chunk), _)) => chunk.get_position(),
+            Ok((None, Some(being_generated))) => being_generated.get_position(),
+            _ => continue,
+        };
+
+        // Don't unload a different chunk that has since been loaded in the same place, or a chunk twice
+        let state = chunk_registry.get(position);
+        if state.entity() != Some(event.0) || matches!(state, ChunkState::Unloading(_)) { continue; }
+
+        chunk_registry.transition_or_warn(position, ChunkState::Unloading(event.0));
+        commands.entity(event.0).insert(Unloading);
+    }
+}
+
+/// Chunks that started unloading, and haven't been despawned yet.
+type UnloadingChunks<'w, 's> = Query<'w, 's, (Entity, Option<&'static Chunk>, Option<&'static BeingGenerated>), With<Unloading>>;
+
+/// Despawns chunks that started unloading in the previous frame.
+///
+/// Despawns the chunk and all of its block entities, and removes it from the registry.
+/// Any generation or meshing task still running for the chunk is cancelled when its component is dropped with the entity.
+/// Loaded neighbours are remeshed if their mesh depends on the chunk, as decided by the `MissingNeighbourPolicy`.
+pub(crate) fn chunk_despawn_system(
+    mut commands: Commands,
+    mut chunk_registry: ResMut<Chunks>,
+    policy: Res<MissingNeighbourPolicy>,
+    chunks: UnloadingChunks,
+    meshed: Query<Option<&MeshedNeighbours>, Without<Unloading>>,
+) {
+    let mut unloaded: HashSet<ChunkPos> = HashSet::new();
+
+    for (entity, chunk, being_generated) in chunks.iter() {
+        let position = match (chunk, being_generated) {
+            (Some(chunk), _) => {
+                let block_entities: HashSet<Entity> = chunk.get_block_entities()
+                    .chain(chunk.get_removed_block_entities())
+                    .collect();
+                for entity in block_entities {
+                    commands.entity(entity).despawn_recursive();
+                }
+                chunk.get_position()
+            },
+            (None, Some(being_generated)) => being_generated.get_position(),
+            (None, None) => continue,
+        };
+
+        if chunk_registry.get(position) == ChunkState::Unloading(entity) {
+            chunk_registry.transition_or_warn(position, ChunkState::Absent);
+        }
+
+        commands.entity(entity).despawn_recursive();
+        unloaded.insert(position);
+    }
+
+    // Chunks that wait for their neighbours keep their mesh until the neighbour is loaded again
+    if *policy == MissingNeighbourPolicy::Wait { return; }
+
+    for position in unloaded.iter() {
+        for (side, (offset, _, _)) in NEIGHBOUR_SIDES.into_iter().enumerate() {
+            let neighbour = *position + offset;
+            if unloaded.contains(&neighbour) { continue; }
+            let entity = match chunk_registry.get(neighbour).loaded_entity() {
+                Some(entity) => entity,
+                None => continue,
+            };
+            if MeshedNeighbours::needs_remesh(meshed.get(entity).ok().flatten(), opposite_side(side), policy.missing_face()) {
+                commands.entity(entity).insert(RemeshChunkMarker);
+            }
+        }
+    }
+}
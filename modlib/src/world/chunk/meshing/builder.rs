@@ -0,0 +1,341 @@
+//! `MeshBuilder`, which meshing passes write their vertices into.
+//!
+//! A mesh has any number of vertex attributes, and every attribute needs one value per vertex. The builder checks that
+//! after every pass, so a pass that writes too many or too few values fails on its own instead of producing a broken mesh.
+//! The built-in attributes have to be written by every pass that adds vertices. Passes that add their own attributes
+//! declare them first, and every other pass gets default values for them.
+
+use std::{collections::BTreeMap, fmt::Display};
+use bevy::{
+    prelude::{Mesh, Vec3},
+    render::{
+        mesh::{Indices, MeshVertexAttribute, MeshVertexAttributeId, VertexAttributeValues},
+        render_resource::{PrimitiveTopology, VertexFormat},
+    },
+};
+use super::texture::ATTRIBUTE_TEXTURE_LAYER;
+
+/// The attributes every mesh has, which every pass has to write to whenever it adds vertices.
+const BUILT_IN_ATTRIBUTES: [MeshVertexAttribute; 5] =
+    [Mesh::ATTRIBUTE_POSITION, Mesh::ATTRIBUTE_NORMAL, Mesh::ATTRIBUTE_UV_0, Mesh::ATTRIBUTE_COLOR, ATTRIBUTE_TEXTURE_LAYER];
+
+/// A value of a single vertex attribute, like `[f32; 3]` for `VertexFormat::Float32x3`.
+pub trait VertexValue: Copy + Default + Send + Sync + 'static {
+    const FORMAT: VertexFormat;
+    /// The values of an attribute, if they have this type.
+    fn values_mut(values: &mut VertexAttributeValues) -> Option<&mut Vec<Self>>;
+}
+
+macro_rules! vertex_values {
+    ($($type:ty => $format:ident),* $(,)?) => {
+        $(
+            impl VertexValue for $type {
+                const FORMAT: VertexFormat = VertexFormat::$format;
+
+                fn values_mut(values: &mut VertexAttributeValues) -> Option<&mut Vec<Self>> {
+                    match values {
+                        VertexAttributeValues::$format(values) => Some(values),
+                        _ => None,
+                    }
+                }
+            }
+        )*
+
+        /// No values for an attribute of a format, or `None` if the format isn't supported.
+        fn empty_values(format: VertexFormat) -> Option<VertexAttributeValues> {
+            match format {
+                $(VertexFormat::$format => Some(VertexAttributeValues::$format(vec![])),)*
+                _ => None,
+            }
+        }
+
+        /// Adds default values to the end of an attribute until it has `len` values.
+        fn pad_values(values: &mut VertexAttributeValues, len: usize) {
+            match values {
+                $(VertexAttributeValues::$format(values) => values.resize(len, Default::default()),)*
+                _ => unreachable!("only supported formats can be declared"),
+            }
+        }
+    };
+}
+
+vertex_values! {
+    f32 => Float32,
+    i32 => Sint32,
+    u32 => Uint32,
+    [f32; 2] => Float32x2,
+    [i32; 2] => Sint32x2,
+    [u32; 2] => Uint32x2,
+    [f32; 3] => Float32x3,
+    [i32; 3] => Sint32x3,
+    [u32; 3] => Uint32x3,
+    [f32; 4] => Float32x4,
+    [i32; 4] => Sint32x4,
+    [u32; 4] => Uint32x4,
+}
+
+/// Returned when a pass leaves a `MeshBuilder` with attributes or indices that don't match its vertices.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MeshBuilderError {
+    /// An attribute has a different number of values than there are vertices.
+    LengthMismatch { attribute: &'static str, vertices: usize, values: usize },
+    /// An index points past the last vertex.
+    IndexOutOfRange { index: u32, vertices: usize },
+    /// The number of indices isn't a multiple of three, so they aren't all whole triangles.
+    IncompleteTriangle { indices: usize },
+}
+
+impl Display for MeshBuilderError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            MeshBuilderError::LengthMismatch { attribute, vertices, values } => {
+                write!(f, "attribute {attribute} has {values} values, but there are {vertices} vertices")
+            },
+            MeshBuilderError::IndexOutOfRange { index, vertices } => {
+                write!(f, "index {index} is out of range for {vertices} vertices")
+            },
+            MeshBuilderError::IncompleteTriangle { indices } => {
+                write!(f, "{indices} indices don't make up whole triangles")
+            },
+        }
+    }
+}
+
+impl std::error::Error for MeshBuilderError {}
+
+/// Collects the vertices and indices of a triangle list mesh from every meshing pass.
+///
+/// Position, normal, UV, color, and `ATTRIBUTE_TEXTURE_LAYER` are always declared, and every pass has to write them.
+/// Vertices are counted by their positions. Passes that only add vertices are indexed in order, so passes with and without indices can be mixed.
+pub struct MeshBuilder {
+    attributes: BTreeMap<MeshVertexAttributeId, (MeshVertexAttribute, VertexAttributeValues)>,
+    indices: Vec<u32>,
+    /// The number of vertices and indices when the current pass started.
+    pass_start: (usize, usize),
+    /// Custom attributes declared by the current pass, which it has to write to like the built-in ones.
+    declared_in_pass: Vec<MeshVertexAttributeId>,
+}
+
+impl Default for MeshBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl MeshBuilder {
+    pub fn new() -> Self {
+        let mut builder = Self {
+            attributes: BTreeMap::new(),
+            indices: vec![],
+            pass_start: (0, 0),
+            declared_in_pass: vec![],
+        };
+        for attribute in BUILT_IN_ATTRIBUTES {
+            builder.declare(attribute);
+        }
+        builder.declared_in_pass.clear();
+        builder
+    }
+
+    /// Declares a custom attribute. Vertices that were already added get the default value for it.
+    /// Does nothing if it's already declared. Panics if the attribute's format isn't supported by `VertexValue`.
+    pub fn declare(&mut self, attribute: MeshVertexAttribute) {
+        if self.attributes.contains_key(&attribute.id) { return; }
+        let mut values = empty_values(attribute.format)
+            .unwrap_or_else(|| panic!("vertex attribute {} has an unsupported format", attribute.name));
+        pad_values(&mut values, self.pass_start.0);
+        self.declared_in_pass.push(attribute.id);
+        self.attributes.insert(attribute.id, (attribute, values));
+    }
+
+    /// The number of vertices, which is the number of positions.
+    pub fn vertex_count(&self) -> usize {
+        self.attributes[&Mesh::ATTRIBUTE_POSITION.id].1.len()
+    }
+
+    /// Adds values to the end of an attribute.
+    /// Panics if the attribute hasn't been declared, or its format doesn't match `T`.
+    pub fn extend<T: VertexValue>(&mut self, attribute: MeshVertexAttribute, values: impl IntoIterator<Item = T>) {
+        let (_, existing) = self.attributes.get_mut(&attribute.id)
+            .unwrap_or_else(|| panic!("vertex attribute {} hasn't been declared", attribute.name));
+        T::values_mut(existing)
+            .unwrap_or_else(|| panic!("vertex attribute {} doesn't have the format {:?}", attribute.name, T::FORMAT))
+            .extend(values);
+    }
+
+    /// Adds indices of vertices to draw as triangles. Indices count from the first vertex of the whole mesh.
+    pub fn extend_indices(&mut self, indices: impl IntoIterator<Item = u32>) {
+        self.indices.extend(indices);
+    }
+
+    /// Adds a quad from four corners going around it, as two triangles facing towards `normal`.
+    /// The quad is split along the diagonal from the first corner to the third, or from the second to the fourth if
+    /// `flip` is set. Only positions, normals, and indices are added, so the pass still needs to add the quad's other attributes.
+    pub fn extend_quad(&mut self, corners: [[f32; 3]; 4], normal: [f32; 3], flip: bool) {
+        let first = self.vertex_count() as u32;
+        let [a, b, c, _] = corners.map(Vec3::from);
+        // Front faces are counter-clockwise, so flip the quad if the corners go around the other way
+        let counter_clockwise = (b - a).cross(c - a).dot(Vec3::from(normal)) >= 0.0;
+        let order = match (counter_clockwise, flip) {
+            (true, false) => [0, 1, 2, 0, 2, 3],
+            (true, true) => [0, 1, 3, 1, 2, 3],
+            (false, false) => [0, 2, 1, 0, 3, 2],
+            (false, true) => [0, 3, 1, 1, 3, 2],
+        };
+        self.extend(Mesh::ATTRIBUTE_POSITION, corners);
+        self.extend(Mesh::ATTRIBUTE_NORMAL, [normal; 4]);
+        self.extend_indices(order.map(|index| first + index));
+    }
+
+    /// Starts a pass. Custom attributes declared by other passes that this one doesn't write to are padded with
+    /// default values when it finishes.
+    pub(crate) fn begin_pass(&mut self) {
+        self.pass_start = (self.vertex_count(), self.indices.len());
+        self.declared_in_pass.clear();
+    }
+
+    /// Finishes a pass, checking that the built-in attributes, the ones it declared, and every other attribute it wrote
+    /// to have a value for every vertex, and that its indices are whole triangles of existing vertices.
+    pub(crate) fn finish_pass(&mut self) -> Result<(), MeshBuilderError> {
+        let vertices = self.vertex_count();
+        let (start_vertices, start_indices) = self.pass_start;
+
+        for (attribute, values) in self.attributes.values_mut() {
+            let required = BUILT_IN_ATTRIBUTES.iter().any(|built_in| built_in.id == attribute.id)
+                || self.declared_in_pass.contains(&attribute.id);
+            if values.len() == start_vertices && !required {
+                pad_values(values, vertices);
+            } else if values.len() != vertices {
+                return Err(MeshBuilderError::LengthMismatch { attribute: attribute.name, vertices, values: values.len() });
+            }
+        }
+
+        if self.indices.len() == start_indices {
+            self.indices.extend(start_vertices as u32..vertices as u32);
+        }
+        let added = &self.indices[start_indices..];
+        if added.len() % 3 != 0 {
+            return Err(MeshBuilderError::IncompleteTriangle { indices: added.len() });
+        }
+        if let Some(index) = added.iter().find(|index| **index as usize >= vertices) {
+            return Err(MeshBuilderError::IndexOutOfRange { index: *index, vertices });
+        }
+
+        self.pass_start = (vertices, self.indices.len());
+        self.declared_in_pass.clear();
+        Ok(())
+    }
+
+    /// Checks everything written since the last pass, and builds the mesh.
+    /// Uses 16-bit indices when there are few enough vertices.
+    pub fn build(mut self) -> Result<Mesh, MeshBuilderError> {
+        self.finish_pass()?;
+
+        let mut mesh = Mesh::new(PrimitiveTopology::TriangleList);
+        let indices = if self.vertex_count() <= u16::MAX as usize + 1 {
+            Indices::U16(self.indices.into_iter().map(|index| index as u16).collect())
+        } else {
+            Indices::U32(self.indices)
+        };
+        mesh.set_indices(Some(indices));
+        for (attribute, values) in self.attributes.into_values() {
+            mesh.insert_attribute(attribute, values);
+        }
+
+        Ok(mesh)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ATTRIBUTE_TEST: MeshVertexAttribute = MeshVertexAttribute::new("Vertex_Test", 3_771_920_663, VertexFormat::Float32);
+
+    /// Adds `count` vertices with every built-in attribute.
+    fn extend_vertices(builder: &mut MeshBuilder, count: usize) {
+        builder.extend(Mesh::ATTRIBUTE_POSITION, vec![[1.0f32; 3]; count]);
+        builder.extend(Mesh::ATTRIBUTE_NORMAL, vec![[0.0f32, 1.0, 0.0]; count]);
+        builder.extend(Mesh::ATTRIBUTE_UV_0, vec![[0.0f32; 2]; count]);
+        builder.extend(Mesh::ATTRIBUTE_COLOR, vec![[1.0f32; 4]; count]);
+        builder.extend(ATTRIBUTE_TEXTURE_LAYER, vec![0u32; count]);
+    }
+
+    #[test]
+    fn passes_are_checked_and_padded() {
+        let mut builder = MeshBuilder::new();
+        builder.begin_pass();
+        extend_vertices(&mut builder, 3);
+        builder.finish_pass().unwrap();
+
+        // Declared after the first pass, so its vertices get the default
+        builder.begin_pass();
+        builder.declare(ATTRIBUTE_TEST);
+        extend_vertices(&mut builder, 4);
+        builder.extend(ATTRIBUTE_TEST, [2.0f32; 4]);
+        builder.extend_indices([3, 4, 5, 5, 4, 6]);
+        builder.finish_pass().unwrap();
+
+        // Passes that don't use it get the default too
+        builder.begin_pass();
+        extend_vertices(&mut builder, 3);
+        builder.finish_pass().unwrap();
+
+        builder.begin_pass();
+        extend_vertices(&mut builder, 3);
+        builder.extend(ATTRIBUTE_TEST, [2.0f32; 2]);
+        assert_eq!(builder.finish_pass(), Err(MeshBuilderError::LengthMismatch { attribute: "Vertex_Test", vertices: 13, values: 12 }));
+
+        let mut builder = MeshBuilder::new();
+        extend_vertices(&mut builder, 3);
+        builder.extend_indices([0, 1, 3]);
+        assert_eq!(builder.build().err(), Some(MeshBuilderError::IndexOutOfRange { index: 3, vertices: 3 }));
+
+        let mut builder = MeshBuilder::new();
+        builder.declare(ATTRIBUTE_TEST);
+        builder.begin_pass();
+        extend_vertices(&mut builder, 6);
+        let mesh = builder.build().unwrap();
+        assert_eq!(mesh.count_vertices(), 6);
+        assert_eq!(mesh.attribute(ATTRIBUTE_TEST).map(VertexAttributeValues::len), Some(6));
+        assert!(matches!(mesh.indices(), Some(Indices::U16(indices)) if indices.len() == 6));
+    }
+
+    #[test]
+    fn required_attributes_are_never_padded() {
+        // Built-in attributes have no sensible default
+        let mut builder = MeshBuilder::new();
+        builder.begin_pass();
+        builder.extend(Mesh::ATTRIBUTE_POSITION, [[0.0f32; 3]; 3]);
+        builder.extend(Mesh::ATTRIBUTE_NORMAL, [[0.0f32, 1.0, 0.0]; 3]);
+        assert_eq!(builder.finish_pass(), Err(MeshBuilderError::LengthMismatch { attribute: Mesh::ATTRIBUTE_UV_0.name, vertices: 3, values: 0 }));
+
+        // Neither do attributes the pass declared itself
+        let mut builder = MeshBuilder::new();
+        builder.begin_pass();
+        builder.declare(ATTRIBUTE_TEST);
+        extend_vertices(&mut builder, 3);
+        assert_eq!(builder.finish_pass(), Err(MeshBuilderError::LengthMismatch { attribute: "Vertex_Test", vertices: 3, values: 0 }));
+    }
+
+    #[test]
+    fn quads_face_their_normal() {
+        let corners = [[0.0, 0.0, 0.0], [1.0, 0.0, 0.0], [1.0, 1.0, 0.0], [0.0, 1.0, 0.0]];
+        let mut builder = MeshBuilder::new();
+        for flip in [false, true] {
+            builder.extend_quad(corners, [0.0, 0.0, 1.0], flip);
+            builder.extend_quad(corners, [0.0, 0.0, -1.0], flip);
+        }
+        builder.extend(Mesh::ATTRIBUTE_UV_0, [[0.0f32; 2]; 16]);
+        builder.extend(Mesh::ATTRIBUTE_COLOR, [[1.0f32; 4]; 16]);
+        builder.extend(ATTRIBUTE_TEXTURE_LAYER, [0u32; 16]);
+        let mesh = builder.build().unwrap();
+        assert_eq!(mesh.count_vertices(), 16);
+
+        let indices: Vec<usize> = mesh.indices().unwrap().iter().collect();
+        for (triangle, normal) in indices.chunks(3).zip([1.0, 1.0, -1.0, -1.0].repeat(2)) {
+            let [a, b, c] = [0, 1, 2].map(|i| Vec3::from(corners[triangle[i] % 4]));
+            assert_eq!((b - a).cross(c - a).z.signum(), normal);
+        }
+    }
+}
@@ -0,0 +1,58 @@
+#import bevy_pbr::mesh_view_bindings
+#import bevy_pbr::mesh_bindings
+
+// NOTE: Bindings must come before functions that use them!
+#import bevy_pbr::mesh_functions
+
+@group(1) @binding(0)
+var block_textures: texture_2d_array<f32>;
+@group(1) @binding(1)
+var block_sampler: sampler;
+
+// Lambertian diffuse is divided by pi, the same as the standard material
+let FRAC_1_PI: f32 = 0.3183098861837907;
+
+struct Vertex {
+    @location(0) position: vec3<f32>,
+    @location(1) normal: vec3<f32>,
+    @location(2) uv: vec2<f32>,
+    @location(3) color: vec4<f32>,
+    @location(4) texture_layer: u32,
+};
+
+struct VertexOutput {
+    @builtin(position) clip_position: vec4<f32>,
+    @location(0) uv: vec2<f32>,
+    @location(1) color: vec4<f32>,
+    @location(2) @interpolate(flat) texture_layer: u32,
+    @location(3) world_normal: vec3<f32>,
+};
+
+@vertex
+fn vertex(vertex: Vertex) -> VertexOutput {
+    var out: VertexOutput;
+    out.clip_position = mesh_position_local_to_clip(mesh.model, vec4<f32>(vertex.position, 1.0));
+    out.uv = vertex.uv;
+    out.color = vertex.color;
+    out.texture_layer = vertex.texture_layer;
+    out.world_normal = mesh_normal_local_to_world(vertex.normal);
+    return out;
+}
+
+// How much light reaches a face from the ambient light and every directional light, without shadows.
+// Block and sky light are already in the vertex colors, so this only gives each side of a block its own shade.
+fn shading(normal: vec3<f32>) -> vec3<f32> {
+    var shade = lights.ambient_color.rgb;
+    for (var i = 0u; i < lights.n_directional_lights; i = i + 1u) {
+        let light = lights.directional_lights[i];
+        shade = shade + light.color.rgb * max(dot(normal, light.direction_to_light), 0.0) * FRAC_1_PI;
+    }
+    return shade;
+}
+
+@fragment
+fn fragment(in: VertexOutput) -> @location(0) vec4<f32> {
+    // UVs are in blocks and the sampler repeats, so merged quads show the texture once per block
+    let color = textureSample(block_textures, block_sampler, in.uv, i32(in.texture_layer)) * in.color;
+    return vec4<f32>(color.rgb * shading(normalize(in.world_normal)), color.a);
+}
@@ -1,18 +1,45 @@
-use crate::world::{chunk::CHUNK_SIZE, block::{BlockId, registry::BlockRegistryInternal}};
+use crate::world::{chunk::CHUNK_SIZE, block::{state::BlockStateId, registry::BlockRegistryInternal}};
 
-/// Somewhat flexible greedy meshing algorithm. Operates over a 2D slice of `BlockId` objects to generate a set of quads.
-/// Each quad is a single `BlockId` type. The algorithm will not create a quad that would contain multiple BlockIds. Quads will always be rectangular and will never create quads that overlap.
+/// A cell of a slice given to `greedy_determine_quads`. Cells are only merged into the same quad if they're equal.
+pub trait GreedyCell: Copy + PartialEq {
+    /// The block in this cell.
+    fn block(&self) -> BlockStateId;
+}
+
+impl GreedyCell for BlockStateId {
+    fn block(&self) -> BlockStateId {
+        *self
+    }
+}
+
+/// A block and its packed light level, as used by `MeshingPass::do_pass`.
+impl GreedyCell for (BlockStateId, u8) {
+    fn block(&self) -> BlockStateId {
+        self.0
+    }
+}
+
+/// A block, its packed light level, and the ambient occlusion of its corners, as used by `SolidBlockMesher`.
+/// Faces are only merged if their ambient occlusion is the same, since it's interpolated across the whole quad.
+impl GreedyCell for (BlockStateId, u8, [u8; 4]) {
+    fn block(&self) -> BlockStateId {
+        self.0
+    }
+}
+
+/// Somewhat flexible greedy meshing algorithm. Operates over a 2D slice of `GreedyCell` objects to generate a set of quads.
+/// Each quad is a single cell type. The algorithm will not create a quad that would contain multiple different cells. Quads will always be rectangular and will never create quads that overlap.
 /// 
 /// Takes the following arguments:
-/// - A 2D 'slice' of the chunk (not in the Rust sense) that will be looped over.
+/// - A 2D 'slice' of the chunk (not in the Rust sense) that will be looped over. Cells containing `BlockStateId::EMPTY` are skipped.
 /// - A reference to a BlockRegistryInternal to use for comparisons.
-/// - A `Fn(&BlockId, &BlockRegistryInternal) -> bool` (called the Selector) object to check if a block should be meshed.
+/// - A `Fn(&BlockStateId, &BlockRegistryInternal) -> bool` (called the Selector) object to check if a block should be meshed.
 /// 
 /// Implementation of the greedy meshing algorithm based on the following resources.
 /// - https://0fps.net/2012/06/30/meshing-in-a-minecraft-game/s
 /// - https://devforum.roblox.com/t/consume-everything-how-greedy-meshing-works/452717
 #[doc(hidden)]
-pub fn greedy_determine_quads<Selector: Fn(&BlockId, &BlockRegistryInternal) -> bool>(slice: &[[BlockId; CHUNK_SIZE]; CHUNK_SIZE], registry: &BlockRegistryInternal, selector: Selector) -> Vec<(BlockId, [u8; 4])> {
+pub fn greedy_determine_quads<Cell: GreedyCell, Selector: Fn(&BlockStateId, &BlockRegistryInternal) -> bool>(slice: &[[Cell; CHUNK_SIZE]; CHUNK_SIZE], registry: &BlockRegistryInternal, selector: Selector) -> Vec<(Cell, [u8; 4])> {
     let mut quads = vec![];
     let mut occupied = [[false; CHUNK_SIZE]; CHUNK_SIZE];
 
@@ -20,7 +47,7 @@ pub fn greedy_determine_quads<Selector: Fn(&BlockId, &BlockRegistryInternal) ->
     for block_x in 0..CHUNK_SIZE {
         for block_y in 0..CHUNK_SIZE {
             // Skip the block if it's already occupied by a quad or it's empty
-            if occupied[block_x][block_y] || slice[block_x][block_y] == BlockId::EMPTY {
+            if occupied[block_x][block_y] || slice[block_x][block_y].block() == BlockStateId::EMPTY {
                 continue;
             }
 
@@ -30,7 +57,7 @@ pub fn greedy_determine_quads<Selector: Fn(&BlockId, &BlockRegistryInternal) ->
             // Check rows
             let mut offset_x = 0;
             for check_x in block_x..CHUNK_SIZE {
-                if slice[check_x][block_y] != current_block || !selector(&current_block, registry) {
+                if slice[check_x][block_y] != current_block || !selector(&current_block.block(), registry) {
                     break;
                 }
                 offset_x += 1;
@@ -40,7 +67,7 @@ pub fn greedy_determine_quads<Selector: Fn(&BlockId, &BlockRegistryInternal) ->
             let mut offset_y = 0;
             'column_checker: for check_y in block_y..CHUNK_SIZE {
                 for b in block_x..block_x + offset_x {
-                    if occupied[b][check_y] || slice[b][check_y] != current_block || !selector(&current_block, registry) {
+                    if occupied[b][check_y] || slice[b][check_y] != current_block || !selector(&current_block.block(), registry) {
                         break 'column_checker;
                     }
                 }
@@ -67,4 +94,16 @@ pub fn greedy_determine_quads<Selector: Fn(&BlockId, &BlockRegistryInternal) ->
     }
 
     quads
-}
\ No newline at end of file
+}
+
+/// The corners of a quad from `greedy_determine_quads` on a slice along `axis`, `depth` blocks into the chunk.
+/// The two indices of the slice are the two other axes in order, so a slice along Y is indexed by X and then Z.
+/// Corners go around the quad, and `MeshBuilder::extend_quad` decides which way they face.
+pub(crate) fn quad_corners(axis: usize, depth: f32, quad: [u8; 4]) -> [[f32; 3]; 4] {
+    let [a0, b0, a1, b1] = quad.map(f32::from);
+    [[a0, b0], [a1, b0], [a1, b1], [a0, b1]].map(|[a, b]| match axis {
+        0 => [depth, a, b],
+        1 => [a, depth, b],
+        _ => [a, b, depth],
+    })
+}
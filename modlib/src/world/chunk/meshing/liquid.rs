@@ -1,6 +1,6 @@
-use ndarray::{Array3, Axis};
-use crate::world::{block::{BlockId, registry::{BLOCK_REGISTRY, BlockRegistryInternal}, data::BlockData}, chunk::{CHUNK_SIZE, meshing::solid::color_extend}};
-use super::{MeshingPass, greedy::greedy_determine_quads, MeshingPassIdentifier};
+use ndarray::Axis;
+use crate::{sync::RecoverPoison, world::{block::{state::BlockStateId, registry::{BLOCK_REGISTRY, BlockRegistryInternal}, data::BlockData}, chunk::{CHUNK_SIZE, meshing::solid::extend_face}}};
+use super::{MeshingPass, MeshingContext, greedy::{greedy_determine_quads, quad_corners}, MeshingPassIdentifier, builder::MeshBuilder};
 
 pub const LIQUID_MESHER_PASS: MeshingPassIdentifier = MeshingPassIdentifier::new("engine_liquid", 1);
 
@@ -9,53 +9,33 @@ pub struct LiquidMesher;
 impl MeshingPass for LiquidMesher {
     fn do_pass(
         &self,
-        positions: &mut Vec<[f32;3]>,
-        normals: &mut Vec<[f32;3]>,
-        uvs: &mut Vec<[f32;2]>,
-        colors: &mut Vec<[f32;4]>,
-        data: &Array3<BlockId>
+        mesh: &mut MeshBuilder,
+        context: &MeshingContext,
     ) {
-        let registry = BLOCK_REGISTRY.read().unwrap();
+        let (data, light) = (context.data, context.light);
+        let registry = BLOCK_REGISTRY.read_or_recover();
 
-        fn selector(block: &BlockId, registry: &BlockRegistryInternal) -> bool {
-            registry.get_by_numerical_id(*block).unwrap().get_attribute(BlockData::ATTRIBUTE_USE_LIQUID_MESHER).is_some()
+        fn selector(block: &BlockStateId, registry: &BlockRegistryInternal) -> bool {
+            registry.get_by_state(*block).is_some_and(|data| data.get_attribute(BlockData::ATTRIBUTE_USE_LIQUID_MESHER).is_some())
         }
         
         for y in 1..CHUNK_SIZE+1 {
             let array_subview = data.index_axis(Axis(1), y);
-            let mut layer = [[BlockId::EMPTY; CHUNK_SIZE]; CHUNK_SIZE];
+            let mut layer = [[(BlockStateId::EMPTY, 0); CHUNK_SIZE]; CHUNK_SIZE];
             for x in 1..CHUNK_SIZE+1 {
                 for z in 1..CHUNK_SIZE+1 {
                     let this_block = array_subview[[x, z]];
                     if selector(&this_block, &registry) && this_block != data[[x, y+1, z]] {
-                        layer[x-1][z-1] = this_block;
+                        layer[x-1][z-1] = (this_block, light[[x, y+1, z]]);
                     }
                 }
             }
 
             let y = y as f32 - 0.15;
 
-            const UVS: [[f32; 2]; 6] = [
-                [0.0, 0.0],
-                [0.0, 1.0],
-                [1.0, 0.0],
-                [1.0, 0.0],
-                [0.0, 1.0],
-                [1.0, 1.0],
-            ];
-
-            for (block, quad) in greedy_determine_quads(&layer, &registry, selector) {
-                positions.extend([
-                    [quad[0] as f32, y, quad[1] as f32],
-                    [quad[0] as f32, y, quad[3] as f32],
-                    [quad[2] as f32, y, quad[1] as f32],
-                    [quad[0] as f32, y, quad[3] as f32],
-                    [quad[2] as f32, y, quad[3] as f32],
-                    [quad[2] as f32, y, quad[1] as f32],
-                ]);
-                normals.extend([[0.0, -1.0, 0.0]; 6]);
-                uvs.extend(UVS);
-                color_extend(colors, block, &registry);
+            for ((block, light), quad) in greedy_determine_quads(&layer, &registry, selector) {
+                // Liquids only have a top surface
+                extend_face(mesh, quad_corners(1, y, quad), 2, block, light, [3; 4], &registry);
             }
         }
     }
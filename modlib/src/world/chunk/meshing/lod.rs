@@ -0,0 +1,227 @@
+//! Levels of detail for distant chunks.
+//!
+//! Chunks far from every camera are meshed from a downsampled copy of their blocks, where each cell of 2, 4, or 8 blocks
+//! across is filled with the most common block in it. The copy has the same shape as the full one, so the usual
+//! meshing passes mesh it as they are, and greedy meshing merges each cell into a few large quads.
+//!
+//! Neighbours at different levels don't line up, which leaves cracks along the border. To hide them, chunks mesh every
+//! face along a border with a chunk at a different level as if the neighbour were empty. These faces are skirts
+//! that are hidden behind the surface of both chunks, except where there's a crack.
+
+use bevy::prelude::*;
+use ndarray::Array3;
+use crate::world::{block::state::BlockStateId, chunk::{Chunk, CHUNK_SIZE, CHUNK_SIZE_F32, failure::ChunkFailure, loader::Unloading, registry::Chunks}, position::ChunkPos};
+use super::{RemeshChunkMarker, neighbours::NEIGHBOUR_SIDES};
+
+/// How detailed a chunk's mesh is. Added to chunks by `chunk_lod_system`, and chunks without it are at full detail.
+#[derive(Component, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Default)]
+pub enum ChunkLod {
+    #[default]
+    Full,
+    Half,
+    Quarter,
+    Eighth,
+}
+
+impl ChunkLod {
+    /// The width of each cell of blocks that's merged into one, in blocks.
+    pub fn scale(&self) -> usize {
+        match self {
+            ChunkLod::Full => 1,
+            ChunkLod::Half => 2,
+            ChunkLod::Quarter => 4,
+            ChunkLod::Eighth => 8,
+        }
+    }
+}
+
+/// The distances to the nearest camera, in chunks, past which chunks are meshed at a lower level of detail.
+#[derive(Resource, Debug, Clone)]
+pub struct LodDistances {
+    pub half: f32,
+    pub quarter: f32,
+    pub eighth: f32,
+    /// How much closer than the distance of a level a chunk has to be before it moves back up to it, in chunks.
+    /// Stops chunks right on the boundary from remeshing every time the camera moves a little.
+    pub hysteresis: f32,
+}
+
+impl Default for LodDistances {
+    fn default() -> Self {
+        Self {
+            half: 8.0,
+            quarter: 16.0,
+            eighth: 32.0,
+            hysteresis: 1.0,
+        }
+    }
+}
+
+impl LodDistances {
+    /// The level of detail for a chunk `distance` chunks from the nearest camera, which currently is at `current`.
+    pub fn lod_at(&self, distance: f32, current: ChunkLod) -> ChunkLod {
+        let pick = |distance: f32| {
+            if distance >= self.eighth {
+                ChunkLod::Eighth
+            } else if distance >= self.quarter {
+                ChunkLod::Quarter
+            } else if distance >= self.half {
+                ChunkLod::Half
+            } else {
+                ChunkLod::Full
+            }
+        };
+
+        let target = pick(distance);
+        if target < current {
+            pick(distance + self.hysteresis).min(current)
+        } else {
+            target
+        }
+    }
+}
+
+/// Chunks that can be given a level of detail, because they aren't unloading or failed.
+type LodChunks<'w, 's> = Query<'w, 's, (Entity, &'static Chunk, Option<&'static ChunkLod>), (Without<Unloading>, Without<ChunkFailure>)>;
+
+/// Picks the level of detail of every chunk from its distance to the nearest camera, and remeshes chunks that change.
+/// Levels are only picked again when a camera moves into another chunk or the distances change, and for chunks that were just added.
+///
+/// Chunks next to one that changes are remeshed as well if their skirts along that border change.
+pub(crate) fn chunk_lod_system(
+    mut commands: Commands,
+    mut camera_chunks: Local<Vec<ChunkPos>>,
+    distances: Res<LodDistances>,
+    registry: Res<Chunks>,
+    cameras: Query<&GlobalTransform, With<Camera>>,
+    chunks: LodChunks,
+    added: Query<(), Added<Chunk>>,
+) {
+    let cameras: Vec<Vec3> = cameras.iter().map(GlobalTransform::translation).collect();
+    if cameras.is_empty() { return; }
+
+    let current_chunks: Vec<ChunkPos> = cameras.iter().copied().map(ChunkPos::containing).collect();
+    let all = *camera_chunks != current_chunks || distances.is_changed();
+    if !all && added.is_empty() { return; }
+    *camera_chunks = current_chunks;
+
+    for (entity, chunk, lod) in chunks.iter() {
+        if !all && !added.contains(entity) { continue; }
+        let current = lod.copied().unwrap_or_default();
+        let center = chunk.get_position().center();
+        let distance = cameras.iter()
+            .map(|camera| camera.distance(center))
+            .fold(f32::INFINITY, f32::min) / CHUNK_SIZE_F32;
+
+        let lod = distances.lod_at(distance, current);
+        if lod == current { continue; }
+        commands.entity(entity).insert((lod, RemeshChunkMarker));
+
+        for (offset, _, _) in NEIGHBOUR_SIDES {
+            let neighbour = registry.get(chunk.get_position() + offset).loaded_entity();
+            if let Some((neighbour, _, neighbour_lod)) = neighbour.and_then(|neighbour| chunks.get(neighbour).ok()) {
+                let neighbour_lod = neighbour_lod.copied().unwrap_or_default();
+                if (lod != neighbour_lod) != (current != neighbour_lod) {
+                    commands.entity(neighbour).insert(RemeshChunkMarker);
+                }
+            }
+        }
+    }
+}
+
+/// Replaces each cell of `scale` blocks across in the meshing arrays with its most common block, and its brightest light.
+/// The border of neighbouring blocks is split into cells of `scale` by `scale` blocks along the same grid, so the faces
+/// along it line up with the cells of the chunk.
+pub(crate) fn downsample(data: &mut Array3<BlockStateId>, light: &mut Array3<u8>, scale: usize) {
+    if scale <= 1 { return; }
+
+    let mut counts: Vec<(BlockStateId, usize)> = Vec::with_capacity(scale * scale * scale);
+    for cell_x in (0..CHUNK_SIZE).step_by(scale) {
+        for cell_y in (0..CHUNK_SIZE).step_by(scale) {
+            for cell_z in (0..CHUNK_SIZE).step_by(scale) {
+                // The meshing arrays have a one block border, so the chunk starts at 1
+                let cell = (0..scale * scale * scale)
+                    .map(move |i| [cell_x + i / (scale * scale) + 1, cell_y + i / scale % scale + 1, cell_z + i % scale + 1]);
+                merge_cell(data, light, cell, &mut counts);
+            }
+        }
+    }
+
+    // The border layer on each side of each axis
+    for (axis, layer) in [0, 1, 2].into_iter().flat_map(|axis| [(axis, 0), (axis, CHUNK_SIZE + 1)]) {
+        for cell_a in (0..CHUNK_SIZE).step_by(scale) {
+            for cell_b in (0..CHUNK_SIZE).step_by(scale) {
+                let cell = (0..scale * scale).map(move |i| {
+                    let (a, b) = (cell_a + i / scale + 1, cell_b + i % scale + 1);
+                    match axis {
+                        0 => [layer, a, b],
+                        1 => [a, layer, b],
+                        _ => [a, b, layer],
+                    }
+                });
+                merge_cell(data, light, cell, &mut counts);
+            }
+        }
+    }
+}
+
+/// Fills every position of `cell` with its most common block and its brightest light.
+fn merge_cell(data: &mut Array3<BlockStateId>, light: &mut Array3<u8>, cell: impl Iterator<Item = [usize; 3]> + Clone, counts: &mut Vec<(BlockStateId, usize)>) {
+    counts.clear();
+    let (mut sky, mut block) = (0, 0);
+    for position in cell.clone() {
+        match counts.iter_mut().find(|(state, _)| *state == data[position]) {
+            Some((_, count)) => *count += 1,
+            None => counts.push((data[position], 1)),
+        }
+        sky = sky.max(light[position] >> 4);
+        block = block.max(light[position] & 0xF);
+    }
+
+    // Ties go to blocks over empty space, so thin layers of terrain don't disappear
+    let dominant = counts.iter()
+        .max_by_key(|(state, count)| (*count, *state != BlockStateId::EMPTY))
+        .map_or(BlockStateId::EMPTY, |(state, _)| *state);
+    for position in cell {
+        data[position] = dominant;
+        light[position] = sky << 4 | block;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::world::chunk::meshing::SHAPE_SIZE_USIZE;
+    use super::*;
+
+    #[test]
+    fn cells_take_their_most_common_block() {
+        let shape = (SHAPE_SIZE_USIZE, SHAPE_SIZE_USIZE, SHAPE_SIZE_USIZE);
+        let mut data = Array3::from_elem(shape, BlockStateId::EMPTY);
+        let mut light = Array3::from_elem(shape, 0);
+        // Half of the first cell, and a single block of the second
+        for [x, y, z] in [[1, 1, 1], [2, 1, 1], [1, 2, 1], [2, 2, 1], [3, 1, 1]] {
+            data[[x, y, z]] = BlockStateId::UNLOADED;
+        }
+        light[[2, 2, 2]] = 0x35;
+        // Half of a cell of the neighbour on the left, and a single block of the one below
+        for [x, y, z] in [[0, 1, 1], [0, 2, 1], [1, 0, 1]] {
+            data[[x, y, z]] = BlockStateId::UNLOADED;
+        }
+
+        downsample(&mut data, &mut light, 2);
+        assert_eq!(data[[2, 2, 2]], BlockStateId::UNLOADED);
+        assert_eq!(light[[1, 1, 1]], 0x35);
+        assert_eq!(data[[3, 1, 1]], BlockStateId::EMPTY);
+        assert_eq!(data[[0, 2, 2]], BlockStateId::UNLOADED);
+        assert_eq!(data[[1, 0, 1]], BlockStateId::EMPTY);
+    }
+
+    #[test]
+    fn levels_of_detail_follow_distance() {
+        let distances = LodDistances::default();
+        assert_eq!(distances.lod_at(4.0, ChunkLod::Full), ChunkLod::Full);
+        assert_eq!(distances.lod_at(20.0, ChunkLod::Full), ChunkLod::Quarter);
+        assert_eq!(distances.lod_at(7.5, ChunkLod::Half), ChunkLod::Half);
+        assert_eq!(distances.lod_at(6.5, ChunkLod::Half), ChunkLod::Full);
+    }
+}
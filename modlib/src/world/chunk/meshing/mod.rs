@@ -1,15 +1,20 @@
-use std::{collections::BTreeMap, ops::Deref, task::Poll, sync::{Arc, RwLock}};
-use bevy::{prelude::*, render::{render_resource::PrimitiveTopology, mesh::{Indices, MeshVertexAttribute, VertexAttributeValues, MeshVertexAttributeId}, once_cell::sync::Lazy}, tasks::{AsyncComputeTaskPool, Task}};
+use std::{collections::{BTreeMap, HashMap}, ops::Deref, task::Poll, sync::{Arc, RwLock}};
+use bevy::{prelude::*, render::once_cell::sync::Lazy, tasks::{AsyncComputeTaskPool, Task}};
 use dyn_clone::DynClone;
 use futures_lite::{FutureExt, future};
 use ndarray::Array3;
-use crate::world::{block::{entity::BlockComponent, BlockId, Block, registry::Blocks}, WorldMapHelpers, chunk::{CHUNK_SIZE, CHUNK_SIZE_U8, GetBlockOrEmpty, CHUNK_SIZE_U16, CHUNK_SIZE_U32}};
-use super::{registry::Chunks, Chunk, CHUNK_SIZE_I32, events::ChunkModifiedEvent};
+use crate::{sync::RecoverPoison, world::{block::{entity::BlockComponent, state::BlockStateId, Block, registry::{Blocks, BlockRegistryInternal, BLOCK_REGISTRY}}, WorldMapHelpers, chunk::{CHUNK_SIZE, CHUNK_SIZE_U8, GetBlockOrEmpty, CHUNK_SIZE_U16, CHUNK_SIZE_U32}}};
+use super::{snapshot::ChunkSnapshot, registry::{Chunks, ChunkState}, loader::Unloading, Chunk, CHUNK_SIZE_I32, events::{BlockChangedEvent, ChunkFailedEvent}, failure::{ChunkFailure, ChunkTaskKind, catch_pass}, light::MAX_LIGHT, scheduler::{ChunkTaskLimits, ChunkTaskPriorities, ChunkTaskQueue}};
 use ndarray::Axis;
+use self::{solid::get_visibility, builder::MeshBuilder, neighbours::{MissingNeighbourPolicy, FaceSignature, MeshedNeighbours, NEIGHBOUR_SIDES, opposite_side}, lod::{ChunkLod, downsample}};
 
+pub mod builder;
 pub mod greedy;
 pub mod solid;
 pub mod liquid;
+pub mod lod;
+pub mod neighbours;
+pub mod texture;
 
 pub static MESHING_PASSES: Lazy<Arc<RwLock<MeshingPassesInternal>>> = Lazy::new(||{Arc::new(RwLock::new(MeshingPassesInternal::new()))});
 
@@ -33,10 +38,18 @@ impl MeshingPassesInternal {
         self.passes.remove(&name);
     }
 
-    fn do_passes(&self, positions: &mut Vec<[f32;3]>, normals: &mut Vec<[f32;3]>, uvs: &mut Vec<[f32;2]>, colors: &mut Vec<[f32;4]>, data: &Array3<BlockId>) {
-        for pass in self.passes.values() {
-            pass.do_pass(positions, normals, uvs, colors, data);
+    /// Runs every pass, stopping at the first one that panics or leaves the mesh with mismatched attributes.
+    fn do_passes(&self, mesh: &mut MeshBuilder, context: &MeshingContext) -> Result<(), ChunkFailure> {
+        for (name, pass) in self.passes.iter() {
+            mesh.begin_pass();
+            catch_pass(ChunkTaskKind::Meshing, name.name, || pass.do_pass(mesh, context))?;
+            mesh.finish_pass().map_err(|error| ChunkFailure {
+                task: ChunkTaskKind::Meshing,
+                pass: name.name,
+                error: error.to_string(),
+            })?;
         }
+        Ok(())
     }
 }
 
@@ -74,11 +87,26 @@ impl Ord for MeshingPassIdentifier {
 /// A single 'pass' of the meshing system. Passes allow new cases for blocks to be specified, allowing the generation of new geometry.
 /// Passes are not ordered and will be executed in the order they were inserted, which can be unpredictable.
 pub trait MeshingPass: 'static + Send + Sync {
-    // TODO: Add support for arbitrary attributes
-    /// Does a pass over the chunk.
-    /// 
-    /// **Warning for implementors:** All vectors must be the same length!
-    fn do_pass(&self, positions: &mut Vec<[f32;3]>, normals: &mut Vec<[f32;3]>, uvs: &mut Vec<[f32;2]>, colors: &mut Vec<[f32;4]>, data: &Array3<BlockId>);
+    /// Does a pass over the chunk described by `context`, adding its vertices to `mesh`.
+    ///
+    /// `ATTRIBUTE_TEXTURE_LAYER` holds the layer of the block texture array each vertex samples, and UVs are measured
+    /// in blocks, since the texture repeats. See the `texture` module.
+    ///
+    /// Every vertex the pass adds needs a position, normal, UV, color, and texture layer, and a value for every other
+    /// attribute the pass writes to or declares with `MeshBuilder::declare`. Custom attributes declared by other passes
+    /// are filled with defaults. If the pass adds no indices, its vertices are drawn in order as a triangle list.
+    fn do_pass(&self, mesh: &mut MeshBuilder, context: &MeshingContext);
+}
+
+/// The chunk a `MeshingPass` meshes. More fields may be added later, so it can't be built or matched exhaustively
+/// outside this crate.
+#[non_exhaustive]
+pub struct MeshingContext<'a> {
+    /// The block states of the chunk and a one block border around it.
+    /// The properties of each block's state can be read with `BlockRegistryInternal::get_property`.
+    pub data: &'a Array3<BlockStateId>,
+    /// The light of the same blocks, with the skylight level in the high four bits and the block light level in the low four bits.
+    pub light: &'a Array3<u8>,
 }
 
 /// Used for generating a mesh for a chunk.
@@ -113,11 +141,25 @@ pub struct RemeshChunkMarker;
 
 /// This chunk has an ongoing asynchronous task to generate its mesh.
 #[derive(Component)]
-pub struct BeingRemeshed(Task<Mesh>);
+pub struct BeingRemeshed(Task<Result<MeshedChunk, ChunkFailure>>);
+
+/// The result of a meshing task.
+struct MeshedChunk {
+    /// The new mesh, or `None` if nothing in the chunk can be seen.
+    mesh: Option<Mesh>,
+    /// The neighbour faces the mesh was built against.
+    neighbours: MeshedNeighbours,
+    /// The chunk's own faces, as `FaceSignature::faces_of` gives them.
+    faces: [FaceSignature; 6],
+}
 
 const SHAPE_SIZE_USIZE: usize = CHUNK_SIZE + 2;
 const UV_SCALE: f32 = 1.0 / CHUNK_SIZE as f32;
 
+/// Chunks that can be given a new mesh, because they aren't being remeshed already, unloading, or failed.
+type RemeshableChunks<'w, 's> = Query<'w, 's, (Entity, &'static Chunk, Option<&'static RemeshChunkMarker>, Option<&'static ChunkLod>), (Without<BeingRemeshed>, Without<Unloading>, Without<ChunkFailure>)>;
+
+#[allow(clippy::too_many_arguments)]
 pub fn chunk_remesh_dispatch_system(
     mut commands: Commands,
     asset_server: Res<AssetServer>,
@@ -126,147 +168,393 @@ pub fn chunk_remesh_dispatch_system(
     chunk_registry: Res<Chunks>,
     world_map: WorldMapHelpers,
     blocks: Query<(Entity, &BlockComponent)>,
-    chunks: Query<(Entity, &Chunk, Option<&RemeshChunkMarker>), Without<BeingRemeshed>>,
+    chunks: RemeshableChunks,
+    lods: Query<&ChunkLod>,
+    remeshing: Query<(), With<BeingRemeshed>>,
+    policy: Res<MissingNeighbourPolicy>,
+    limits: Res<ChunkTaskLimits>,
+    priorities: ChunkTaskPriorities,
 ) {
-    let task_pool = AsyncComputeTaskPool::get();
-
-    for (chunk_entityid, this_chunk, chunk_remesh_marker) in chunks.iter() {
-        if let Some(_) = chunk_remesh_marker {
-            let this_chunk_position = this_chunk.get_position();
-
-            let left_chunk = world_map.get_chunk((this_chunk_position.0 - 1, this_chunk_position.1, this_chunk_position.2)); // left
-            let right_chunk = world_map.get_chunk((this_chunk_position.0 + 1, this_chunk_position.1, this_chunk_position.2)); // right
-            let up_chunk = world_map.get_chunk((this_chunk_position.0, this_chunk_position.1 + 1, this_chunk_position.2)); // up
-            let down_chunk = world_map.get_chunk((this_chunk_position.0, this_chunk_position.1 - 1, this_chunk_position.2)); // down
-            let forward_chunk = world_map.get_chunk((this_chunk_position.0, this_chunk_position.1, this_chunk_position.2 + 1)); // forward
-            let back_chunk = world_map.get_chunk((this_chunk_position.0, this_chunk_position.1, this_chunk_position.2 - 1)); // back
-                
-            let mut intermediate_array: Array3<BlockId> = Array3::from_elem((SHAPE_SIZE_USIZE, SHAPE_SIZE_USIZE, SHAPE_SIZE_USIZE), BlockId::EMPTY);
-
-            // Main chunk
-            for x in 0..CHUNK_SIZE {
-                for y in 0..CHUNK_SIZE {
-                    for z in 0..CHUNK_SIZE {
-                        intermediate_array[[x+1, y+1, z+1]] = this_chunk.get_blockid_or_empty(&blocks, x, y, z);
-                    }
-                } 
-            }
-
-            // Left chunk
-            if left_chunk.is_some() {
-                let left_chunk = left_chunk.unwrap();
-                for y in 0..CHUNK_SIZE {
-                    for z in 0..CHUNK_SIZE {
-                        intermediate_array[[0, y+1, z+1]] = left_chunk.get_blockid_or_empty(&blocks, 15, y, z);
-                    }
-                }
-            }
-
-            // Right chunk
-            if right_chunk.is_some() {
-                let right_chunk = right_chunk.unwrap();
-                for y in 0..CHUNK_SIZE {
-                    for z in 0..CHUNK_SIZE {
-                        intermediate_array[[17, y+1, z+1]] = right_chunk.get_blockid_or_empty(&blocks, 0, y, z);
-                    }
-                }
-            }
+    let in_flight = remeshing.iter().count();
+    let available = limits.available_meshing_tasks(in_flight);
+    if available == 0 { return; }
 
-            // Top chunk
-            if up_chunk.is_some() {
-                let up_chunk = up_chunk.unwrap();
-                for x in 0..CHUNK_SIZE {
-                    for z in 0..CHUNK_SIZE {
-                        intermediate_array[[x+1, 17, z+1]] = up_chunk.get_blockid_or_empty(&blocks, x, 0, z);
-                    }
-                }
+    let task_pool = AsyncComputeTaskPool::get();
+    let registry = BLOCK_REGISTRY.read_or_recover();
+
+    // Chunks waiting for a new mesh and their level of detail, most important first
+    let queued: ChunkTaskQueue<(Entity, &Chunk, ChunkLod)> = chunks.iter()
+        .filter(|(_, _, marker, _)| marker.is_some())
+        .map(|(entity, chunk, _, lod)| ((entity, chunk, lod.copied().unwrap_or_default()), priorities.get(chunk.get_position())))
+        .collect();
+
+    let mut dispatched = 0;
+    for (chunk_entityid, this_chunk, lod) in queued {
+        if dispatched == available { break; }
+        let this_chunk_position = this_chunk.get_position();
+        let first_mesh = chunk_registry.get(this_chunk_position) == ChunkState::Generated(chunk_entityid);
+
+        let neighbour_chunks = NEIGHBOUR_SIDES.map(|(offset, _, _)| world_map.get_chunk(this_chunk_position + offset));
+        if *policy == MissingNeighbourPolicy::Wait && neighbour_chunks.iter().any(Option::is_none) { continue; }
+
+        // Only snapshots are sent to the task, so copying the blocks into arrays and hashing faces happens off the main thread
+        let snapshot = this_chunk.snapshot();
+        let neighbours = neighbour_chunks.map(|chunk| chunk.map(Chunk::snapshot));
+        // Block entities can only be looked up here, but there are few enough of them to do it up front
+        let entity_states: HashMap<Entity, BlockStateId> = neighbours.iter().flatten()
+            .chain(std::iter::once(&snapshot))
+            .flat_map(|snapshot| snapshot.get_block_entities())
+            .filter_map(|entity| {
+                let (_, block) = blocks.get(entity).ok()?;
+                Some((entity, registry.get_default_state(block.0)?))
+            })
+            .collect();
+
+        // Borders with chunks at a different level of detail get skirts, see the `lod` module
+        let skirts = NEIGHBOUR_SIDES.map(|(offset, _, _)| {
+            let neighbour_lod = chunk_registry.get(this_chunk_position + offset).loaded_entity()
+                .and_then(|neighbour| lods.get(neighbour).ok().copied())
+                .unwrap_or_default();
+            lod != neighbour_lod
+        });
+
+        // Spawn task
+        let policy = *policy;
+        let missing = if policy == MissingNeighbourPolicy::Opaque { BlockStateId::UNLOADED } else { BlockStateId::EMPTY };
+        commands.entity(chunk_entityid).remove::<RemeshChunkMarker>().insert(BeingRemeshed(task_pool.spawn(async move {
+            let (meshed_neighbours, faces, hidden) = catch_pass(ChunkTaskKind::Meshing, "engine_signatures", || {
+                let registry = BLOCK_REGISTRY.read_or_recover();
+                // Remembered so that neighbours changing only remesh this chunk if the faces touching it changed
+                let meshed_neighbours = MeshedNeighbours::of(&neighbours, &registry, policy);
+                let faces = FaceSignature::faces_of(&snapshot, &registry, policy);
+
+                // Uniform chunks that can't be seen don't need a mesh at all
+                let hidden = match snapshot.get_uniform_block() {
+                    Some(Block::Generic(state)) => match get_visibility(state, &registry) {
+                        MeshingVisibility::Invisible => true,
+                        MeshingVisibility::Opaque => meshed_neighbours.0.iter().all(|signature| *signature == FaceSignature::Opaque),
+                        MeshingVisibility::Translucent => false,
+                    },
+                    _ => false,
+                };
+                (meshed_neighbours, faces, hidden)
+            })?;
+            if hidden {
+                return Ok(MeshedChunk { mesh: None, neighbours: meshed_neighbours, faces });
             }
 
-            // Bottom chunk
-            if down_chunk.is_some() {
-                let down_chunk = down_chunk.unwrap();
-                for x in 0..CHUNK_SIZE {
-                    for z in 0..CHUNK_SIZE {
-                        intermediate_array[[x+1, 0, z+1]] = down_chunk.get_blockid_or_empty(&blocks, x, 15, z);
-                    }
-                }
-            }
+            let mut mesh = MeshBuilder::new();
+            let (data, light) = catch_pass(ChunkTaskKind::Meshing, "engine_arrays", || {
+                let (mut data, mut light) = meshing_arrays(&snapshot, &neighbours, &entity_states, missing, skirts);
+                downsample(&mut data, &mut light, lod.scale());
+                (data, light)
+            })?;
+            MESHING_PASSES.read_or_recover().do_passes(&mut mesh, &MeshingContext { data: &data, light: &light })?;
+
+            // Every pass has already been checked, so this can't fail
+            let mesh = mesh.build().expect("meshing passes were checked");
+            Ok(MeshedChunk { mesh: Some(mesh), neighbours: meshed_neighbours, faces })
+        })));
+        if first_mesh {
+            commands.add(Chunks::transition_command(this_chunk_position, ChunkState::Generated(chunk_entityid), ChunkState::Meshing(chunk_entityid)));
+        }
+        dispatched += 1;
+    }
+}
 
-            // Forward chunk
-            if forward_chunk.is_some() {
-                let forward_chunk = forward_chunk.unwrap();
-                for x in 0..CHUNK_SIZE {
-                    for y in 0..CHUNK_SIZE {
-                        intermediate_array[[x+1, y+1, 17]] = forward_chunk.get_blockid_or_empty(&blocks, x, y, 0);
-                    }
-                }
+/// Copies a chunk and the layers of its neighbours that touch it into the arrays given to `MeshingPass::do_pass`.
+/// `neighbours` are in the order left, right, up, down, forward, back, and the layers of missing ones are filled with `missing`.
+/// The layers of neighbours on sides with `skirts` are left empty, but keep their light.
+fn meshing_arrays(chunk: &ChunkSnapshot, neighbours: &[Option<ChunkSnapshot>; 6], entity_states: &HashMap<Entity, BlockStateId>, missing: BlockStateId, skirts: [bool; 6]) -> (Array3<BlockStateId>, Array3<u8>) {
+    let shape = (SHAPE_SIZE_USIZE, SHAPE_SIZE_USIZE, SHAPE_SIZE_USIZE);
+    let mut data = Array3::from_elem(shape, BlockStateId::EMPTY);
+    // Missing neighbours are treated as open sky, so chunk borders don't go dark while neighbours load
+    let mut light = Array3::from_elem(shape, MAX_LIGHT << 4);
+
+    let state = |snapshot: &ChunkSnapshot, [x, y, z]: [usize; 3]| match snapshot.get_block(x, y, z) {
+        Block::Generic(state) => state,
+        Block::Entity(entity) => entity_states.get(&entity).copied().unwrap_or(BlockStateId::EMPTY),
+    };
+
+    // Main chunk
+    for x in 0..CHUNK_SIZE {
+        for y in 0..CHUNK_SIZE {
+            for z in 0..CHUNK_SIZE {
+                data[[x+1, y+1, z+1]] = state(chunk, [x, y, z]);
+                light[[x+1, y+1, z+1]] = chunk.get_light().get_packed(x, y, z);
             }
+        }
+    }
 
-            // Back chunk
-            if back_chunk.is_some() {
-                let back_chunk = back_chunk.unwrap();
-                for x in 0..CHUNK_SIZE {
-                    for y in 0..CHUNK_SIZE {
-                        intermediate_array[[x+1, y+1, 0]] = back_chunk.get_blockid_or_empty(&blocks, x, y, 15);
-                    }
+    // One layer of each neighbour
+    let sides = [(0, false), (0, true), (1, true), (1, false), (2, true), (2, false)];
+    for ((neighbour, (axis, positive)), skirt) in neighbours.iter().zip(sides).zip(skirts) {
+        let (source_layer, target_layer) = if positive { (0, SHAPE_SIZE_USIZE - 1) } else { (CHUNK_SIZE - 1, 0) };
+        let place = |layer: usize, a: usize, b: usize| match axis {
+            0 => [layer, a, b],
+            1 => [a, layer, b],
+            _ => [a, b, layer],
+        };
+
+        for a in 0..CHUNK_SIZE {
+            for b in 0..CHUNK_SIZE {
+                let source = place(source_layer, a, b);
+                let target = place(target_layer, a + 1, b + 1);
+                match neighbour {
+                    Some(neighbour) => {
+                        if !skirt { data[target] = state(neighbour, source); }
+                        light[target] = neighbour.get_light().get_packed(source[0], source[1], source[2]);
+                    },
+                    None => data[target] = missing,
                 }
             }
+        }
+    }
 
-            // Spawn task
-            commands.entity(chunk_entityid).remove::<RemeshChunkMarker>().insert(BeingRemeshed(task_pool.spawn(async move {
-                let mut positions: Vec<[f32; 3]> = vec![];
-                let mut normals: Vec<[f32; 3]> = vec![];
-                let mut uvs: Vec<[f32; 2]> = vec![];
-                let mut colors: Vec<[f32; 4]> = vec![];
+    (data, light)
+}
 
-                MESHING_PASSES.read().unwrap().do_passes(&mut positions, &mut normals, &mut uvs, &mut colors, &intermediate_array);
+/// Checks if every block on one face of a chunk is opaque, hiding anything behind it.
+/// `axis` is 0, 1, or 2 for the X, Y, and Z axes, and `layer` is which layer of blocks along that axis to check.
+fn chunk_face_is_opaque(chunk: &ChunkSnapshot, axis: usize, layer: usize, registry: &BlockRegistryInternal) -> bool {
+    let is_opaque = |block: Block| match block {
+        Block::Generic(state) => get_visibility(state, registry) == MeshingVisibility::Opaque,
+        Block::Entity(_) => false,
+    };
 
-                let mut render_mesh = Mesh::new(PrimitiveTopology::TriangleList);
-                render_mesh.insert_attribute(Mesh::ATTRIBUTE_POSITION, positions);
-                render_mesh.insert_attribute(Mesh::ATTRIBUTE_NORMAL, normals);
-                render_mesh.insert_attribute(Mesh::ATTRIBUTE_UV_0, uvs);
-                render_mesh.insert_attribute(Mesh::ATTRIBUTE_COLOR, colors);
+    if let Some(block) = chunk.get_uniform_block() {
+        return is_opaque(block);
+    }
 
-                render_mesh
-            })));
+    for a in 0..CHUNK_SIZE {
+        for b in 0..CHUNK_SIZE {
+            let block = match axis {
+                0 => chunk.get_block(layer, a, b),
+                1 => chunk.get_block(a, layer, b),
+                _ => chunk.get_block(a, b, layer),
+            };
+            if !is_opaque(block) { return false; }
         }
     }
+
+    true
 }
 
-pub fn chunk_remesh_polling_system(
+/// Gives chunks their finished meshes, and remeshes neighbours whose mesh was built against a different face of the chunk.
+pub(crate) fn chunk_remesh_polling_system(
     mut commands: Commands,
     mut meshes: ResMut<Assets<Mesh>>,
-    mut query: Query<(Entity, &mut Handle<Mesh>, &mut BeingRemeshed)>
+    mut chunk_registry: ResMut<Chunks>,
+    mut failed_events: EventWriter<ChunkFailedEvent>,
+    mut query: Query<(Entity, &Chunk, &mut Handle<Mesh>, &mut BeingRemeshed)>,
+    meshed: Query<Option<&MeshedNeighbours>, With<Chunk>>,
 ) {
-    for (entity, mut handle, mut remesh) in query.iter_mut() {
-        if let Some(mesh) = future::block_on(future::poll_once(&mut remesh.0)) {
-            *handle = meshes.add(mesh);
-            commands.entity(entity).remove::<BeingRemeshed>();
+    for (entity, chunk, mut handle, mut remesh) in query.iter_mut() {
+        let position = chunk.get_position();
+        match future::block_on(future::poll_once(&mut remesh.0)) {
+            Some(Ok(MeshedChunk { mesh, neighbours, faces })) => {
+                *handle = mesh.map(|mesh| meshes.add(mesh)).unwrap_or_default();
+                commands.entity(entity).remove::<BeingRemeshed>().insert(neighbours);
+                if chunk_registry.get(position) == ChunkState::Meshing(entity) {
+                    chunk_registry.transition_or_warn(position, ChunkState::Ready(entity));
+                }
+
+                // This chunk is on the opposite side of each of its neighbours
+                for (side, (offset, _, _)) in NEIGHBOUR_SIDES.into_iter().enumerate() {
+                    let neighbour = match chunk_registry.get(position + offset).loaded_entity() {
+                        Some(neighbour) => neighbour,
+                        None => continue,
+                    };
+                    let neighbour_meshed = match meshed.get(neighbour) {
+                        Ok(neighbour_meshed) => neighbour_meshed,
+                        Err(_) => continue,
+                    };
+                    if MeshedNeighbours::needs_remesh(neighbour_meshed, opposite_side(side), faces[side]) {
+                        commands.entity(neighbour).insert(RemeshChunkMarker);
+                    }
+                }
+            },
+            Some(Err(failure)) => {
+                // Chunks that already have a mesh keep it, and only chunks without one are moved to `Failed`
+                error!("Failed to mesh chunk {position}: {failure}");
+                commands.entity(entity).remove::<BeingRemeshed>().insert(failure.clone());
+                if chunk_registry.get(position) == ChunkState::Meshing(entity) {
+                    chunk_registry.transition_or_warn(position, ChunkState::Failed(entity));
+                }
+                failed_events.send(ChunkFailedEvent { position, entity, failure });
+            },
+            None => {},
         }
     }
 }
 
+/// Remeshes chunks whose blocks changed, and the neighbours of blocks that changed on a chunk's border, if the face
+/// they share is now different from the one their mesh was built against. Neighbours are checked straight away, so
+/// they're remeshed together with the chunk even if its own remesh waits or fails.
+/// Chunks whose light changed are remeshed by `chunk_light_system`, and their neighbours by `chunk_remesh_polling_system`.
 pub(crate) fn remesh_changed_chunks_system(
     registry: Res<Chunks>,
-    mut events: EventReader<ChunkModifiedEvent>,
+    policy: Res<MissingNeighbourPolicy>,
+    mut events: EventReader<BlockChangedEvent>,
     mut commands: Commands,
+    chunks: Query<&Chunk>,
+    meshed: Query<Option<&MeshedNeighbours>, With<Chunk>>,
 ) {
+    // The sides of each changed chunk that had a block change on them, in the order of `NEIGHBOUR_SIDES`
+    let mut changed: HashMap<Entity, [bool; 6]> = HashMap::new();
     for event in events.iter() {
-        for offset in [
-            [1,0,0], [-1,0,0],
-            [0,1,0], [0,-1,0],
-            [0,0,1], [0,0,-1],
-        ] {
-            match registry.get((
-                event.0.x + offset[0],
-                event.0.y + offset[1],
-                event.0.z + offset[2]
-            )) {
-                super::registry::ChunkState::Present(entity) => {
-                    commands.entity(entity).insert(RemeshChunkMarker);
-                },
-                _ => {},
+        let entity = match registry.get(event.chunk).loaded_entity() {
+            Some(entity) => entity,
+            None => continue,
+        };
+        if !chunks.contains(entity) { continue; }
+
+        let sides = changed.entry(entity).or_default();
+        for change in event.changes.iter() {
+            let local = change.position.local();
+            let local = [local.x as usize, local.y as usize, local.z as usize];
+            for (side, changed) in sides.iter_mut().enumerate() {
+                let (_, axis, layer) = NEIGHBOUR_SIDES[opposite_side(side)];
+                *changed |= local[axis] == layer;
             }
         }
     }
-}
\ No newline at end of file
+    if changed.is_empty() { return; }
+
+    let block_registry = BLOCK_REGISTRY.read_or_recover();
+    for (entity, sides) in changed {
+        commands.entity(entity).insert(RemeshChunkMarker);
+        if !sides.contains(&true) { continue; }
+
+        let chunk = chunks.get(entity).unwrap();
+        let snapshot = chunk.snapshot();
+        let faces = FaceSignature::faces_of(&snapshot, &block_registry, *policy);
+        for (side, (offset, _, _)) in NEIGHBOUR_SIDES.into_iter().enumerate() {
+            if !sides[side] { continue; }
+            let neighbour = match registry.get(chunk.get_position() + offset).loaded_entity() {
+                Some(neighbour) => neighbour,
+                None => continue,
+            };
+            let neighbour_meshed = match meshed.get(neighbour) {
+                Ok(neighbour_meshed) => neighbour_meshed,
+                Err(_) => continue,
+            };
+            if MeshedNeighbours::needs_remesh(neighbour_meshed, opposite_side(side), faces[side]) {
+                commands.entity(neighbour).insert(RemeshChunkMarker);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+    use bevy::{prelude::*, asset::AssetPlugin, tasks::TaskPoolBuilder};
+    use ndarray::Array3;
+    use crate::{sync::RecoverPoison, world::{block::{Block, registry::BLOCK_REGISTRY, state::BlockStateId}, position::{BlockPos, ChunkPos, LocalPos}, chunk::{Chunk, CHUNK_SIZE_U8, events::*, snapshot::ChunkSnapshot, failure::{ChunkFailure, chunk_retry_system}, registry::{Chunks, ChunkState}, ticket::{ChunkTicket, ChunkTickets, TicketKind, TicketLevel, chunk_ticket_system}}}};
+    use super::{BeingRemeshed, MeshingContext, MeshingPass, MeshingPassesInternal, MeshingPassIdentifier, RemeshChunkMarker, builder::MeshBuilder, neighbours::{MeshedNeighbours, MissingNeighbourPolicy}, chunk_remesh_polling_system, remesh_changed_chunks_system};
+
+    struct BrokenPass;
+
+    impl MeshingPass for BrokenPass {
+        fn do_pass(&self, _mesh: &mut MeshBuilder, _context: &MeshingContext) {
+            panic!("broken pass");
+        }
+    }
+
+    #[test]
+    fn failed_remeshes_of_ready_chunks_are_retried() {
+        let mut app = App::new();
+        app.add_plugin(AssetPlugin::default());
+        app.add_asset::<Mesh>();
+        app.insert_resource(Time::default());
+        app.insert_resource(Chunks::new());
+        app.init_resource::<ChunkTickets>();
+        app.add_event::<LoadChunkMessage>();
+        app.add_event::<UnloadChunkMessage>();
+        app.add_event::<RetryChunkMessage>();
+        app.add_event::<ChunkStateChangedEvent>();
+        app.add_event::<ChunkFailedEvent>();
+        app.add_system(chunk_remesh_polling_system);
+        app.add_system(chunk_ticket_system.after(chunk_remesh_polling_system));
+        app.add_system(chunk_retry_system.after(chunk_ticket_system));
+
+        let position = ChunkPos::new(0, 0, 0);
+        app.world.resource_mut::<ChunkTickets>().add(ChunkTicket::new(TicketKind::Script, position, 1.0, TicketLevel::Loaded));
+        app.update();
+
+        // Spawned on this thread's local executor, so the pass can be run to the end before the chunk is polled
+        let pool = TaskPoolBuilder::new().num_threads(1).build();
+        let task = pool.spawn_local(async move {
+            let mut passes = MeshingPassesInternal::new();
+            passes.add_pass(MeshingPassIdentifier::new("broken", 0), BrokenPass);
+            let context = MeshingContext { data: &Array3::from_elem((1, 1, 1), BlockStateId::EMPTY), light: &Array3::zeros((1, 1, 1)) };
+            passes.do_passes(&mut MeshBuilder::new(), &context)?;
+            unreachable!("the broken pass panics")
+        });
+        pool.with_local_executor(|executor| while executor.try_tick() {});
+        assert!(task.is_finished());
+        let entity = app.world.spawn((Chunk::new(position), Handle::<Mesh>::default(), BeingRemeshed(task))).id();
+        let mut chunk_registry = app.world.resource_mut::<Chunks>();
+        for state in [ChunkState::Queued(entity), ChunkState::Generating(entity), ChunkState::Generated(entity), ChunkState::Meshing(entity), ChunkState::Ready(entity)] {
+            chunk_registry.transition(position, state).unwrap();
+        }
+
+        app.update();
+        assert!(!app.world.entity(entity).contains::<BeingRemeshed>());
+        // The chunk keeps its old mesh, so it stays ready
+        assert_eq!(app.world.get::<ChunkFailure>(entity).map(|failure| failure.pass), Some("broken"));
+        assert_eq!(app.world.resource::<Chunks>().get(position), ChunkState::Ready(entity));
+
+        // The first update of `Time` only sets when the last update was
+        let now = Instant::now();
+        app.world.resource_mut::<Time>().update_with_instant(now);
+        app.world.resource_mut::<Time>().update_with_instant(now + Duration::from_secs(10));
+        app.update();
+        assert!(app.world.get::<ChunkFailure>(entity).is_none());
+        assert!(app.world.entity(entity).contains::<RemeshChunkMarker>());
+        assert_eq!(app.world.resource::<Chunks>().get(position), ChunkState::Ready(entity));
+    }
+
+    #[test]
+    fn neighbours_are_remeshed_when_blocks_on_their_face_change() {
+        let mut app = App::new();
+        app.insert_resource(Chunks::new());
+        app.init_resource::<MissingNeighbourPolicy>();
+        app.add_event::<BlockChangedEvent>();
+        app.add_system(remesh_changed_chunks_system);
+
+        let (position, right) = (ChunkPos::new(0, 0, 0), ChunkPos::new(1, 0, 0));
+        let chunk = Chunk::new(position);
+        // The right neighbour was meshed against the chunk as it is now, and the chunk is its left neighbour
+        let mut neighbours: [Option<ChunkSnapshot>; 6] = Default::default();
+        neighbours[0] = Some(chunk.snapshot());
+        let meshed = MeshedNeighbours::of(&neighbours, &BLOCK_REGISTRY.read_or_recover(), MissingNeighbourPolicy::default());
+        let entity = app.world.spawn(chunk).id();
+        let neighbour = app.world.spawn((Chunk::new(right), meshed)).id();
+        for (position, entity) in [(position, entity), (right, neighbour)] {
+            for state in [ChunkState::Queued(entity), ChunkState::Generating(entity), ChunkState::Generated(entity), ChunkState::Meshing(entity), ChunkState::Ready(entity)] {
+                app.world.resource_mut::<Chunks>().transition(position, state).unwrap();
+            }
+        }
+
+        let change = |app: &mut App, x: u8| {
+            let block = Block::Generic(BlockStateId(5));
+            app.world.get_mut::<Chunk>(entity).unwrap().set_block(x as usize, 5, 5, block);
+            app.world.send_event(BlockChangedEvent {
+                chunk: position,
+                cause: BlockChangeCause::Script,
+                changes: vec![BlockChange { position: BlockPos::from_parts(position, LocalPos::new(x, 5, 5)), old: Block::EMPTY, new: block }],
+            });
+            app.update();
+        };
+
+        // Blocks away from the border don't touch the neighbour's mesh
+        change(&mut app, 5);
+        assert!(app.world.entity(entity).contains::<RemeshChunkMarker>());
+        assert!(!app.world.entity(neighbour).contains::<RemeshChunkMarker>());
+
+        change(&mut app, CHUNK_SIZE_U8 - 1);
+        assert!(app.world.entity(neighbour).contains::<RemeshChunkMarker>());
+    }
+}
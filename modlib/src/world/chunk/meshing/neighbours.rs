@@ -0,0 +1,151 @@
+//! How chunks are meshed next to neighbours that aren't loaded, and which neighbours need a new mesh when a chunk changes.
+//!
+//! A chunk's mesh depends on the layer of each neighbour that touches it. When a chunk is meshed, a `FaceSignature` of
+//! each of those layers is stored with it, and a neighbour changing or unloading only remeshes the chunk if the
+//! signature of the touching layer is different from the one the mesh was built against.
+//!
+//! Signatures are hashed by the meshing task from the same snapshots the mesh is built from. When blocks on a chunk's
+//! border change, the changed faces are hashed straight away and compared against what its neighbours were meshed
+//! against. A chunk's own faces are hashed by its meshing task too, and compared again once its new mesh is ready,
+//! which catches faces where only the light changed.
+
+use std::{collections::hash_map::DefaultHasher, hash::{Hash, Hasher}};
+use bevy::prelude::*;
+use crate::world::{block::registry::BlockRegistryInternal, chunk::{CHUNK_SIZE, snapshot::ChunkSnapshot}};
+use super::chunk_face_is_opaque;
+
+/// What the meshers assume is on the other side of a chunk border when the neighbour isn't loaded.
+#[derive(Resource, Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum MissingNeighbourPolicy {
+    /// Missing neighbours are empty. Every face along the border is meshed, and most of them are thrown away again
+    /// once the neighbour is loaded.
+    #[default]
+    Empty,
+    /// Missing neighbours are filled with `BlockStateId::UNLOADED`, which is opaque, so no faces are meshed along the
+    /// border. The outer faces of the loaded area are left open until the chunks behind them are loaded.
+    Opaque,
+    /// Chunks aren't meshed until all six neighbours are generated.
+    /// Chunks on the edge of the loaded area never get a mesh, and stay `Generated` until their neighbours load.
+    Wait,
+}
+
+impl MissingNeighbourPolicy {
+    /// The signature that a neighbour which isn't loaded has under this policy.
+    pub(crate) fn missing_face(&self) -> FaceSignature {
+        match self {
+            MissingNeighbourPolicy::Opaque => FaceSignature::Opaque,
+            MissingNeighbourPolicy::Empty | MissingNeighbourPolicy::Wait => FaceSignature::Missing,
+        }
+    }
+}
+
+/// The neighbours of a chunk, as the offset to the neighbour, the axis of the shared face, and the neighbour's layer
+/// along that axis that touches the chunk. In the order left, right, up, down, forward, back.
+pub(crate) const NEIGHBOUR_SIDES: [(IVec3, usize, usize); 6] = [
+    (IVec3::NEG_X, 0, CHUNK_SIZE - 1),
+    (IVec3::X, 0, 0),
+    (IVec3::Y, 1, 0),
+    (IVec3::NEG_Y, 1, CHUNK_SIZE - 1),
+    (IVec3::Z, 2, 0),
+    (IVec3::NEG_Z, 2, CHUNK_SIZE - 1),
+];
+
+/// The side of a neighbour that faces back towards a chunk, as an index into `NEIGHBOUR_SIDES`.
+pub(crate) fn opposite_side(side: usize) -> usize {
+    side ^ 1
+}
+
+/// Summarises the layer of a neighbour that touches a chunk.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum FaceSignature {
+    /// The neighbour isn't loaded, and is meshed against as if it's empty.
+    Missing,
+    /// Every block in the layer is opaque, so nothing behind it matters.
+    Opaque,
+    /// A hash of the blocks and light in the layer.
+    Blocks(u64),
+}
+
+impl FaceSignature {
+    /// The signature of the layer of `chunk` along `axis`, or of a missing neighbour if `chunk` is `None`.
+    pub(crate) fn of(chunk: Option<&ChunkSnapshot>, axis: usize, layer: usize, registry: &BlockRegistryInternal, policy: MissingNeighbourPolicy) -> Self {
+        let chunk = match chunk {
+            Some(chunk) => chunk,
+            None => return policy.missing_face(),
+        };
+        if chunk_face_is_opaque(chunk, axis, layer, registry) {
+            return FaceSignature::Opaque;
+        }
+
+        let mut hasher = DefaultHasher::new();
+        for a in 0..CHUNK_SIZE {
+            for b in 0..CHUNK_SIZE {
+                let [x, y, z] = match axis {
+                    0 => [layer, a, b],
+                    1 => [a, layer, b],
+                    _ => [a, b, layer],
+                };
+                chunk.get_block(x, y, z).hash(&mut hasher);
+                chunk.get_light().get_packed(x, y, z).hash(&mut hasher);
+            }
+        }
+        FaceSignature::Blocks(hasher.finish())
+    }
+
+    /// The signatures of the faces of `chunk` that touch each of its neighbours, in the order of `NEIGHBOUR_SIDES`.
+    /// Each one is compared against the opposite side of the neighbour's `MeshedNeighbours`.
+    pub(crate) fn faces_of(chunk: &ChunkSnapshot, registry: &BlockRegistryInternal, policy: MissingNeighbourPolicy) -> [Self; 6] {
+        std::array::from_fn(|side| {
+            let (_, axis, layer) = NEIGHBOUR_SIDES[opposite_side(side)];
+            FaceSignature::of(Some(chunk), axis, layer, registry, policy)
+        })
+    }
+}
+
+/// The signatures of the neighbour faces that a chunk's current mesh was built against, in the order of `NEIGHBOUR_SIDES`.
+#[derive(Component, Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct MeshedNeighbours(pub(crate) [FaceSignature; 6]);
+
+impl MeshedNeighbours {
+    /// The signatures of the layers of `neighbours` that touch a chunk, with `neighbours` in the order of `NEIGHBOUR_SIDES`.
+    pub(crate) fn of(neighbours: &[Option<ChunkSnapshot>; 6], registry: &BlockRegistryInternal, policy: MissingNeighbourPolicy) -> Self {
+        MeshedNeighbours(std::array::from_fn(|side| {
+            let (_, axis, layer) = NEIGHBOUR_SIDES[side];
+            FaceSignature::of(neighbours[side].as_ref(), axis, layer, registry, policy)
+        }))
+    }
+
+    /// Checks if a chunk needs a new mesh now that the neighbour on `side` has the signature `face`.
+    /// Chunks without a mesh always do.
+    pub(crate) fn needs_remesh(meshed: Option<&MeshedNeighbours>, side: usize, face: FaceSignature) -> bool {
+        meshed.map_or(true, |meshed| meshed.0[side] != face)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::world::{block::{Block, data::BlockData}, chunk::{Chunk, meshing::MeshingVisibility}, position::ChunkPos};
+    use super::*;
+
+    #[test]
+    fn signatures_only_change_with_the_touching_layer() {
+        let mut registry = BlockRegistryInternal::new();
+        registry.add_block_type(BlockData::new("stone", MeshingVisibility::Opaque));
+        let stone = registry.get_default_state_by_string_id("stone").unwrap();
+        let mut chunk = Chunk::new(ChunkPos::new(0, 0, 0));
+        let (_, axis, layer) = NEIGHBOUR_SIDES[1];
+        let signature = |chunk: &Chunk| FaceSignature::of(Some(&chunk.snapshot()), axis, layer, &registry, MissingNeighbourPolicy::Opaque);
+
+        let before = signature(&chunk);
+        chunk.set_block(5, 5, 5, Block::Generic(stone));
+        assert_eq!(signature(&chunk), before);
+        chunk.set_block(0, 5, 5, Block::Generic(stone));
+        assert_ne!(signature(&chunk), before);
+        // The layer at X = 0 is also the face the chunk shows its left neighbour
+        assert_eq!(FaceSignature::faces_of(&chunk.snapshot(), &registry, MissingNeighbourPolicy::Opaque)[0], signature(&chunk));
+
+        assert_eq!(FaceSignature::of(None, axis, layer, &registry, MissingNeighbourPolicy::Opaque), FaceSignature::Opaque);
+        assert_eq!(opposite_side(2), 3);
+        assert!(MeshedNeighbours::needs_remesh(None, 0, FaceSignature::Opaque));
+    }
+}
@@ -2,20 +2,18 @@ use crate::world::{
     block::{
         data::BlockData,
         registry::{BlockRegistryInternal, BLOCK_REGISTRY},
-        Block, BlockId,
+        Block, state::BlockStateId,
     },
-    chunk::{CHUNK_SIZE, CHUNK_SIZE_U8, meshing::greedy::greedy_determine_quads},
-};
-use bevy::{
-    prelude::{Color, Mesh},
-    render::mesh::{Indices, MeshVertexAttribute, VertexAttributeValues},
+    chunk::{CHUNK_SIZE, CHUNK_SIZE_U8, meshing::greedy::{greedy_determine_quads, quad_corners}, light::MAX_LIGHT},
 };
+use crate::sync::RecoverPoison;
+use bevy::prelude::{Color, Mesh};
 use ndarray::{Array3, Axis};
 use std::{
     collections::BTreeMap,
     sync::{Arc, RwLockReadGuard},
 };
-use super::{MeshingPass, MeshingVisibility, SHAPE_SIZE_USIZE, MeshingPassIdentifier};
+use super::{MeshingPass, MeshingContext, MeshingVisibility, SHAPE_SIZE_USIZE, MeshingPassIdentifier, builder::MeshBuilder, texture::ATTRIBUTE_TEXTURE_LAYER, neighbours::NEIGHBOUR_SIDES};
 
 pub const SOLID_BLOCK_MESHER_PASS: MeshingPassIdentifier = MeshingPassIdentifier::new("engine_solid", 0);
 
@@ -23,206 +21,247 @@ pub(crate) struct SolidBlockMesher;
 impl MeshingPass for SolidBlockMesher {
     fn do_pass(
         &self,
-        positions: &mut Vec<[f32;3]>,
-        normals: &mut Vec<[f32;3]>,
-        uvs: &mut Vec<[f32;2]>,
-        colors: &mut Vec<[f32;4]>,
-        array: &Array3<BlockId>,
+        mesh: &mut MeshBuilder,
+        context: &MeshingContext,
     ) {
-        let registry = BLOCK_REGISTRY.read().unwrap();
+        let (array, light) = (context.data, context.light);
+        let registry = BLOCK_REGISTRY.read_or_recover();
 
-        fn selector(block: &BlockId, registry: &BlockRegistryInternal) -> bool {
-            registry.get_by_numerical_id(*block).unwrap().get_attribute(BlockData::ATTRIBUTE_USE_SOLID_MESHER).is_some()
+        fn selector(block: &BlockStateId, registry: &BlockRegistryInternal) -> bool {
+            registry.get_by_state(*block).is_some_and(|data| data.get_attribute(BlockData::ATTRIBUTE_USE_SOLID_MESHER).is_some())
         }
 
-        const UVS: [[f32; 2]; 6] = [
-            [0.0, 0.0],
-            [0.0, 1.0],
-            [1.0, 0.0],
-            [1.0, 0.0],
-            [0.0, 1.0],
-            [1.0, 1.0],
-        ];
-
         // Left and right
         for x in 1..SHAPE_SIZE_USIZE - 1 {
             let array_subview = array.index_axis(Axis(0), x);
-            let mut left_slice = [[BlockId::EMPTY; CHUNK_SIZE]; CHUNK_SIZE];
-            let mut right_slice = [[BlockId::EMPTY; CHUNK_SIZE]; CHUNK_SIZE];
+            let mut left_slice = [[(BlockStateId::EMPTY, 0, [0; 4]); CHUNK_SIZE]; CHUNK_SIZE];
+            let mut right_slice = [[(BlockStateId::EMPTY, 0, [0; 4]); CHUNK_SIZE]; CHUNK_SIZE];
             for y in 1..SHAPE_SIZE_USIZE - 1 {
                 for z in 1..SHAPE_SIZE_USIZE - 1 {
                     let this_block = array_subview[[y, z]];
                     if get_visibility(this_block, &registry)
                         .is_visible_against(&get_visibility(array[[x - 1, y, z]], &registry))
                     {
-                        left_slice[y - 1][z - 1] = this_block;
+                        left_slice[y - 1][z - 1] = (this_block, light[[x - 1, y, z]], face_ambient_occlusion(array, [x - 1, y, z], 0, &registry));
                     }
                     if get_visibility(this_block, &registry)
                         .is_visible_against(&get_visibility(array[[x + 1, y, z]], &registry))
                     {
-                        right_slice[y - 1][z - 1] = this_block;
+                        right_slice[y - 1][z - 1] = (this_block, light[[x + 1, y, z]], face_ambient_occlusion(array, [x + 1, y, z], 0, &registry));
                     }
                 }
             }
 
             let x = x - 1;
 
-            for (blockid, quad) in greedy_determine_quads(&left_slice, &registry, selector) {
-                positions.extend([
-                    [x as f32, quad[0] as f32, quad[1] as f32],
-                    [x as f32, quad[0] as f32, quad[3] as f32],
-                    [x as f32, quad[2] as f32, quad[1] as f32],
-                    [x as f32, quad[0] as f32, quad[3] as f32],
-                    [x as f32, quad[2] as f32, quad[3] as f32],
-                    [x as f32, quad[2] as f32, quad[1] as f32],
-                ]);
-                normals.extend([[1.0, 0.0, 0.0]; 6]);
-                uvs.extend(UVS);
-                color_extend(colors, blockid, &registry);
+            for ((state, light, ao), quad) in greedy_determine_quads(&left_slice, &registry, selector) {
+                extend_face(mesh, quad_corners(0, x as f32, quad), 0, state, light, ao, &registry);
             }
-            for (blockid, quad) in greedy_determine_quads(&right_slice, &registry, selector) {
-                positions.extend([
-                    [x as f32 + 1.0, quad[0] as f32, quad[3] as f32],
-                    [x as f32 + 1.0, quad[0] as f32, quad[1] as f32],
-                    [x as f32 + 1.0, quad[2] as f32, quad[1] as f32],
-                    [x as f32 + 1.0, quad[2] as f32, quad[3] as f32],
-                    [x as f32 + 1.0, quad[0] as f32, quad[3] as f32],
-                    [x as f32 + 1.0, quad[2] as f32, quad[1] as f32],
-                ]);
-                normals.extend([[-1.0, 0.0, 0.0]; 6]);
-                uvs.extend(UVS);
-                color_extend(colors, blockid, &registry);
+            for ((state, light, ao), quad) in greedy_determine_quads(&right_slice, &registry, selector) {
+                extend_face(mesh, quad_corners(0, x as f32 + 1.0, quad), 1, state, light, ao, &registry);
             }
         }
 
         // Up and down
         for y in 1..SHAPE_SIZE_USIZE - 1 {
             let array_subview = array.index_axis(Axis(1), y);
-            let mut left_slice = [[BlockId::EMPTY; CHUNK_SIZE]; CHUNK_SIZE];
-            let mut right_slice = [[BlockId::EMPTY; CHUNK_SIZE]; CHUNK_SIZE];
+            let mut left_slice = [[(BlockStateId::EMPTY, 0, [0; 4]); CHUNK_SIZE]; CHUNK_SIZE];
+            let mut right_slice = [[(BlockStateId::EMPTY, 0, [0; 4]); CHUNK_SIZE]; CHUNK_SIZE];
             for x in 1..SHAPE_SIZE_USIZE - 1 {
                 for z in 1..SHAPE_SIZE_USIZE - 1 {
                     let this_block = array_subview[[x, z]];
                     if get_visibility(this_block, &registry)
                         .is_visible_against(&get_visibility(array[[x, y - 1, z]], &registry))
                     {
-                        left_slice[x - 1][z - 1] = this_block;
+                        left_slice[x - 1][z - 1] = (this_block, light[[x, y - 1, z]], face_ambient_occlusion(array, [x, y - 1, z], 1, &registry));
                     }
                     if get_visibility(this_block, &registry)
                         .is_visible_against(&get_visibility(array[[x, y + 1, z]], &registry))
                     {
-                        right_slice[x - 1][z - 1] = this_block;
+                        right_slice[x - 1][z - 1] = (this_block, light[[x, y + 1, z]], face_ambient_occlusion(array, [x, y + 1, z], 1, &registry));
                     }
                 }
             }
 
             let y = y - 1;
 
-            for (blockid, quad) in greedy_determine_quads(&left_slice, &registry, selector) {
-                positions.extend([
-                    [quad[0] as f32, y as f32, quad[3] as f32],
-                    [quad[0] as f32, y as f32, quad[1] as f32],
-                    [quad[2] as f32, y as f32, quad[1] as f32],
-                    [quad[2] as f32, y as f32, quad[3] as f32],
-                    [quad[0] as f32, y as f32, quad[3] as f32],
-                    [quad[2] as f32, y as f32, quad[1] as f32],
-                ]);
-                normals.extend([[0.0, 1.0, 0.0]; 6]);
-                uvs.extend(UVS);
-                color_extend(colors, blockid, &registry);
+            for ((state, light, ao), quad) in greedy_determine_quads(&left_slice, &registry, selector) {
+                extend_face(mesh, quad_corners(1, y as f32, quad), 3, state, light, ao, &registry);
             }
-            for (blockid, quad) in greedy_determine_quads(&right_slice, &registry, selector) {
-                positions.extend([
-                    [quad[0] as f32, y as f32 + 1.0, quad[1] as f32],
-                    [quad[0] as f32, y as f32 + 1.0, quad[3] as f32],
-                    [quad[2] as f32, y as f32 + 1.0, quad[1] as f32],
-                    [quad[0] as f32, y as f32 + 1.0, quad[3] as f32],
-                    [quad[2] as f32, y as f32 + 1.0, quad[3] as f32],
-                    [quad[2] as f32, y as f32 + 1.0, quad[1] as f32],
-                ]);
-                normals.extend([[0.0, -1.0, 0.0]; 6]);
-                uvs.extend(UVS);
-                color_extend(colors, blockid, &registry);
+            for ((state, light, ao), quad) in greedy_determine_quads(&right_slice, &registry, selector) {
+                extend_face(mesh, quad_corners(1, y as f32 + 1.0, quad), 2, state, light, ao, &registry);
             }
         }
 
         // Forward and backward
         for z in 1..SHAPE_SIZE_USIZE - 1 {
             let array_subview = array.index_axis(Axis(2), z);
-            let mut left_slice = [[BlockId::EMPTY; CHUNK_SIZE]; CHUNK_SIZE];
-            let mut right_slice = [[BlockId::EMPTY; CHUNK_SIZE]; CHUNK_SIZE];
+            let mut left_slice = [[(BlockStateId::EMPTY, 0, [0; 4]); CHUNK_SIZE]; CHUNK_SIZE];
+            let mut right_slice = [[(BlockStateId::EMPTY, 0, [0; 4]); CHUNK_SIZE]; CHUNK_SIZE];
             for x in 1..SHAPE_SIZE_USIZE - 1 {
                 for y in 1..SHAPE_SIZE_USIZE - 1 {
                     let this_block = array_subview[[x, y]];
                     if get_visibility(this_block, &registry)
                         .is_visible_against(&get_visibility(array[[x, y, z - 1]], &registry))
                     {
-                        left_slice[x - 1][y - 1] = this_block;
+                        left_slice[x - 1][y - 1] = (this_block, light[[x, y, z - 1]], face_ambient_occlusion(array, [x, y, z - 1], 2, &registry));
                     }
                     if get_visibility(this_block, &registry)
                         .is_visible_against(&get_visibility(array[[x, y, z + 1]], &registry))
                     {
-                        right_slice[x - 1][y - 1] = this_block;
+                        right_slice[x - 1][y - 1] = (this_block, light[[x, y, z + 1]], face_ambient_occlusion(array, [x, y, z + 1], 2, &registry));
                     }
                 }
             }
 
             let z = z - 1;
 
-            for (blockid, quad) in greedy_determine_quads(&left_slice, &registry, selector) {
-                positions.extend([
-                    [quad[0] as f32, quad[1] as f32, z as f32],
-                    [quad[0] as f32, quad[3] as f32, z as f32],
-                    [quad[2] as f32, quad[1] as f32, z as f32],
-                    [quad[0] as f32, quad[3] as f32, z as f32],
-                    [quad[2] as f32, quad[3] as f32, z as f32],
-                    [quad[2] as f32, quad[1] as f32, z as f32],
-                ]);
-                normals.extend([[0.0, 0.0, 1.0]; 6]);
-                uvs.extend(UVS);
-                color_extend(colors, blockid, &registry);
+            for ((state, light, ao), quad) in greedy_determine_quads(&left_slice, &registry, selector) {
+                extend_face(mesh, quad_corners(2, z as f32, quad), 5, state, light, ao, &registry);
             }
-            for (blockid, quad) in greedy_determine_quads(&right_slice, &registry, selector) {
-                positions.extend([
-                    [quad[0] as f32, quad[3] as f32, z as f32 + 1.0],
-                    [quad[0] as f32, quad[1] as f32, z as f32 + 1.0],
-                    [quad[2] as f32, quad[1] as f32, z as f32 + 1.0],
-                    [quad[2] as f32, quad[3] as f32, z as f32 + 1.0],
-                    [quad[0] as f32, quad[3] as f32, z as f32 + 1.0],
-                    [quad[2] as f32, quad[1] as f32, z as f32 + 1.0],
-                ]);
-                normals.extend([[0.0, 0.0, -1.0]; 6]);
-                uvs.extend(UVS);
-                color_extend(colors, blockid, &registry);
+            for ((state, light, ao), quad) in greedy_determine_quads(&right_slice, &registry, selector) {
+                extend_face(mesh, quad_corners(2, z as f32 + 1.0, quad), 4, state, light, ao, &registry);
             }
         }
     }
 }
 
-pub(crate) fn color_extend(
-    colors: &mut Vec<[f32; 4]>,
-    blockid: BlockId,
+/// Adds one face of a block as a quad, facing the neighbour on `side`, as an index into `NEIGHBOUR_SIDES`.
+/// `side` is also the index of the texture layer used, from `BlockRegistryInternal::get_texture_layers`.
+/// `ao` is the ambient occlusion of each corner, as returned by `face_ambient_occlusion`.
+pub(crate) fn extend_face(
+    mesh: &mut MeshBuilder,
+    corners: [[f32; 3]; 4],
+    side: usize,
+    state: BlockStateId,
+    light: u8,
+    ao: [u8; 4],
     registry: &BlockRegistryInternal,
 ) {
-    const EMPTY_COLOR: [[f32; 4]; 6] = [[1.0, 1.0, 1.0, 1.0]; 6];
-    colors.extend(match registry.get_by_numerical_id(blockid) {
+    /// How bright each level of ambient occlusion is, from the darkest to none at all.
+    const AO_BRIGHTNESS: [f32; 4] = [0.5, 0.7, 0.85, 1.0];
+
+    let (direction, axis, _) = NEIGHBOUR_SIDES[side];
+    // Split along the brighter diagonal, so a single dark corner doesn't streak across the whole quad
+    let flip = ao[1] + ao[3] > ao[0] + ao[2];
+    mesh.extend_quad(corners, direction.as_vec3().to_array(), flip);
+    mesh.extend(Mesh::ATTRIBUTE_UV_0, tiled_uvs(&corners, axis));
+    let [r, g, b, a] = block_color(state, light, registry);
+    mesh.extend(Mesh::ATTRIBUTE_COLOR, ao.map(|ao| {
+        let brightness = AO_BRIGHTNESS[ao as usize];
+        [r * brightness, g * brightness, b * brightness, a]
+    }));
+    mesh.extend(ATTRIBUTE_TEXTURE_LAYER, [registry.get_texture_layers(state)[side]; 4]);
+}
+
+/// The ambient occlusion of each corner of a face, from 0 for the darkest to 3 for none, in the order of `quad_corners`.
+///
+/// `outside` is the position in the meshing array of the block the face looks out into, and `axis` is the axis the face
+/// is along. Each corner is darkened by the opaque blocks next to it in that layer, and is fully dark if both of its
+/// sides are blocked, whatever is in the corner itself.
+pub(crate) fn face_ambient_occlusion(
+    array: &Array3<BlockStateId>,
+    outside: [usize; 3],
+    axis: usize,
+    registry: &BlockRegistryInternal,
+) -> [u8; 4] {
+    let (a, b) = match axis {
+        0 => (1, 2),
+        1 => (0, 2),
+        _ => (0, 1),
+    };
+    // Faces are always inside the chunk along `a` and `b`, so their neighbours are still in the array's border
+    let opaque = |offset_a: isize, offset_b: isize| {
+        let mut position = outside;
+        position[a] = position[a].wrapping_add_signed(offset_a);
+        position[b] = position[b].wrapping_add_signed(offset_b);
+        get_visibility(array[position], registry) == MeshingVisibility::Opaque
+    };
+
+    [(-1, -1), (1, -1), (1, 1), (-1, 1)].map(|(offset_a, offset_b)| {
+        let (side_a, side_b) = (opaque(offset_a, 0), opaque(0, offset_b));
+        if side_a && side_b {
+            0
+        } else {
+            3 - side_a as u8 - side_b as u8 - opaque(offset_a, offset_b) as u8
+        }
+    })
+}
+
+/// UVs for the corners of a quad facing along `axis`, measured in blocks, so the texture repeats once per block
+/// across merged quads. Textures on side faces are upright.
+pub(crate) fn tiled_uvs(corners: &[[f32; 3]; 4], axis: usize) -> [[f32; 2]; 4] {
+    let (u, v) = match axis {
+        0 => (2, 1),
+        1 => (0, 2),
+        _ => (0, 1),
+    };
+    let min_u = corners.iter().map(|corner| corner[u]).fold(f32::INFINITY, f32::min);
+    let max_v = corners.iter().map(|corner| corner[v]).fold(f32::NEG_INFINITY, f32::max);
+    corners.map(|corner| [corner[u] - min_u, max_v - corner[v]])
+}
+
+/// The vertex color of a block's faces, darkened by the packed light level lighting it.
+pub(crate) fn block_color(
+    state: BlockStateId,
+    light: u8,
+    registry: &BlockRegistryInternal,
+) -> [f32; 4] {
+    const EMPTY_COLOR: [f32; 4] = [1.0, 1.0, 1.0, 1.0];
+    let [r, g, b, a] = match registry.get_by_state(state) {
         Some(blockdata) => match blockdata.get_attribute(BlockData::ATTRIBUTE_BASE_COLOR) {
             Some(value) => {
                 let value: Color = value.clone().try_into().unwrap();
-                [value.as_rgba_f32(); 6]
+                value.as_rgba_f32()
             }
             None => EMPTY_COLOR,
         },
         None => EMPTY_COLOR,
-    });
+    };
+    let brightness = light_brightness(light);
+    [r * brightness, g * brightness, b * brightness, a]
+}
+
+/// How bright a packed light level from `MeshingPass::do_pass` looks, from 0 to 1.
+/// Each level is a bit darker than the one above it, and even complete darkness isn't entirely black.
+pub(crate) fn light_brightness(light: u8) -> f32 {
+    const FALLOFF: f32 = 0.85;
+    const MIN_BRIGHTNESS: f32 = 0.05;
+    let level = (light >> 4).max(light & 0xF).min(MAX_LIGHT);
+    FALLOFF.powi((MAX_LIGHT - level) as i32).max(MIN_BRIGHTNESS)
 }
 
+/// How a block hides the faces of the blocks next to it. `BlockStateId::UNLOADED` isn't in the registry, and is always opaque.
 pub(crate) fn get_visibility(
-    block: BlockId,
+    block: BlockStateId,
     registry: &BlockRegistryInternal,
 ) -> MeshingVisibility {
-    match registry.get_by_numerical_id(block) {
+    if block == BlockStateId::UNLOADED {
+        return MeshingVisibility::Opaque;
+    }
+    match registry.get_by_state(block) {
         Some(entry) => entry.block_visibility,
         None => MeshingVisibility::Invisible,
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn corners_are_darkened_by_opaque_neighbours() {
+        let registry = BlockRegistryInternal::new();
+        let mut array = Array3::from_elem((SHAPE_SIZE_USIZE, SHAPE_SIZE_USIZE, SHAPE_SIZE_USIZE), BlockStateId::EMPTY);
+        // The top face of a block at 5, 5, 5 looks out into 5, 6, 5, with X as the first axis of the face and Z the second
+        let outside = [5, 6, 5];
+        assert_eq!(face_ambient_occlusion(&array, outside, 1, &registry), [3; 4]);
+
+        array[[4, 6, 4]] = BlockStateId::UNLOADED;
+        assert_eq!(face_ambient_occlusion(&array, outside, 1, &registry), [2, 3, 3, 3]);
+
+        array[[6, 6, 5]] = BlockStateId::UNLOADED;
+        array[[5, 6, 6]] = BlockStateId::UNLOADED;
+        assert_eq!(face_ambient_occlusion(&array, outside, 1, &registry), [2, 2, 0, 2]);
+    }
+}
@@ -0,0 +1,219 @@
+//! Block textures and the material chunks are drawn with.
+//!
+//! Every image named in a block's `BlockData::ATTRIBUTE_SOLID_TEXTURE_SIDES` gets a layer in one array texture, which the
+//! block registry hands out as blocks are added. Once the blocks are registered, the images are loaded from
+//! `textures/blocks/<name>.png` and copied into the array. Meshers write the layer of each face into
+//! `ATTRIBUTE_TEXTURE_LAYER`, and UVs measured in blocks, so the texture repeats once per block across merged quads.
+//!
+//! The chunk shader doesn't use the full PBR lighting of `StandardMaterial`. Block and sky light are baked into the vertex
+//! colors, and the ambient light and directional lights only shade each face by its normal, so they don't cast shadows.
+
+use bevy::{
+    prelude::*,
+    asset::{load_internal_asset, LoadState},
+    pbr::{MaterialPipeline, MaterialPipelineKey},
+    reflect::TypeUuid,
+    render::{
+        mesh::{MeshVertexAttribute, MeshVertexBufferLayout},
+        render_resource::{
+            AsBindGroup, ShaderRef, RenderPipelineDescriptor, SpecializedMeshPipelineError, VertexFormat, Extent3d,
+            TextureDimension, TextureFormat, TextureViewDescriptor, TextureViewDimension, SamplerDescriptor, AddressMode, FilterMode,
+        },
+        texture::ImageSampler,
+    },
+};
+use crate::{sync::RecoverPoison, world::block::registry::BLOCK_REGISTRY};
+
+/// The width and height of every block texture, in pixels.
+pub const BLOCK_TEXTURE_SIZE: u32 = 16;
+
+/// The layer of the block texture array each vertex samples. See `BlockRegistryInternal::get_texture_layers`.
+pub const ATTRIBUTE_TEXTURE_LAYER: MeshVertexAttribute =
+    MeshVertexAttribute::new("Vertex_TextureLayer", 1_943_750_117, VertexFormat::Uint32);
+
+const CHUNK_SHADER_HANDLE: HandleUntyped = HandleUntyped::weak_from_u64(Shader::TYPE_UUID, 7_356_120_884_471_029_531);
+
+pub(crate) struct BlockTexturePlugin;
+impl Plugin for BlockTexturePlugin {
+    fn build(&self, app: &mut App) {
+        load_internal_asset!(app, CHUNK_SHADER_HANDLE, "chunk.wgsl", Shader::from_wgsl);
+        app.add_plugin(MaterialPlugin::<ChunkMaterial>::default());
+        app.init_resource::<BlockTextures>();
+
+        app.add_startup_system(chunk_material_setup_system);
+        // Blocks are added by startup systems, so their images are only known afterwards
+        app.add_startup_system_to_stage(StartupStage::PostStartup, block_texture_load_system);
+        app.add_system(block_texture_build_system);
+    }
+}
+
+/// The material every chunk is drawn with. Multiplies the block textures by the vertex colors, and shades faces by how
+/// much of the ambient and directional lights they face.
+#[derive(AsBindGroup, TypeUuid, Debug, Clone)]
+#[uuid = "b4a1cbe4-2f0c-4c36-9a3e-5d0c4f6e8a71"]
+pub struct ChunkMaterial {
+    /// An array texture with one layer for each block texture, and plain white in layer 0.
+    #[texture(0, dimension = "2d_array")]
+    #[sampler(1)]
+    pub textures: Handle<Image>,
+}
+
+impl Material for ChunkMaterial {
+    fn vertex_shader() -> ShaderRef {
+        CHUNK_SHADER_HANDLE.typed().into()
+    }
+
+    fn fragment_shader() -> ShaderRef {
+        CHUNK_SHADER_HANDLE.typed().into()
+    }
+
+    fn specialize(
+        _pipeline: &MaterialPipeline<Self>,
+        descriptor: &mut RenderPipelineDescriptor,
+        layout: &MeshVertexBufferLayout,
+        _key: MaterialPipelineKey<Self>,
+    ) -> Result<(), SpecializedMeshPipelineError> {
+        let vertex_layout = layout.get_layout(&[
+            Mesh::ATTRIBUTE_POSITION.at_shader_location(0),
+            Mesh::ATTRIBUTE_NORMAL.at_shader_location(1),
+            Mesh::ATTRIBUTE_UV_0.at_shader_location(2),
+            Mesh::ATTRIBUTE_COLOR.at_shader_location(3),
+            ATTRIBUTE_TEXTURE_LAYER.at_shader_location(4),
+        ])?;
+        descriptor.vertex.buffers = vec![vertex_layout];
+        Ok(())
+    }
+}
+
+/// The `ChunkMaterial` shared by every chunk.
+#[derive(Resource)]
+pub struct ChunkMaterialHandle(pub Handle<ChunkMaterial>);
+
+/// Keeps track of the block texture array while its images load.
+#[derive(Resource, Default)]
+pub struct BlockTextures {
+    /// The images of every layer after the first, while they're loading.
+    loading: Vec<(&'static str, Handle<Image>)>,
+    /// Set once every image has been copied into the array.
+    array: Option<Handle<Image>>,
+}
+
+impl BlockTextures {
+    /// The finished texture array, once every block texture has loaded or failed to.
+    pub fn get_array(&self) -> Option<&Handle<Image>> {
+        self.array.as_ref()
+    }
+}
+
+fn chunk_material_setup_system(
+    mut commands: Commands,
+    mut images: ResMut<Assets<Image>>,
+    mut materials: ResMut<Assets<ChunkMaterial>>,
+) {
+    // Chunks are drawn in plain white until the real textures are ready
+    let textures = images.add(texture_array(vec![white_layer()]));
+    commands.insert_resource(ChunkMaterialHandle(materials.add(ChunkMaterial { textures })));
+}
+
+fn block_texture_load_system(asset_server: Res<AssetServer>, mut textures: ResMut<BlockTextures>) {
+    let registry = BLOCK_REGISTRY.read_or_recover();
+    textures.loading = registry.get_texture_names().iter()
+        .map(|name| (*name, asset_server.load(format!("textures/blocks/{name}.png"))))
+        .collect();
+}
+
+/// Builds the texture array and gives it to the chunk material once every image has loaded or failed to.
+fn block_texture_build_system(
+    asset_server: Res<AssetServer>,
+    mut textures: ResMut<BlockTextures>,
+    mut images: ResMut<Assets<Image>>,
+    mut materials: ResMut<Assets<ChunkMaterial>>,
+    material: Res<ChunkMaterialHandle>,
+) {
+    if textures.array.is_some() { return; }
+    let pending = textures.loading.iter()
+        .any(|(_, handle)| !matches!(asset_server.get_load_state(handle), LoadState::Loaded | LoadState::Failed));
+    if pending { return; }
+
+    let layers = std::iter::once(white_layer())
+        .chain(textures.loading.iter().map(|(name, handle)| layer_data(name, images.get(handle))))
+        .collect();
+    let array = images.add(texture_array(layers));
+    if let Some(material) = materials.get_mut(&material.0) {
+        material.textures = array.clone();
+    }
+
+    info!("Built block texture array with {} textures", textures.loading.len());
+    textures.loading.clear();
+    textures.array = Some(array);
+}
+
+/// A layer of plain white, which leaves the vertex color as it is.
+fn white_layer() -> Vec<u8> {
+    vec![u8::MAX; (BLOCK_TEXTURE_SIZE * BLOCK_TEXTURE_SIZE * 4) as usize]
+}
+
+/// The pixels of one layer of the array. Images that are missing or the wrong size are left white.
+fn layer_data(name: &str, image: Option<&Image>) -> Vec<u8> {
+    let image = match image.and_then(|image| image.convert(TextureFormat::Rgba8UnormSrgb)) {
+        Some(image) => image,
+        None => {
+            warn!("Block texture {name} couldn't be loaded, so it's left blank");
+            return white_layer();
+        },
+    };
+    if image.size() != Vec2::splat(BLOCK_TEXTURE_SIZE as f32) {
+        warn!("Block texture {name} isn't {BLOCK_TEXTURE_SIZE}x{BLOCK_TEXTURE_SIZE} pixels, so it's left blank");
+        return white_layer();
+    }
+    image.data
+}
+
+/// Stacks layers of `BLOCK_TEXTURE_SIZE` square pixels into an array texture that repeats and isn't filtered.
+fn texture_array(layers: Vec<Vec<u8>>) -> Image {
+    let count = layers.len() as u32;
+    let mut image = Image::new(
+        Extent3d { width: BLOCK_TEXTURE_SIZE, height: BLOCK_TEXTURE_SIZE * count, depth_or_array_layers: 1 },
+        TextureDimension::D2,
+        layers.concat(),
+        TextureFormat::Rgba8UnormSrgb,
+    );
+    image.reinterpret_stacked_2d_as_array(count);
+    // A single layer would be viewed as a plain 2D texture otherwise
+    image.texture_view_descriptor = Some(TextureViewDescriptor {
+        dimension: Some(TextureViewDimension::D2Array),
+        ..default()
+    });
+    image.sampler_descriptor = ImageSampler::Descriptor(SamplerDescriptor {
+        address_mode_u: AddressMode::Repeat,
+        address_mode_v: AddressMode::Repeat,
+        mag_filter: FilterMode::Nearest,
+        min_filter: FilterMode::Nearest,
+        ..default()
+    });
+    image
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn layers_are_stacked_in_order() {
+        let red = Image::new_fill(
+            Extent3d { width: BLOCK_TEXTURE_SIZE, height: BLOCK_TEXTURE_SIZE, depth_or_array_layers: 1 },
+            TextureDimension::D2,
+            &[255, 0, 0, 255],
+            TextureFormat::Rgba8UnormSrgb,
+        );
+        let tiny = Image::new_fill(Extent3d::default(), TextureDimension::D2, &[0, 0, 255, 255], TextureFormat::Rgba8UnormSrgb);
+
+        let layers = vec![white_layer(), layer_data("red", Some(&red)), layer_data("tiny", Some(&tiny)), layer_data("missing", None)];
+        let array = texture_array(layers);
+        assert_eq!(array.texture_descriptor.size.depth_or_array_layers, 4);
+
+        let layer_size = (BLOCK_TEXTURE_SIZE * BLOCK_TEXTURE_SIZE * 4) as usize;
+        assert_eq!(&array.data[layer_size..layer_size + 4], &[255, 0, 0, 255]);
+        assert!(array.data[layer_size * 2..].iter().all(|byte| *byte == u8::MAX));
+    }
+}
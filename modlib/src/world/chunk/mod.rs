@@ -3,35 +3,92 @@ pub mod bundle;
 pub mod registry;
 pub mod loader;
 pub mod events;
+pub(crate) mod storage;
+pub mod serialize;
+pub mod region;
+pub mod scheduler;
+pub mod heightmap;
+pub mod light;
+pub mod snapshot;
+pub(crate) mod spatial;
+pub mod ticket;
+pub mod failure;
 
-use bevy::{prelude::{Component, SystemLabel, Entity, Plugin, IntoSystemDescriptor, App, Query}, utils::HashMap};
-use ndarray::Array3;
-use self::{registry::{ChunkCoordinate, Chunks}, events::*, meshing::{*, solid::{SolidBlockMesher, SOLID_BLOCK_MESHER_PASS}, liquid::{LIQUID_MESHER_PASS, LiquidMesher}}};
+use std::{borrow::Cow, sync::Arc};
+use bevy::{prelude::{Component, SystemLabel, Entity, Plugin, IntoSystemDescriptor, App, Query, CoreStage}, utils::HashMap};
+use self::{registry::Chunks, heightmap::{Heightmaps, HeightmapKind}, light::{LightStorage, LightKind, chunk_light_system}, events::*, loader::{chunk_unload_system, chunk_despawn_system, chunk_streaming_system, ChunkStreaming}, ticket::{ChunkTickets, chunk_ticket_system}, failure::chunk_retry_system, scheduler::ChunkTaskLimits, storage::PalettedStorage, region::{save_unloaded_chunks_system, flush_world_save_system, save_on_exit_system}, meshing::{*, neighbours::MissingNeighbourPolicy, lod::{LodDistances, chunk_lod_system}, texture::BlockTexturePlugin, solid::{SolidBlockMesher, SOLID_BLOCK_MESHER_PASS}, liquid::{LIQUID_MESHER_PASS, LiquidMesher}}};
 
-use super::block::{BlockId, Block, entity::BlockComponent};
+use crate::sync::RecoverPoison;
+use super::{block::{state::BlockStateId, Block, registry::BlockRegistryInternal, entity::{BlockComponent, block_entity_lifecycle_system}}, position::{BlockPos, ChunkPos, LocalPos}};
 
 pub struct ChunkedWorldPlugin;
 impl Plugin for ChunkedWorldPlugin {
     fn build(&self, app: &mut bevy::prelude::App) {
         app.insert_resource(Chunks::new());
+        app.init_resource::<ChunkStreaming>();
+        app.init_resource::<ChunkTickets>();
+        app.init_resource::<ChunkTaskLimits>();
+        app.init_resource::<MissingNeighbourPolicy>();
+        app.init_resource::<LodDistances>();
+        app.add_plugin(BlockTexturePlugin);
 
-        let mut meshing_passes = MESHING_PASSES.write().unwrap();
+        let mut meshing_passes = MESHING_PASSES.write_or_recover();
         meshing_passes.add_pass(SOLID_BLOCK_MESHER_PASS, SolidBlockMesher);
         meshing_passes.add_pass(LIQUID_MESHER_PASS, LiquidMesher);
 
         app.add_event::<UnloadChunkMessage>();
         app.add_event::<LoadChunkMessage>();
+        app.add_event::<RetryChunkMessage>();
         app.add_event::<ChunkModifiedEvent>();
+        app.add_event::<BlockChangedEvent>();
+        app.add_event::<ChunkStateChangedEvent>();
+        app.add_event::<ChunkGeneratedEvent>();
+        app.add_event::<ChunkMeshedEvent>();
+        app.add_event::<ChunkFailedEvent>();
+        app.add_event::<ChunkUnloadingEvent>();
 
+        app.add_system(chunk_streaming_system
+            .label(SystemLabels::ChunkStreamingSystem));
+        app.add_system(chunk_ticket_system
+            .label(SystemLabels::ChunkTicketSystem)
+            .after(SystemLabels::ChunkStreamingSystem));
+        app.add_system(chunk_retry_system
+            .label(SystemLabels::ChunkRetrySystem));
         app.add_system(chunk_change_system
             .label(SystemLabels::ChunkChangeEventSystem));
+        app.add_system(block_change_event_system
+            .label(SystemLabels::BlockChangeEventSystem));
+        app.add_system(chunk_light_system
+            .label(SystemLabels::ChunkLightSystem)
+            .after(SystemLabels::BlockChangeEventSystem)
+            .before(SystemLabels::ChunkChangeEventSystem));
+        app.add_system(block_entity_lifecycle_system
+            .label(SystemLabels::BlockEntityLifecycleSystem));
+        app.add_system(chunk_lod_system
+            .label(SystemLabels::ChunkLodSystem)
+            .before(SystemLabels::ChunkMeshingDispatchSystem));
         app.add_system(chunk_remesh_dispatch_system
             .label(SystemLabels::ChunkMeshingDispatchSystem));
         app.add_system(chunk_remesh_polling_system
             .label(SystemLabels::ChunkMeshingPollingSystem)
             .after(SystemLabels::ChunkMeshingDispatchSystem));
         app.add_system(remesh_changed_chunks_system
-            .after(SystemLabels::ChunkChangeEventSystem));
+            .after(SystemLabels::BlockChangeEventSystem));
+        app.add_system_to_stage(CoreStage::Last, save_on_exit_system);
+        app.add_system_to_stage(CoreStage::Last, chunk_lifecycle_event_system
+            .label(SystemLabels::ChunkLifecycleEventSystem));
+        // Unloading happens after Update so that no commands for the unloaded chunks are left to apply afterwards.
+        // Chunks that started unloading last frame are despawned before new ones start unloading.
+        app.add_system_to_stage(CoreStage::PostUpdate, save_unloaded_chunks_system
+            .label(SystemLabels::ChunkSaveSystem)
+            .before(SystemLabels::ChunkDespawnSystem));
+        app.add_system_to_stage(CoreStage::PostUpdate, flush_world_save_system
+            .after(SystemLabels::ChunkSaveSystem));
+        app.add_system_to_stage(CoreStage::PostUpdate, chunk_despawn_system
+            .label(SystemLabels::ChunkDespawnSystem)
+            .before(SystemLabels::ChunkUnloadSystem));
+        app.add_system_to_stage(CoreStage::PostUpdate, chunk_unload_system
+            .label(SystemLabels::ChunkUnloadSystem));
     }
 }
 
@@ -40,6 +97,17 @@ pub enum SystemLabels {
     ChunkMeshingDispatchSystem,
     ChunkMeshingPollingSystem,
     ChunkChangeEventSystem,
+    BlockChangeEventSystem,
+    BlockEntityLifecycleSystem,
+    ChunkLightSystem,
+    ChunkSaveSystem,
+    ChunkUnloadSystem,
+    ChunkDespawnSystem,
+    ChunkStreamingSystem,
+    ChunkTicketSystem,
+    ChunkLifecycleEventSystem,
+    ChunkRetrySystem,
+    ChunkLodSystem,
 }
 
 // The size of each chunk in all axes, so a value of 16 would be 16x16x16.
@@ -56,98 +124,303 @@ pub const CHUNK_SIZE_I32: i32 = CHUNK_SIZE as i32;
 
 #[derive(Component)]
 pub struct Chunk {
-    position: ChunkCoordinate,
-    array: Array3<ChunkBlockInternal>,
-    entities: HashMap<u16, Entity>,
+    position: ChunkPos,
+    /// Blocks, block entities and light are shared with `ChunkSnapshot`s, and copied the first time they're changed after a snapshot.
+    blocks: Arc<PalettedStorage>,
+    entities: Arc<HashMap<u16, Entity>>,
+    heightmaps: Heightmaps,
+    /// Blocks changed since the heightmaps were last updated, or `None` if the heightmaps need to be computed from scratch.
+    heightmap_changes: Option<Vec<LocalPos>>,
+    light: Arc<LightStorage>,
+    modified: bool,
+    /// Block changes that haven't been sent as `BlockChangedEvent`s yet.
+    changes: Vec<(BlockChangeCause, BlockChange)>,
+    /// Whether changes are recorded in `changes`. Off while a chunk is generated or loaded, since nothing has seen its blocks yet.
+    record_changes: bool,
+    /// Block entities that were placed or removed, and haven't been updated by `block_entity_lifecycle_system` yet.
+    entity_updates: Vec<BlockEntityUpdate>,
 }
 
-#[derive(Debug, Clone, Copy)]
+/// A block entity that was placed in or removed from a chunk.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum BlockEntityUpdate {
+    /// The entity was placed, and needs a `BlockPosition`.
+    Placed(Entity, BlockPos),
+    /// The entity was replaced by another block, and needs to be despawned.
+    Removed(Entity),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub(crate) enum ChunkBlockInternal {
-    Generic(BlockId),
+    Generic(BlockStateId),
     Entity(u16),
 }
 
 impl ChunkBlockInternal {
-    pub const EMPTY: ChunkBlockInternal = ChunkBlockInternal::Generic(BlockId::EMPTY);
+    pub const EMPTY: ChunkBlockInternal = ChunkBlockInternal::Generic(BlockStateId::EMPTY);
 }
 
 impl Default for ChunkBlockInternal {
     fn default() -> Self {
-        Self::Generic(BlockId(0))
+        Self::Generic(BlockStateId(0))
     }
 }
 
-impl From<BlockId> for ChunkBlockInternal {
-    fn from(value: BlockId) -> Self {
+impl From<BlockStateId> for ChunkBlockInternal {
+    fn from(value: BlockStateId) -> Self {
         Self::Generic(value)
     }
 }
 
 impl Chunk {
-    pub fn new(at_coordinates: ChunkCoordinate) -> Self {
+    pub fn new(at_coordinates: ChunkPos) -> Self {
+        Self {
+            position: at_coordinates,
+            blocks: Arc::new(PalettedStorage::new(ChunkBlockInternal::EMPTY)),
+            entities: Arc::new(HashMap::new()),
+            heightmaps: Heightmaps::empty(),
+            heightmap_changes: Some(vec![]),
+            light: Arc::new(LightStorage::dark()),
+            modified: false,
+            changes: vec![],
+            record_changes: true,
+            entity_updates: vec![],
+        }
+    }
+
+    pub(crate) fn from_storage(at_coordinates: ChunkPos, blocks: PalettedStorage) -> Self {
         Self {
             position: at_coordinates,
-            array: Array3::from_elem([CHUNK_SIZE, CHUNK_SIZE, CHUNK_SIZE], ChunkBlockInternal::EMPTY),
-            entities: HashMap::new(),
+            heightmaps: Heightmaps::empty(),
+            heightmap_changes: None,
+            blocks: Arc::new(blocks),
+            light: Arc::new(LightStorage::dark()),
+            entities: Arc::new(HashMap::new()),
+            modified: false,
+            changes: vec![],
+            record_changes: true,
+            entity_updates: vec![],
         }
     }
 
-    /// Gets a `BlockId` or `Entity` from the chunk.
+    /// Gets a `BlockStateId` or `Entity` from the chunk.
     pub fn get_block(&self, x: usize, y: usize, z: usize) -> Block {
-        match self.array[[x, y, z]] {
-            ChunkBlockInternal::Generic(blockid) => Block::Generic(blockid),
+        match self.blocks.get(x, y, z) {
+            ChunkBlockInternal::Generic(state) => Block::Generic(state),
             ChunkBlockInternal::Entity(entityid) => Block::Entity(self.get_entity_from_ent_idx(&entityid)),
         }
     }
 
-    /// Returns the relevant `BlockId` if possible, or `BlockId::EMPTY` if it can't be found.
-    pub fn get_blockid_or_empty(&self, blocks: &Query<(Entity, &BlockComponent)>, x: usize, y: usize, z: usize) -> BlockId {
-        match self.array[[x, y, z]] {
-            ChunkBlockInternal::Generic(blockid) => blockid,
+    /// Returns the relevant `BlockStateId` if possible, or `BlockStateId::EMPTY` if it can't be found.
+    /// Block entities are given the default state of their block type.
+    pub fn get_state_or_empty(&self, blocks: &Query<(Entity, &BlockComponent)>, registry: &BlockRegistryInternal, x: usize, y: usize, z: usize) -> BlockStateId {
+        match self.blocks.get(x, y, z) {
+            ChunkBlockInternal::Generic(state) => state,
             ChunkBlockInternal::Entity(entityid) => {
-                match blocks.get(self.get_entity_from_ent_idx(&entityid)) {
-                    Ok(query) => query.1.0,
-                    Err(_) => BlockId::EMPTY,
-                }
+                blocks.get(self.get_entity_from_ent_idx(&entityid)).ok()
+                    .and_then(|(_, block)| registry.get_default_state(block.0))
+                    .unwrap_or(BlockStateId::EMPTY)
             },
         }
     }
 
+    /// Returns every block entity stored in this chunk.
+    pub fn get_block_entities(&self) -> impl Iterator<Item = Entity> + '_ {
+        self.entities.values().copied()
+    }
+
     pub(crate) fn get_entity_from_ent_idx(&self, id: &u16) -> Entity {
         *self.entities.get(&id).expect("Entity index should have been in the the map!")
     }
 
+    /// Sets a block in the chunk, recording the change with `BlockChangeCause::Script`.
     pub fn set_block(&mut self, x: usize, y: usize, z: usize, to: Block) {
-        match to {
-            Block::Generic(blockid) => {
-                if let ChunkBlockInternal::Entity(idx) = self.array[[x, y, z]] {
-                    self.entities.remove(&idx);
+        self.set_block_with_cause(x, y, z, to, BlockChangeCause::Script);
+    }
+
+    /// Sets a block in the chunk. The change is sent as a `BlockChangedEvent` with the given cause, if the block is actually different.
+    ///
+    /// Placing a block entity gives it a `BlockPosition`, and block entities that are replaced are despawned,
+    /// unless they were placed somewhere else, in this chunk or another one.
+    /// Both happen when `block_entity_lifecycle_system` runs, not immediately.
+    pub fn set_block_with_cause(&mut self, x: usize, y: usize, z: usize, to: Block, cause: BlockChangeCause) {
+        let old = self.get_block(x, y, z);
+        if old == to { return; }
+
+        let position = self.position.block(LocalPos::from_indices(x, y, z));
+        if self.record_changes {
+            self.changes.push((cause, BlockChange { position, old, new: to }));
+        }
+        self.modified = true;
+
+        if let ChunkBlockInternal::Entity(idx) = self.blocks.get(x, y, z) {
+            if let Some(entity) = Arc::make_mut(&mut self.entities).remove(&idx) {
+                // The same entity can be stored in more than one place, and is only despawned once it's gone from all of them
+                if !self.entities.values().any(|other| *other == entity) && to != Block::Entity(entity) {
+                    self.entity_updates.push(BlockEntityUpdate::Removed(entity));
                 }
-                self.array[[x, y, z]] = ChunkBlockInternal::Generic(blockid)
+            }
+        }
+
+        match to {
+            Block::Generic(state) => {
+                Arc::make_mut(&mut self.blocks).set(x, y, z, ChunkBlockInternal::Generic(state))
             },
             Block::Entity(entity) => {
-                for idx in 0..u16::MAX {
-                    if self.entities.contains_key(&idx) { continue; }
-                    self.entities.insert(idx, entity);
-                    self.array[[x, y, z]] = ChunkBlockInternal::Entity(idx);
+                // A chunk has fewer blocks than there are indices, so there's always a free one
+                let idx = (0..=u16::MAX).find(|idx| !self.entities.contains_key(idx))
+                    .expect("Chunk should always have a free entity index!");
+                Arc::make_mut(&mut self.entities).insert(idx, entity);
+                Arc::make_mut(&mut self.blocks).set(x, y, z, ChunkBlockInternal::Entity(idx));
+                self.entity_updates.push(BlockEntityUpdate::Placed(entity, position));
+            },
+        }
+
+        // Once most columns have changed, it's quicker to compute the heightmaps from scratch
+        if let Some(changes) = &mut self.heightmap_changes {
+            if changes.len() < CHUNK_SIZE * CHUNK_SIZE {
+                changes.push(LocalPos::from_indices(x, y, z));
+            } else {
+                self.heightmap_changes = None;
+            }
+        }
+    }
+
+    /// Brings the heightmaps up to date with the blocks that changed since the last update.
+    pub(crate) fn update_heightmaps(&mut self, registry: &BlockRegistryInternal) {
+        match self.heightmap_changes.replace(vec![]) {
+            Some(changes) => {
+                for local in changes {
+                    let (x, y, z) = local.indices();
+                    self.heightmaps.update(&self.blocks, registry, x, y, z);
+                }
+            },
+            None => self.heightmaps = Heightmaps::compute(&self.blocks, registry),
+        }
+    }
+
+    /// The local y coordinate of the highest block of a kind in a column of this chunk, if there is one.
+    /// Columns with blocks that changed since the heightmaps were last updated are scanned again, so this is always up to date.
+    pub fn get_height(&self, registry: &BlockRegistryInternal, kind: HeightmapKind, x: usize, z: usize) -> Option<usize> {
+        if !self.column_changed(x, z) {
+            return self.heightmaps.get(kind, x, z);
+        }
+        (0..CHUNK_SIZE).rev().find(|y| kind.matches_internal(registry, self.blocks.get(x, *y, z)))
+    }
+
+    /// The heightmaps of this chunk, including blocks that changed since they were last updated.
+    /// Only copies the heightmaps if there are changes they don't include yet.
+    pub fn get_heightmaps(&self, registry: &BlockRegistryInternal) -> Cow<'_, Heightmaps> {
+        match &self.heightmap_changes {
+            Some(changes) if changes.is_empty() => Cow::Borrowed(&self.heightmaps),
+            Some(changes) => {
+                let mut heightmaps = self.heightmaps.clone();
+                for local in changes {
+                    let (x, y, z) = local.indices();
+                    heightmaps.update(&self.blocks, registry, x, y, z);
                 }
+                Cow::Owned(heightmaps)
             },
+            None => Cow::Owned(Heightmaps::compute(&self.blocks, registry)),
+        }
+    }
+
+    /// Checks if a block in a column changed since the heightmaps were last updated.
+    fn column_changed(&self, x: usize, z: usize) -> bool {
+        match &self.heightmap_changes {
+            Some(changes) => changes.iter().any(|local| local.x as usize == x && local.z as usize == z),
+            None => true,
+        }
+    }
+
+    /// The skylight level of a block in this chunk, from 0 to `MAX_LIGHT`.
+    pub fn get_sky_light(&self, x: usize, y: usize, z: usize) -> u8 {
+        self.light.get(LightKind::Sky, x, y, z)
+    }
+
+    /// The block light level of a block in this chunk, from 0 to `MAX_LIGHT`.
+    pub fn get_block_light(&self, x: usize, y: usize, z: usize) -> u8 {
+        self.light.get(LightKind::Block, x, y, z)
+    }
+
+    /// The light levels of this chunk.
+    pub fn get_light(&self) -> &LightStorage {
+        &self.light
+    }
+
+    /// Gets the block entity at a position in the chunk, if there is one.
+    pub fn get_block_entity(&self, x: usize, y: usize, z: usize) -> Option<Entity> {
+        match self.blocks.get(x, y, z) {
+            ChunkBlockInternal::Entity(idx) => self.entities.get(&idx).copied(),
+            ChunkBlockInternal::Generic(_) => None,
+        }
+    }
+
+    /// Removes and returns the block entity updates that haven't been applied yet.
+    pub(crate) fn take_block_entity_updates(&mut self) -> Vec<BlockEntityUpdate> {
+        std::mem::take(&mut self.entity_updates)
+    }
+
+    /// Block entities that were replaced, but haven't been despawned yet.
+    pub(crate) fn get_removed_block_entities(&self) -> impl Iterator<Item = Entity> + '_ {
+        self.entity_updates.iter().filter_map(|update| match update {
+            BlockEntityUpdate::Removed(entity) => Some(*entity),
+            BlockEntityUpdate::Placed(..) => None,
+        })
+    }
+
+    /// Returns the block that fills the entire chunk, if the chunk is uniform.
+    /// Uniform chunks don't store a block array, and are cheap to check against when meshing.
+    pub fn get_uniform_block(&self) -> Option<Block> {
+        match self.blocks.uniform_value()? {
+            ChunkBlockInternal::Generic(state) => Some(Block::Generic(state)),
+            ChunkBlockInternal::Entity(entityid) => Some(Block::Entity(self.get_entity_from_ent_idx(&entityid))),
         }
     }
 
-    #[allow(dead_code)]
-    pub(crate) fn get_internal_array(&self) -> &Array3<ChunkBlockInternal> {
-        &self.array
+    /// Compacts the chunk's block storage. Chunks that end up containing only one kind of block become uniform.
+    /// This is done automatically after a chunk is generated.
+    pub fn shrink_storage(&mut self) {
+        Arc::make_mut(&mut self.blocks).shrink_to_fit();
+    }
+
+    /// Returns `true` if any blocks have been set since the chunk was generated, loaded, or last saved.
+    pub fn is_modified(&self) -> bool {
+        self.modified
+    }
+
+    /// Clears the modified flag, usually after the chunk has been saved.
+    pub fn clear_modified(&mut self) {
+        self.modified = false;
+    }
+
+    /// Removes and returns the block changes that haven't been sent as events yet.
+    pub(crate) fn take_block_changes(&mut self) -> Vec<(BlockChangeCause, BlockChange)> {
+        std::mem::take(&mut self.changes)
+    }
+
+    /// Turns recording block changes as `BlockChangedEvent`s on or off. Generation turns it off until the chunk is in the world.
+    pub(crate) fn set_recording_changes(&mut self, record: bool) {
+        self.record_changes = record;
     }
 
-    pub fn get_position(&self) -> ChunkCoordinate {
+    pub(crate) fn get_internal_storage(&self) -> &PalettedStorage {
+        &self.blocks
+    }
+
+    /// Approximate heap memory used by this chunk's block storage, in bytes.
+    pub fn block_storage_size(&self) -> usize {
+        self.blocks.heap_size()
+    }
+
+    pub fn get_position(&self) -> ChunkPos {
         self.position
     }
 }   
 
 trait GetBlockOrEmpty {
     fn get_block_or_empty(&self, x: usize, y: usize, z: usize) -> Block;
-    fn get_generic_or_empty(&self, x: usize, y: usize, z: usize) -> BlockId;
+    fn get_generic_or_empty(&self, x: usize, y: usize, z: usize) -> BlockStateId;
 }
 
 impl GetBlockOrEmpty for Option<&Chunk> {
@@ -160,10 +433,79 @@ impl GetBlockOrEmpty for Option<&Chunk> {
         }
     }
 
-    fn get_generic_or_empty(&self, x: usize, y: usize, z: usize) -> BlockId {
+    fn get_generic_or_empty(&self, x: usize, y: usize, z: usize) -> BlockStateId {
         match self.get_block_or_empty(x, y, z) {
-            Block::Generic(blockid) => blockid,
-            Block::Entity(_) => BlockId::EMPTY,
+            Block::Generic(state) => state,
+            Block::Entity(_) => BlockStateId::EMPTY,
         }
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use bevy::prelude::{App, Entity};
+    use crate::world::{block::{Block, state::BlockStateId, entity::{BlockComponent, BlockPosition, block_entity_lifecycle_system}, BlockId}, position::{BlockPos, ChunkPos}};
+    use super::{Chunk, BlockEntityUpdate, registry::{Chunks, ChunkState}};
+
+    #[test]
+    fn block_entities_are_despawned_when_replaced() {
+        let mut chunk = Chunk::new(ChunkPos::new(-1, 0, 2));
+        let (first, second) = (Entity::from_raw(1), Entity::from_raw(2));
+
+        chunk.set_block(1, 2, 3, Block::Entity(first));
+        chunk.set_block(4, 5, 6, Block::Entity(second));
+        assert_eq!(chunk.get_block_entities().count(), 2);
+        assert_eq!(chunk.get_block_entity(1, 2, 3), Some(first));
+
+        chunk.set_block(1, 2, 3, Block::Generic(BlockStateId(1)));
+        assert_eq!(chunk.get_block_entity(1, 2, 3), None);
+        assert_eq!(chunk.get_block_entities().collect::<Vec<_>>(), vec![second]);
+        assert_eq!(chunk.take_block_entity_updates(), vec![
+            BlockEntityUpdate::Placed(first, BlockPos::new(-15, 2, 35)),
+            BlockEntityUpdate::Placed(second, BlockPos::new(-12, 5, 38)),
+            BlockEntityUpdate::Removed(first),
+        ]);
+    }
+
+    #[test]
+    fn changes_are_only_recorded_when_turned_on() {
+        let mut chunk = Chunk::new(ChunkPos::new(0, 0, 0));
+        chunk.set_recording_changes(false);
+        chunk.set_block(1, 2, 3, Block::Generic(BlockStateId(1)));
+        // Block entities still get their position, even when the change isn't sent
+        chunk.set_block(4, 5, 6, Block::Entity(Entity::from_raw(1)));
+        assert!(chunk.take_block_changes().is_empty());
+        assert_eq!(chunk.take_block_entity_updates().len(), 1);
+
+        chunk.set_recording_changes(true);
+        chunk.set_block(1, 2, 3, Block::EMPTY);
+        assert_eq!(chunk.take_block_changes().len(), 1);
+    }
+
+    #[test]
+    fn block_entities_moved_to_another_chunk_are_kept() {
+        let mut app = App::new();
+        app.insert_resource(Chunks::new());
+        app.add_system(block_entity_lifecycle_system);
+
+        let block_entity = app.world.spawn(BlockComponent(BlockId(5))).id();
+        let mut from = Chunk::new(ChunkPos::new(0, 0, 0));
+        from.set_block(1, 2, 3, Block::Entity(block_entity));
+        let from = app.world.spawn(from).id();
+        let to = app.world.spawn(Chunk::new(ChunkPos::new(1, 0, 0))).id();
+        let mut chunk_registry = app.world.resource_mut::<Chunks>();
+        chunk_registry.transition(ChunkPos::new(0, 0, 0), ChunkState::Queued(from)).unwrap();
+        chunk_registry.transition(ChunkPos::new(1, 0, 0), ChunkState::Queued(to)).unwrap();
+        app.update();
+
+        app.world.get_mut::<Chunk>(from).unwrap().set_block(1, 2, 3, Block::EMPTY);
+        app.world.get_mut::<Chunk>(to).unwrap().set_block(4, 5, 6, Block::Entity(block_entity));
+        app.update();
+        assert_eq!(app.world.get::<BlockPosition>(block_entity), Some(&BlockPosition(BlockPos::new(20, 5, 6))));
+
+        // Once it's gone from every chunk, it's despawned
+        app.world.get_mut::<Chunk>(to).unwrap().set_block(4, 5, 6, Block::EMPTY);
+        app.update();
+        assert!(app.world.get_entity(block_entity).is_none());
+    }
+}
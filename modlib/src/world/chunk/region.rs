@@ -0,0 +1,597 @@
+//! Region files for saving chunks to disk.
+//!
+//! Chunks are grouped into cubic regions of `REGION_SIZE` chunks along each axis, and each region is stored in its own file,
+//! named `r.{x}.{y}.{z}.rcr` after the region's coordinates. All values are little-endian. A region file is laid out as follows:
+//!
+//! | Size           | Field                                                         |
+//! |----------------|---------------------------------------------------------------|
+//! | 4              | Magic bytes, `RCRG`                                           |
+//! | 2              | Format version, a `u16`                                       |
+//! | 2              | Reserved, always zero                                         |
+//! | 8 per slot     | Chunk table, a `u32` offset and `u32` length for each slot    |
+//! | varies         | Chunk data, encoded with [`serialize`](super::serialize)      |
+//!
+//! Slots are ordered by x, then y, then z of the chunk's position in the region.
+//! Offsets are from the start of the file, and a length of zero means the chunk isn't saved.
+//!
+//! Version 2 regions hold chunks in version 2 of the chunk format. Version 1 regions hold version 1 chunks, which are
+//! migrated with the block registry when the region is read, and the region is written back as version 2 on the next flush.
+//!
+//! Block entities are saved as the `BlockId` of their `BlockComponent`, a `u16`, in the chunk's block entity payloads.
+//! Nothing else about them is saved, so when they're loaded they're spawned again with only a `BlockComponent`.
+
+use std::{path::{PathBuf, Path}, sync::{Arc, Mutex, MutexGuard, RwLock}, fs, io, fmt::Display, time::Duration};
+use bevy::{prelude::*, app::AppExit, tasks::{IoTaskPool, Task}, utils::HashMap};
+use futures_lite::future;
+use crate::{
+    sync::{RecoverMutexPoison, RecoverPoison},
+    world::{block::{BlockId, entity::BlockComponent, registry::{BlockRegistryInternal, BLOCK_REGISTRY}}, position::{ChunkPos, LocalPos}},
+};
+use super::{
+    Chunk,
+    loader::Unloading,
+    serialize::{encode_chunk_with_entities, decode_chunk, migrate_chunk, ChunkDecodeError},
+};
+
+/// The amount of chunks along each axis of a region.
+pub const REGION_SIZE: i32 = 8;
+const REGION_SLOTS: usize = (REGION_SIZE * REGION_SIZE * REGION_SIZE) as usize;
+
+/// The magic bytes at the start of every region file.
+pub const REGION_FORMAT_MAGIC: [u8; 4] = *b"RCRG";
+/// The newest version of the region format.
+pub const REGION_FORMAT_VERSION: u16 = 2;
+
+const HEADER_SIZE: usize = 8 + REGION_SLOTS * 8;
+
+/// How many regions are kept in memory before unused ones are dropped.
+const MAX_CACHED_REGIONS: usize = 64;
+
+/// How often `flush_world_save_system` writes changed regions to disk.
+pub const FLUSH_INTERVAL: Duration = Duration::from_secs(10);
+
+pub type RegionCoordinate = (i32, i32, i32);
+
+/// Saves and loads chunks from region files in a directory. Insert this as a resource to enable world persistence.
+///
+/// When present, chunks are read from disk before being generated, and modified chunks are written back when they're unloaded or the app exits.
+/// Regions are cached in memory, and changes are only written to disk when the `WorldSave` is flushed, which happens
+/// in the background every `FLUSH_INTERVAL` and when the app exits.
+///
+/// Only the block type of block entities is saved. Any other components on them, like the contents of a chest, are lost
+/// when their chunk is unloaded.
+#[derive(Resource, Clone)]
+pub struct WorldSave {
+    storage: Arc<Mutex<RegionStorage>>,
+    /// Held while regions are written to disk, so two flushes never write the same file at once.
+    writing: Arc<Mutex<()>>,
+    /// Used to migrate chunks saved with older versions of the chunk format.
+    registry: Arc<RwLock<BlockRegistryInternal>>,
+}
+
+impl WorldSave {
+    pub fn new(directory: impl Into<PathBuf>) -> Self {
+        Self::with_registry(directory, BLOCK_REGISTRY.clone())
+    }
+
+    /// Creates a `WorldSave` that migrates old chunks with the given block registry, instead of the global one.
+    pub(crate) fn with_registry(directory: impl Into<PathBuf>, registry: Arc<RwLock<BlockRegistryInternal>>) -> Self {
+        Self {
+            storage: Arc::new(Mutex::new(RegionStorage {
+                directory: directory.into(),
+                regions: HashMap::new(),
+                access_counter: 0,
+                evictions: 0,
+            })),
+            writing: Arc::new(Mutex::new(())),
+            registry,
+        }
+    }
+
+    /// Loads a chunk from disk. Returns `Ok(None)` if the chunk has never been saved.
+    ///
+    /// Block entities aren't spawned, so their blocks are loaded as `BlockStateId::EMPTY`, and are returned separately.
+    pub fn load_chunk(&self, coord: ChunkPos) -> Result<Option<LoadedChunk>, RegionError> {
+        // Copied out so the chunk is decoded without holding the lock
+        let bytes = match self.lock_region(region_of(coord))?.region(region_of(coord)).slots[slot_of(coord)].clone() {
+            Some(bytes) => bytes,
+            None => return Ok(None),
+        };
+
+        let decoded = decode_chunk(&bytes)?;
+        let mut block_entities = Vec::with_capacity(decoded.block_entities.len());
+        for entity in decoded.block_entities {
+            let [x, y, z] = entity.position;
+            let block: [u8; 2] = entity.payload.as_slice().try_into()
+                .map_err(|_| RegionError::InvalidBlockEntity(coord))?;
+            block_entities.push((LocalPos::new(x, y, z), BlockId(u16::from_le_bytes(block))));
+        }
+
+        Ok(Some(LoadedChunk { chunk: decoded.chunk, block_entities }))
+    }
+
+    /// Stores a chunk in its region. The chunk is only written to disk when `flush` is called.
+    ///
+    /// `block_of` gives the block of each block entity in the chunk. Block entities it returns `None` for aren't saved.
+    pub fn save_chunk(&self, chunk: &Chunk, block_of: impl Fn(Entity) -> Option<BlockId>) -> Result<(), RegionError> {
+        let coord = chunk.get_position();
+        let mut unsaved = 0;
+        let bytes = encode_chunk_with_entities(chunk, |entity| {
+            let block = block_of(entity);
+            if block.is_none() { unsaved += 1; }
+            block.map(|block| block.0.to_le_bytes().to_vec())
+        });
+        if unsaved > 0 {
+            warn!("{unsaved} block entities in chunk {coord} have no block, and weren't saved");
+        }
+
+        let mut storage = self.lock_region(region_of(coord))?;
+        let region = storage.region(region_of(coord));
+        region.slots[slot_of(coord)] = Some(bytes);
+        region.dirty = true;
+
+        Ok(())
+    }
+
+    /// Writes all changed regions to disk.
+    ///
+    /// Regions are encoded while the storage is locked, but written without holding the lock,
+    /// so chunks can still be saved and loaded by other threads while a flush is writing.
+    pub fn flush(&self) -> Result<(), RegionError> {
+        let _writing = self.writing.lock_or_recover();
+        let (directory, pending) = self.storage.lock_or_recover().take_dirty();
+
+        let mut result = Ok(());
+        let mut written = Vec::with_capacity(pending.len());
+        for (coord, bytes) in pending {
+            match write_region(&directory, coord, &bytes) {
+                Ok(()) => written.push(coord),
+                Err(error) => if result.is_ok() { result = Err(error) },
+            }
+        }
+
+        self.storage.lock_or_recover().finish_writing(&written);
+        result
+    }
+
+    /// Locks the storage, with the region at `coord` in the cache.
+    ///
+    /// Region files that aren't cached yet are read before taking the lock, so reading from disk doesn't hold up
+    /// other threads loading and saving chunks.
+    fn lock_region(&self, coord: RegionCoordinate) -> Result<MutexGuard<'_, RegionStorage>, RegionError> {
+        loop {
+            let (directory, evictions) = {
+                let storage = self.storage.lock_or_recover();
+                if storage.regions.contains_key(&coord) { return Ok(storage); }
+                (storage.directory.clone(), storage.evictions)
+            };
+
+            let region = Region::read(&region_path(&directory, coord), &self.registry)?;
+            let mut storage = self.storage.lock_or_recover();
+            // If the region was cached, saved to, and evicted while it was being read, the file that was read may be outdated
+            if storage.evictions != evictions && !storage.regions.contains_key(&coord) { continue; }
+            storage.insert(coord, region);
+            return Ok(storage);
+        }
+    }
+}
+
+/// A chunk loaded by `WorldSave::load_chunk`.
+pub struct LoadedChunk {
+    /// The loaded chunk. Block entities are not spawned, so their positions contain `BlockStateId::EMPTY`.
+    pub chunk: Chunk,
+    /// The position and block of each block entity in the chunk, to be spawned and placed into `chunk` by the caller.
+    pub block_entities: Vec<(LocalPos, BlockId)>,
+}
+
+#[derive(Debug)]
+pub enum RegionError {
+    Io(io::Error),
+    /// The region file is malformed.
+    InvalidRegion(PathBuf),
+    /// A chunk in the region file couldn't be decoded.
+    InvalidChunk(ChunkDecodeError),
+    /// A block entity in the chunk at this position doesn't have a valid block.
+    InvalidBlockEntity(ChunkPos),
+}
+
+impl Display for RegionError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Io(error) => write!(f, "region i/o error: {error}"),
+            Self::InvalidRegion(path) => write!(f, "invalid region file {}", path.display()),
+            Self::InvalidChunk(error) => write!(f, "invalid chunk in region: {error}"),
+            Self::InvalidBlockEntity(coord) => write!(f, "invalid block entity in chunk {coord}"),
+        }
+    }
+}
+
+impl std::error::Error for RegionError {}
+
+impl From<io::Error> for RegionError {
+    fn from(value: io::Error) -> Self {
+        Self::Io(value)
+    }
+}
+
+impl From<ChunkDecodeError> for RegionError {
+    fn from(value: ChunkDecodeError) -> Self {
+        Self::InvalidChunk(value)
+    }
+}
+
+struct RegionStorage {
+    directory: PathBuf,
+    regions: HashMap<RegionCoordinate, Region>,
+    access_counter: u64,
+    /// How many regions have been dropped from the cache.
+    evictions: u64,
+}
+
+impl RegionStorage {
+    /// Returns a region from the cache, which `WorldSave::lock_region` makes sure it's in.
+    fn region(&mut self, coord: RegionCoordinate) -> &mut Region {
+        self.access_counter += 1;
+        let last_access = self.access_counter;
+
+        let region = self.regions.get_mut(&coord).expect("the region was loaded by lock_region");
+        region.last_access = last_access;
+        region
+    }
+
+    /// Adds a region read from disk to the cache, unless another thread already added it while it was being read.
+    /// Evicts after inserting, so the cache doesn't grow past `MAX_CACHED_REGIONS`.
+    fn insert(&mut self, coord: RegionCoordinate, mut region: Region) {
+        self.access_counter += 1;
+        region.last_access = self.access_counter;
+        self.regions.entry(coord).or_insert(region);
+        self.evict_unused();
+    }
+
+    /// Encodes every changed region to be written to disk, and marks them as being written.
+    fn take_dirty(&mut self) -> (PathBuf, Vec<(RegionCoordinate, Vec<u8>)>) {
+        let mut pending = vec![];
+        for (coord, region) in self.regions.iter_mut() {
+            if !region.dirty { continue; }
+            pending.push((*coord, region.encode()));
+            region.dirty = false;
+            region.writing = true;
+        }
+        (self.directory.clone(), pending)
+    }
+
+    /// Finishes a flush. Regions that were being written but aren't in `written` failed, and are written again next time.
+    fn finish_writing(&mut self, written: &[RegionCoordinate]) {
+        for (coord, region) in self.regions.iter_mut() {
+            if !region.writing { continue; }
+            region.writing = false;
+            if !written.contains(coord) {
+                region.dirty = true;
+            }
+        }
+
+        self.evict_unused();
+    }
+
+    /// Drops the least recently used regions that have already been written, until the cache is small enough.
+    fn evict_unused(&mut self) {
+        while self.regions.len() > MAX_CACHED_REGIONS {
+            let oldest = self.regions.iter()
+                .filter(|(_, region)| !region.dirty && !region.writing)
+                .min_by_key(|(_, region)| region.last_access)
+                .map(|(coord, _)| *coord);
+            match oldest {
+                Some(coord) => {
+                    self.regions.remove(&coord);
+                    self.evictions += 1;
+                },
+                None => break,
+            }
+        }
+    }
+}
+
+struct Region {
+    slots: Vec<Option<Vec<u8>>>,
+    /// Set when a chunk was saved since the region was last written.
+    dirty: bool,
+    /// Set while a flush is writing the region, so it isn't dropped from the cache before it's on disk.
+    writing: bool,
+    last_access: u64,
+}
+
+impl Region {
+    /// Reads a region file, or creates an empty region if the file doesn't exist.
+    /// The registry is only locked if the region has to be migrated from an older version.
+    fn read(path: &Path, registry: &RwLock<BlockRegistryInternal>) -> Result<Self, RegionError> {
+        let mut region = Self {
+            slots: vec![None; REGION_SLOTS],
+            dirty: false,
+            writing: false,
+            last_access: 0,
+        };
+
+        let bytes = match fs::read(path) {
+            Ok(bytes) => bytes,
+            Err(error) if error.kind() == io::ErrorKind::NotFound => return Ok(region),
+            Err(error) => return Err(error.into()),
+        };
+
+        let invalid = || RegionError::InvalidRegion(path.to_owned());
+        if bytes.len() < HEADER_SIZE || bytes[0..4] != REGION_FORMAT_MAGIC {
+            return Err(invalid());
+        }
+        let version = u16::from_le_bytes([bytes[4], bytes[5]]);
+        if version == 0 || version > REGION_FORMAT_VERSION {
+            return Err(invalid());
+        }
+
+        for (slot, entry) in bytes[8..HEADER_SIZE].chunks_exact(8).enumerate() {
+            let offset = u32::from_le_bytes(entry[0..4].try_into().unwrap()) as usize;
+            let length = u32::from_le_bytes(entry[4..8].try_into().unwrap()) as usize;
+            if length == 0 { continue; }
+            let data = bytes.get(offset..offset + length).ok_or_else(invalid)?;
+            region.slots[slot] = Some(data.to_vec());
+        }
+
+        if version < REGION_FORMAT_VERSION {
+            info!("Migrating region file {} from region format version {version}", path.display());
+            let registry = registry.read_or_recover();
+            for data in region.slots.iter_mut().flatten() {
+                *data = migrate_chunk(data, &registry)?;
+            }
+            region.dirty = true;
+        }
+
+        Ok(region)
+    }
+
+    /// Encodes the region as the contents of a region file.
+    fn encode(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(HEADER_SIZE);
+        bytes.extend(REGION_FORMAT_MAGIC);
+        bytes.extend(REGION_FORMAT_VERSION.to_le_bytes());
+        bytes.extend(0u16.to_le_bytes());
+
+        let mut offset = HEADER_SIZE;
+        for slot in &self.slots {
+            let length = slot.as_ref().map_or(0, |data| data.len());
+            bytes.extend((offset as u32).to_le_bytes());
+            bytes.extend((length as u32).to_le_bytes());
+            offset += length;
+        }
+        for data in self.slots.iter().flatten() {
+            bytes.extend(data);
+        }
+
+        bytes
+    }
+}
+
+/// Writes a region file to a temporary file, then moves it over the old one, so a crash can't leave a half-written region behind.
+fn write_region(directory: &Path, coord: RegionCoordinate, bytes: &[u8]) -> Result<(), RegionError> {
+    fs::create_dir_all(directory)?;
+    let path = region_path(directory, coord);
+    let temp_path = path.with_extension("rcr.tmp");
+    fs::write(&temp_path, bytes)?;
+    fs::rename(&temp_path, path)?;
+
+    Ok(())
+}
+
+fn region_of(coord: ChunkPos) -> RegionCoordinate {
+    (coord.x.div_euclid(REGION_SIZE), coord.y.div_euclid(REGION_SIZE), coord.z.div_euclid(REGION_SIZE))
+}
+
+fn slot_of(coord: ChunkPos) -> usize {
+    let (x, y, z) = (coord.x.rem_euclid(REGION_SIZE), coord.y.rem_euclid(REGION_SIZE), coord.z.rem_euclid(REGION_SIZE));
+    ((x * REGION_SIZE + y) * REGION_SIZE + z) as usize
+}
+
+fn region_path(directory: &Path, coord: RegionCoordinate) -> PathBuf {
+    directory.join(format!("r.{}.{}.{}.rcr", coord.0, coord.1, coord.2))
+}
+
+/// Stores modified chunks in their region right before `chunk_despawn_system` despawns them. The regions are written
+/// to disk by `flush_world_save_system`.
+///
+/// Chunks are saved once they're actually `Unloading`, rather than when the unload is requested, so unloads that are
+/// rejected don't save anything, and edits made while the chunk is unloading aren't lost.
+pub(crate) fn save_unloaded_chunks_system(
+    world_save: Option<Res<WorldSave>>,
+    mut chunks: Query<&mut Chunk, With<Unloading>>,
+    blocks: Query<&BlockComponent>,
+) {
+    let world_save = match world_save {
+        Some(world_save) => world_save,
+        None => return,
+    };
+
+    for mut chunk in chunks.iter_mut() {
+        if !chunk.is_modified() { continue; }
+
+        match world_save.save_chunk(&chunk, |entity| blocks.get(entity).ok().map(|block| block.0)) {
+            Ok(()) => chunk.bypass_change_detection().clear_modified(),
+            Err(error) => error!("Failed to save chunk {}: {error}", chunk.get_position()),
+        }
+    }
+}
+
+/// Writes changed regions to disk every `FLUSH_INTERVAL`. Regions are written in a task on the `IoTaskPool`,
+/// so writing whole region files doesn't hold up the frame.
+pub(crate) fn flush_world_save_system(
+    world_save: Option<Res<WorldSave>>,
+    time: Res<Time>,
+    mut since_flush: Local<Duration>,
+    mut flushing: Local<Option<Task<Result<(), RegionError>>>>,
+) {
+    let world_save = match world_save {
+        Some(world_save) => world_save,
+        None => return,
+    };
+
+    // Only one flush runs at a time
+    if let Some(task) = flushing.as_mut() {
+        match future::block_on(future::poll_once(task)) {
+            Some(Err(error)) => error!("Failed to write regions to disk: {error}"),
+            Some(Ok(())) => {},
+            None => return,
+        }
+        *flushing = None;
+    }
+
+    *since_flush += time.delta();
+    if *since_flush < FLUSH_INTERVAL { return; }
+    *since_flush = Duration::ZERO;
+
+    let world_save = world_save.clone();
+    *flushing = Some(IoTaskPool::get().spawn(async move { world_save.flush() }));
+}
+
+/// Saves every modified chunk when the app exits.
+pub(crate) fn save_on_exit_system(
+    world_save: Option<Res<WorldSave>>,
+    exit_events: EventReader<AppExit>,
+    mut chunks: Query<&mut Chunk>,
+    blocks: Query<&BlockComponent>,
+) {
+    if exit_events.is_empty() { return; }
+    let world_save = match world_save {
+        Some(world_save) => world_save,
+        None => return,
+    };
+
+    for mut chunk in chunks.iter_mut() {
+        if !chunk.is_modified() { continue; }
+        match world_save.save_chunk(&chunk, |entity| blocks.get(entity).ok().map(|block| block.0)) {
+            Ok(()) => chunk.bypass_change_detection().clear_modified(),
+            Err(error) => error!("Failed to save chunk {}: {error}", chunk.get_position()),
+        }
+    }
+
+    match world_save.flush() {
+        Ok(()) => info!("Saved world"),
+        Err(error) => error!("Failed to write regions to disk: {error}"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::world::{block::{Block, data::BlockData, state::{BlockProperty, BlockStateId}}, chunk::{Chunk, meshing::MeshingVisibility}};
+    use super::*;
+
+    #[test]
+    fn chunks_survive_a_flush() {
+        let directory = std::env::temp_dir().join(format!("rustcraft_region_test_{}", std::process::id()));
+        let _ = fs::remove_dir_all(&directory);
+
+        let mut chunk = Chunk::new(ChunkPos::new(-1, 9, 4));
+        chunk.set_block(3, 4, 5, Block::Generic(BlockStateId(2)));
+        let chest = Entity::from_raw(7);
+        chunk.set_block(1, 2, 3, Block::Entity(chest));
+        chunk.set_block(4, 4, 4, Block::Entity(Entity::from_raw(8)));
+        let save = WorldSave::new(&directory);
+        save.save_chunk(&chunk, |entity| (entity == chest).then_some(BlockId(5))).unwrap();
+        // Nothing is written until the save is flushed
+        assert!(!directory.exists());
+        save.flush().unwrap();
+
+        // A fresh WorldSave has to read the region back from disk
+        let save = WorldSave::new(&directory);
+        let LoadedChunk { chunk: loaded, block_entities } = save.load_chunk(ChunkPos::new(-1, 9, 4)).unwrap().unwrap();
+        // Only block entities with a block are saved, and come back as empty blocks until they're spawned again
+        assert_eq!(block_entities, vec![(LocalPos::new(1, 2, 3), BlockId(5))]);
+        assert!(matches!(loaded.get_block(1, 2, 3), Block::Generic(BlockStateId::EMPTY)));
+        assert!(matches!(loaded.get_block(3, 4, 5), Block::Generic(BlockStateId(2))));
+        assert!(matches!(loaded.get_block(0, 0, 0), Block::Generic(BlockStateId::EMPTY)));
+        assert!(save.load_chunk(ChunkPos::new(-1, 9, 5)).unwrap().is_none());
+
+        fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn outdated_regions_are_migrated() {
+        let directory = std::env::temp_dir().join(format!("rustcraft_region_outdated_test_{}", std::process::id()));
+        let _ = fs::remove_dir_all(&directory);
+        let mut registry = BlockRegistryInternal::new();
+        let mut door = BlockData::new("door", MeshingVisibility::Opaque);
+        door.add_property(BlockProperty::bool("open"));
+        registry.add_block_type(door);
+        registry.add_block_type(BlockData::new("stone", MeshingVisibility::Opaque));
+        let stone = registry.get_default_state_by_string_id("stone").unwrap();
+        let registry = Arc::new(RwLock::new(registry));
+
+        // A version 1 region, holding a version 1 chunk with the stone's `BlockId` in its palette
+        let mut chunk = Chunk::new(ChunkPos::new(1, 2, 3));
+        chunk.set_block(4, 5, 6, Block::Generic(BlockStateId(2)));
+        let save = WorldSave::with_registry(&directory, registry.clone());
+        save.save_chunk(&chunk, |_| None).unwrap();
+        save.flush().unwrap();
+        let path = region_path(&directory, (0, 0, 0));
+        let mut bytes = fs::read(&path).unwrap();
+        bytes[4..6].copy_from_slice(&1u16.to_le_bytes());
+        bytes[HEADER_SIZE + 4..HEADER_SIZE + 6].copy_from_slice(&1u16.to_le_bytes());
+        fs::write(&path, &bytes).unwrap();
+
+        let save = WorldSave::with_registry(&directory, registry.clone());
+        let loaded = save.load_chunk(ChunkPos::new(1, 2, 3)).unwrap().unwrap().chunk;
+        assert!(matches!(loaded.get_block(4, 5, 6), Block::Generic(state) if state == stone));
+        assert!(matches!(loaded.get_block(0, 0, 0), Block::Generic(BlockStateId::EMPTY)));
+
+        // The migrated region is written back in the newest version
+        save.flush().unwrap();
+        let bytes = fs::read(&path).unwrap();
+        assert_eq!(bytes[4..6], REGION_FORMAT_VERSION.to_le_bytes());
+        let loaded = WorldSave::with_registry(&directory, registry).load_chunk(ChunkPos::new(1, 2, 3)).unwrap().unwrap().chunk;
+        assert!(matches!(loaded.get_block(4, 5, 6), Block::Generic(state) if state == stone));
+
+        // Newer regions can't be read at all
+        let mut bytes = bytes;
+        bytes[4..6].copy_from_slice(&(REGION_FORMAT_VERSION + 1).to_le_bytes());
+        fs::write(&path, &bytes).unwrap();
+        assert!(matches!(WorldSave::new(&directory).load_chunk(ChunkPos::new(1, 2, 3)), Err(RegionError::InvalidRegion(_))));
+
+        fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn chunks_are_saved_once_they_are_unloading() {
+        let directory = std::env::temp_dir().join(format!("rustcraft_region_unload_test_{}", std::process::id()));
+        let _ = fs::remove_dir_all(&directory);
+        let save = WorldSave::new(&directory);
+
+        let mut app = App::new();
+        app.insert_resource(save.clone());
+        app.add_system(save_unloaded_chunks_system);
+        let mut unloading = Chunk::new(ChunkPos::new(0, 0, 0));
+        unloading.set_block(1, 1, 1, Block::Generic(BlockStateId(3)));
+        let mut loaded = Chunk::new(ChunkPos::new(1, 0, 0));
+        loaded.set_block(1, 1, 1, Block::Generic(BlockStateId(3)));
+        let entity = app.world.spawn(unloading).id();
+        app.world.spawn(loaded);
+        app.update();
+        assert!(save.load_chunk(ChunkPos::new(0, 0, 0)).unwrap().is_none());
+
+        // Edits made before the chunk is despawned are part of what's saved
+        app.world.get_mut::<Chunk>(entity).unwrap().set_block(2, 2, 2, Block::Generic(BlockStateId(4)));
+        app.world.entity_mut(entity).insert(Unloading);
+        app.update();
+        let saved = save.load_chunk(ChunkPos::new(0, 0, 0)).unwrap().unwrap().chunk;
+        assert!(matches!(saved.get_block(1, 1, 1), Block::Generic(BlockStateId(3))));
+        assert!(matches!(saved.get_block(2, 2, 2), Block::Generic(BlockStateId(4))));
+        assert!(save.load_chunk(ChunkPos::new(1, 0, 0)).unwrap().is_none());
+    }
+
+    #[test]
+    fn the_region_cache_stays_bounded() {
+        // Regions that were never saved are read as empty, so nothing has to exist on disk
+        let save = WorldSave::new(std::env::temp_dir().join(format!("rustcraft_region_cache_test_{}", std::process::id())));
+        for x in 0..=MAX_CACHED_REGIONS as i32 {
+            assert!(save.load_chunk(ChunkPos::new(x * REGION_SIZE, 0, 0)).unwrap().is_none());
+        }
+        let storage = save.storage.lock_or_recover();
+        assert_eq!(storage.regions.len(), MAX_CACHED_REGIONS);
+        // The least recently used region is the one dropped
+        assert!(!storage.regions.contains_key(&(0, 0, 0)));
+    }
+}
@@ -1,50 +1,302 @@
-use std::collections::BTreeMap;
-use bevy::prelude::{Resource, Entity, warn, IVec3};
-
-pub type ChunkCoordinate = (i32, i32, i32);
+use std::{collections::{BTreeMap, BTreeSet}, fmt::Display};
+use bevy::{prelude::{Resource, Entity, World, IVec2, Vec3, warn}, utils::HashMap};
+use crate::world::position::ChunkPos;
+use super::spatial::{SpatialIndex, chunks_along_ray};
 
 #[derive(Resource)]
 pub struct Chunks {
-    registry: BTreeMap<ChunkCoordinate, ChunkState>,
+    registry: BTreeMap<ChunkPos, ChunkState>,
+    /// Every chunk in `registry`, for the spatial queries.
+    spatial: SpatialIndex,
+    /// The y coordinates of the chunks in `registry`, for each x and z.
+    columns: HashMap<IVec2, BTreeSet<i32>>,
+    /// Transitions since the last time lifecycle events were sent.
+    transitions: Vec<(ChunkPos, ChunkState, ChunkState)>,
 }
 
-#[derive(Clone, Copy, PartialEq, Eq)]
+/// The lifecycle of a chunk. Chunks move through these states in order, except that they can start unloading at any point.
+///
+/// `Absent` → `Queued` → `Generating` → `Generated` → `Meshing` → `Ready` → `Unloading` → `Absent`
+///
+/// Chunks that don't need a mesh, like chunks full of air, go straight from `Generated` to `Ready`.
+/// Chunks whose generation or first meshing task fails go to `Failed`, and back to `Queued` or `Generated` when retried.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum ChunkState {
-    /// This variant has multiple meanings.
-    /// If it's returned from `get`, the chunk does not exist.
-    /// If it's passed in `set`, it removes the chunk from the registry.
+    /// The chunk isn't loaded. Returned from `get` for chunks that aren't in the registry.
     Absent,
-    BeingGenerated,
-    Present(Entity),
+    /// The chunk's entity exists, and is waiting for a generation task to be started.
+    Queued(Entity),
+    /// The chunk is being generated or loaded from disk.
+    Generating(Entity),
+    /// The chunk's blocks are available, but it doesn't have a mesh yet.
+    Generated(Entity),
+    /// The chunk's first mesh is being built.
+    Meshing(Entity),
+    /// The chunk is fully loaded and has its first mesh. Remeshing a ready chunk doesn't change its state.
+    Ready(Entity),
+    /// A generation or meshing task for the chunk failed. The entity has a `ChunkFailure` saying why.
+    Failed(Entity),
+    /// The chunk is about to be unloaded. Its entity is despawned in the frame after it starts unloading.
+    Unloading(Entity),
+}
+
+impl ChunkState {
+    /// The chunk's entity, if it has one.
+    pub fn entity(&self) -> Option<Entity> {
+        match self {
+            ChunkState::Absent => None,
+            ChunkState::Queued(entity)
+            | ChunkState::Generating(entity)
+            | ChunkState::Generated(entity)
+            | ChunkState::Meshing(entity)
+            | ChunkState::Ready(entity)
+            | ChunkState::Failed(entity)
+            | ChunkState::Unloading(entity) => Some(*entity),
+        }
+    }
+
+    /// The chunk's entity, if its blocks can be accessed.
+    pub fn loaded_entity(&self) -> Option<Entity> {
+        match self {
+            ChunkState::Generated(entity)
+            | ChunkState::Meshing(entity)
+            | ChunkState::Ready(entity) => Some(*entity),
+            _ => None,
+        }
+    }
+
+    /// Checks if a chunk can go from this state to `to`.
+    pub fn can_transition_to(&self, to: &ChunkState) -> bool {
+        // A chunk keeps the same entity for its whole lifetime
+        if let (Some(from), Some(to)) = (self.entity(), to.entity()) {
+            if from != to { return false; }
+        }
+
+        matches!((self, to),
+            (ChunkState::Absent, ChunkState::Queued(_))
+            | (ChunkState::Queued(_), ChunkState::Generating(_))
+            | (ChunkState::Generating(_), ChunkState::Generated(_))
+            | (ChunkState::Generated(_), ChunkState::Meshing(_))
+            | (ChunkState::Generated(_), ChunkState::Ready(_))
+            | (ChunkState::Meshing(_), ChunkState::Ready(_))
+            | (ChunkState::Generating(_), ChunkState::Failed(_))
+            | (ChunkState::Meshing(_), ChunkState::Failed(_))
+            | (ChunkState::Failed(_), ChunkState::Queued(_))
+            | (ChunkState::Failed(_), ChunkState::Generated(_))
+            | (ChunkState::Queued(_), ChunkState::Unloading(_))
+            | (ChunkState::Generating(_), ChunkState::Unloading(_))
+            | (ChunkState::Generated(_), ChunkState::Unloading(_))
+            | (ChunkState::Meshing(_), ChunkState::Unloading(_))
+            | (ChunkState::Ready(_), ChunkState::Unloading(_))
+            | (ChunkState::Failed(_), ChunkState::Unloading(_))
+            | (ChunkState::Unloading(_), ChunkState::Absent)
+        )
+    }
+}
+
+/// Returned when trying to move a chunk into a state it can't go to from its current state.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct InvalidChunkTransition {
+    pub coord: ChunkPos,
+    pub from: ChunkState,
+    pub to: ChunkState,
+}
+
+impl Display for InvalidChunkTransition {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "invalid state transition for chunk {}: {:?} to {:?}", self.coord, self.from, self.to)
+    }
 }
 
+impl std::error::Error for InvalidChunkTransition {}
+
 impl Chunks {
     pub fn new() -> Self {
         Self {
             registry: BTreeMap::new(),
+            spatial: SpatialIndex::default(),
+            columns: HashMap::new(),
+            transitions: vec![],
         }
     }
 
-    pub fn get(&self, coord: ChunkCoordinate) -> ChunkState {
+    pub fn get(&self, coord: ChunkPos) -> ChunkState {
         match self.registry.get(&coord) {
             Some(value) => *value,
             None => ChunkState::Absent,
         }
     }
 
-    /// Removes the chunk from the registry. This does not delete the entity itself! Use WorldMapHelpers for confidence in deleting chunks.
-    pub fn set(&mut self, coord: ChunkCoordinate, to: ChunkState){
+    /// Moves a chunk into a new state, if it's a valid transition from its current state. See `ChunkState` for the valid transitions.
+    ///
+    /// This only changes the registry, not the chunk's entity. Transitioning to `Absent` removes the chunk from the registry.
+    pub fn transition(&mut self, coord: ChunkPos, to: ChunkState) -> Result<(), InvalidChunkTransition> {
+        let from = self.get(coord);
+        if !from.can_transition_to(&to) {
+            return Err(InvalidChunkTransition { coord, from, to });
+        }
+
         match to {
             ChunkState::Absent => {
                 self.registry.remove(&coord);
+                self.spatial.remove(coord);
+                let column = IVec2::new(coord.x, coord.z);
+                if let Some(ys) = self.columns.get_mut(&column) {
+                    ys.remove(&coord.y);
+                    if ys.is_empty() {
+                        self.columns.remove(&column);
+                    }
+                }
             },
             _ => {
-                self.registry.insert(coord, to);
+                if self.registry.insert(coord, to).is_none() {
+                    self.spatial.insert(coord);
+                    self.columns.entry(IVec2::new(coord.x, coord.z)).or_default().insert(coord.y);
+                }
             },
         }
+        self.transitions.push((coord, from, to));
+
+        Ok(())
     }
 
-    pub fn get_inner_registry(&self) -> &BTreeMap<ChunkCoordinate, ChunkState> {
+    /// Like `transition`, but logs a warning instead of returning an error.
+    pub(crate) fn transition_or_warn(&mut self, coord: ChunkPos, to: ChunkState) {
+        if let Err(error) = self.transition(coord, to) {
+            warn!("{error}");
+        }
+    }
+
+    /// Returns a command that transitions the chunk from `from` to `to` when it's applied, for systems that can't access `Chunks` mutably.
+    /// Does nothing if the chunk is no longer in the `from` state by then.
+    pub(crate) fn transition_command(coord: ChunkPos, from: ChunkState, to: ChunkState) -> impl FnOnce(&mut World) + Send + Sync + 'static {
+        move |world: &mut World| {
+            let mut chunks = world.resource_mut::<Chunks>();
+            if chunks.get(coord) == from {
+                chunks.transition_or_warn(coord, to);
+            }
+        }
+    }
+
+    pub(crate) fn drain_transitions(&mut self) -> impl Iterator<Item = (ChunkPos, ChunkState, ChunkState)> + '_ {
+        self.transitions.drain(..)
+    }
+
+    pub fn get_inner_registry(&self) -> &BTreeMap<ChunkPos, ChunkState> {
         &self.registry
     }
-}
\ No newline at end of file
+
+    /// The number of chunks that aren't `Absent`.
+    pub fn len(&self) -> usize {
+        self.registry.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.registry.is_empty()
+    }
+
+    /// Every chunk from `min` to `max`, inclusive, in no particular order.
+    pub fn in_aabb(&self, min: ChunkPos, max: ChunkPos) -> impl Iterator<Item = (ChunkPos, ChunkState)> + '_ {
+        self.spatial.in_aabb(min, max).map(|coord| (coord, self.get(coord)))
+    }
+
+    /// Every chunk whose center is at most `radius` world units from `point` in world space, in no particular order.
+    /// Distances are measured like `by_distance`, so this is the same as taking chunks from it until they're too far.
+    pub fn within_radius(&self, point: Vec3, radius: f32) -> impl Iterator<Item = (ChunkPos, ChunkState)> + '_ {
+        let radius = radius.max(0.0);
+        let (min, max) = (ChunkPos::containing(point - Vec3::splat(radius)), ChunkPos::containing(point + Vec3::splat(radius)));
+        self.in_aabb(min, max).filter(move |(coord, _)| coord.center().distance_squared(point) <= radius * radius)
+    }
+
+    /// Every chunk a ray passes through, in the order the ray reaches them. Chunks that are `Absent` are skipped.
+    /// `origin` is in world space, and the ray stops after `max_distance` world units.
+    pub fn along_ray(&self, origin: Vec3, direction: Vec3, max_distance: f32) -> impl Iterator<Item = (ChunkPos, ChunkState)> + '_ {
+        chunks_along_ray(origin, direction, max_distance)
+            .map(|coord| (coord, self.get(coord)))
+            .filter(|(_, state)| *state != ChunkState::Absent)
+    }
+
+    /// Every chunk stacked in the column at chunk coordinates `x` and `z`, from the bottom up.
+    pub fn column(&self, x: i32, z: i32) -> impl DoubleEndedIterator<Item = (ChunkPos, ChunkState)> + '_ {
+        self.columns.get(&IVec2::new(x, z))
+            .into_iter()
+            .flatten()
+            .map(move |y| (ChunkPos::new(x, *y, z), self.get(ChunkPos::new(x, *y, z))))
+    }
+
+    /// Every chunk, closest first, by the distance from `point` in world space to the center of the chunk.
+    /// The chunks are found lazily, so taking only the first few is cheap.
+    pub fn by_distance(&self, point: Vec3) -> impl Iterator<Item = (ChunkPos, ChunkState)> + '_ {
+        self.spatial.by_distance(point).map(|coord| (coord, self.get(coord)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lifecycle_transitions() {
+        let entity = Entity::from_raw(1);
+        let mut chunks = Chunks::new();
+
+        assert!(chunks.transition(ChunkPos::new(0, 0, 0), ChunkState::Generating(entity)).is_err());
+        for state in [
+            ChunkState::Queued(entity),
+            ChunkState::Generating(entity),
+            ChunkState::Generated(entity),
+            ChunkState::Meshing(entity),
+            ChunkState::Ready(entity),
+        ] {
+            chunks.transition(ChunkPos::new(0, 0, 0), state).unwrap();
+        }
+        assert!(chunks.transition(ChunkPos::new(0, 0, 0), ChunkState::Unloading(Entity::from_raw(2))).is_err());
+        chunks.transition(ChunkPos::new(0, 0, 0), ChunkState::Unloading(entity)).unwrap();
+        chunks.transition(ChunkPos::new(0, 0, 0), ChunkState::Absent).unwrap();
+
+        assert_eq!(chunks.get(ChunkPos::new(0, 0, 0)), ChunkState::Absent);
+        assert!(chunks.get_inner_registry().is_empty());
+        assert_eq!(chunks.drain_transitions().count(), 7);
+    }
+
+    #[test]
+    fn columns_follow_the_registry() {
+        let mut chunks = Chunks::new();
+        for (i, coord) in [ChunkPos::new(1, 4, 2), ChunkPos::new(1, -3, 2), ChunkPos::new(1, 0, 3), ChunkPos::new(1, 9, 2)].into_iter().enumerate() {
+            chunks.transition(coord, ChunkState::Queued(Entity::from_raw(i as u32))).unwrap();
+        }
+        let column = |chunks: &Chunks| chunks.column(1, 2).rev().map(|(coord, _)| coord.y).collect::<Vec<_>>();
+        assert_eq!(column(&chunks), vec![9, 4, -3]);
+
+        chunks.transition(ChunkPos::new(1, 4, 2), ChunkState::Unloading(Entity::from_raw(0))).unwrap();
+        chunks.transition(ChunkPos::new(1, 4, 2), ChunkState::Absent).unwrap();
+        assert_eq!(column(&chunks), vec![9, -3]);
+        assert_eq!(chunks.column(5, 5).count(), 0);
+    }
+
+    #[test]
+    fn radius_and_distance_use_world_units() {
+        let mut chunks = Chunks::new();
+        let mut i = 0;
+        for x in -4..4 {
+            for y in -4..4 {
+                for z in -4..4 {
+                    chunks.transition(ChunkPos::new(x, y, z), ChunkState::Queued(Entity::from_raw(i))).unwrap();
+                    i += 1;
+                }
+            }
+        }
+
+        let point = Vec3::new(3.0, -20.0, 7.5);
+        let radius = 40.0;
+        let mut within: Vec<ChunkPos> = chunks.within_radius(point, radius).map(|(coord, _)| coord).collect();
+        let mut closest: Vec<ChunkPos> = chunks.by_distance(point)
+            .map(|(coord, _)| coord)
+            .take_while(|coord| coord.center().distance(point) <= radius)
+            .collect();
+        within.sort();
+        closest.sort();
+        assert!(!within.is_empty() && within.len() < chunks.len());
+        assert_eq!(within, closest);
+    }
+}
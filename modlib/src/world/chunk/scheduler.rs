@@ -0,0 +1,172 @@
+//! Limits and priorities for chunk generation and meshing tasks.
+
+use std::{cmp::Ordering, collections::BinaryHeap};
+use bevy::{prelude::*, ecs::system::SystemParam, math::Vec3A, render::primitives::{Frustum, Sphere}};
+use crate::world::position::ChunkPos;
+use super::{loader::ChunkLoader, CHUNK_SIZE_F32};
+
+/// Limits how many chunk tasks can run at once, and how many can be started each frame.
+///
+/// Starting too many tasks at once floods the `AsyncComputeTaskPool` and causes hitches,
+/// so chunks wait in a queue and are started in order of priority, see `ChunkTaskPriorities`.
+#[derive(Resource, Clone)]
+pub struct ChunkTaskLimits {
+    /// The maximum amount of generation tasks running at once.
+    pub max_generation_tasks: usize,
+    /// The maximum amount of generation tasks started in a single frame.
+    pub generation_tasks_per_frame: usize,
+    /// The maximum amount of meshing tasks running at once.
+    pub max_meshing_tasks: usize,
+    /// The maximum amount of meshing tasks started in a single frame.
+    pub meshing_tasks_per_frame: usize,
+}
+
+impl ChunkTaskLimits {
+    /// How many generation tasks can be started this frame, given how many are already running.
+    pub fn available_generation_tasks(&self, in_flight: usize) -> usize {
+        self.generation_tasks_per_frame.min(self.max_generation_tasks.saturating_sub(in_flight))
+    }
+
+    /// How many meshing tasks can be started this frame, given how many are already running.
+    pub fn available_meshing_tasks(&self, in_flight: usize) -> usize {
+        self.meshing_tasks_per_frame.min(self.max_meshing_tasks.saturating_sub(in_flight))
+    }
+}
+
+impl Default for ChunkTaskLimits {
+    fn default() -> Self {
+        Self {
+            max_generation_tasks: 64,
+            generation_tasks_per_frame: 16,
+            max_meshing_tasks: 32,
+            meshing_tasks_per_frame: 16,
+        }
+    }
+}
+
+/// How urgently a chunk task should be started. Smaller values come first.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ChunkTaskPriority {
+    /// Whether the chunk is outside the view of every camera.
+    pub outside_view: bool,
+    /// Distance from the chunk's center to the nearest loader or camera, in blocks.
+    pub distance: f32,
+}
+
+impl Eq for ChunkTaskPriority {}
+
+impl PartialOrd for ChunkTaskPriority {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for ChunkTaskPriority {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.outside_view.cmp(&other.outside_view)
+            .then(self.distance.total_cmp(&other.distance))
+    }
+}
+
+/// Works out the priority of chunk tasks from the positions of loaders and cameras.
+/// Chunks that any camera can see come first, and are then ordered by distance to the nearest loader or camera.
+#[derive(SystemParam)]
+pub struct ChunkTaskPriorities<'w, 's> {
+    loaders: Query<'w, 's, &'static GlobalTransform, With<ChunkLoader>>,
+    cameras: Query<'w, 's, (&'static GlobalTransform, &'static Frustum), With<Camera>>,
+}
+
+impl ChunkTaskPriorities<'_, '_> {
+    pub fn get(&self, chunk_position: ChunkPos) -> ChunkTaskPriority {
+        let center = chunk_position.center();
+        let bounds = Sphere {
+            center: Vec3A::from(center),
+            radius: CHUNK_SIZE_F32 * 0.5 * 3f32.sqrt(),
+        };
+
+        let mut distance = f32::INFINITY;
+        for transform in self.loaders.iter() {
+            distance = distance.min(transform.translation().distance(center));
+        }
+
+        let mut outside_view = true;
+        for (transform, frustum) in self.cameras.iter() {
+            distance = distance.min(transform.translation().distance(center));
+            if frustum.intersects_sphere(&bounds, true) {
+                outside_view = false;
+            }
+        }
+
+        ChunkTaskPriority { outside_view, distance }
+    }
+}
+
+/// Queued chunk tasks, taken most important first.
+///
+/// Collecting the queue is linear in the amount of chunks, and only the chunks that are taken from it are put in order,
+/// so a long queue doesn't have to be sorted every frame when only a few tasks can be started.
+pub struct ChunkTaskQueue<T> {
+    heap: BinaryHeap<QueuedTask<T>>,
+}
+
+impl<T> FromIterator<(T, ChunkTaskPriority)> for ChunkTaskQueue<T> {
+    fn from_iter<I: IntoIterator<Item = (T, ChunkTaskPriority)>>(iter: I) -> Self {
+        Self { heap: iter.into_iter().map(|(task, priority)| QueuedTask { task, priority }).collect() }
+    }
+}
+
+impl<T> Iterator for ChunkTaskQueue<T> {
+    type Item = T;
+
+    fn next(&mut self) -> Option<T> {
+        self.heap.pop().map(|queued| queued.task)
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (self.heap.len(), Some(self.heap.len()))
+    }
+}
+
+/// A task in a `ChunkTaskQueue`, ordered only by its priority.
+struct QueuedTask<T> {
+    task: T,
+    priority: ChunkTaskPriority,
+}
+
+impl<T> PartialEq for QueuedTask<T> {
+    fn eq(&self, other: &Self) -> bool {
+        self.priority == other.priority
+    }
+}
+
+impl<T> Eq for QueuedTask<T> {}
+
+impl<T> PartialOrd for QueuedTask<T> {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl<T> Ord for QueuedTask<T> {
+    fn cmp(&self, other: &Self) -> Ordering {
+        // `BinaryHeap` gives the largest item first, but smaller priorities come first
+        other.priority.cmp(&self.priority)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn queue_gives_most_important_first() {
+        let priority = |outside_view, distance| ChunkTaskPriority { outside_view, distance };
+        let queue: ChunkTaskQueue<&str> = [
+            ("far", priority(false, 40.0)),
+            ("hidden", priority(true, 1.0)),
+            ("near", priority(false, 2.0)),
+        ].into_iter().collect();
+
+        assert_eq!(queue.collect::<Vec<_>>(), vec!["near", "far", "hidden"]);
+    }
+}
@@ -0,0 +1,389 @@
+//! Binary encoding for chunks.
+//!
+//! Chunks are encoded into a small, versioned binary format for saving, networking, and tooling.
+//! All values are little-endian. Version 2 of the format is laid out as follows:
+//!
+//! | Size       | Field                                                          |
+//! |------------|----------------------------------------------------------------|
+//! | 4          | Magic bytes, `RCCK`                                            |
+//! | 2          | Format version, a `u16`                                        |
+//! | 12         | Chunk position, three `i32`s in the order x, y, z              |
+//! | 1          | Bits per block index, from 0 to 16                             |
+//! | 2          | Palette length, a `u16`                                        |
+//! | 2 per item | Palette entries, each a `u16` `BlockStateId`                   |
+//! | 8 per item | Block indices, bit-packed into `u64` words                     |
+//! | 2          | Block entity count, a `u16`                                    |
+//! | varies     | Block entities, see below                                      |
+//!
+//! The amount of index words follows from the bits per block. Indices never straddle two words,
+//! so each word holds `64 / bits` indices, starting from the least significant bits.
+//! Blocks are ordered by x, then y, then z, so the index of a block is `(x * 16 + y) * 16 + z`.
+//! A bit width of zero means the chunk is uniform: there are no index words, and the palette has exactly one entry.
+//!
+//! Block entities can't be encoded generically, so their blocks are stored as `BlockStateId::EMPTY` in the palette.
+//! Instead, each block entity is written after the block data with a payload supplied by the caller:
+//!
+//! | Size   | Field                                  |
+//! |--------|----------------------------------------|
+//! | 3      | Block position in the chunk, x, y, z   |
+//! | 4      | Payload length, a `u32`                |
+//! | varies | Payload bytes                          |
+//!
+//! `BlockStateId`s are stored as-is, so they're only meaningful as long as blocks and their properties are registered in the same order.
+//!
+//! Version 1 had the same layout, but stored `BlockId`s in the palette. `decode_chunk` only reads the newest version,
+//! so older data has to go through `migrate_chunk` first, which turns every block into its default state.
+
+use std::fmt::Display;
+use bevy::prelude::Entity;
+use super::{Chunk, ChunkBlockInternal, CHUNK_SIZE, storage::{PalettedStorage, word_count}};
+use crate::world::{block::{BlockId, registry::BlockRegistryInternal, state::BlockStateId}, position::ChunkPos};
+
+/// The magic bytes at the start of every encoded chunk.
+pub const CHUNK_FORMAT_MAGIC: [u8; 4] = *b"RCCK";
+/// The newest version of the chunk format. Encoding always uses this version, and it's the only version that can be decoded.
+pub const CHUNK_FORMAT_VERSION: u16 = 2;
+
+/// A block entity read from an encoded chunk.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EncodedBlockEntity {
+    /// Position of the block in the chunk.
+    pub position: [u8; 3],
+    /// The payload that was given when the chunk was encoded.
+    pub payload: Vec<u8>,
+}
+
+/// A chunk read from bytes.
+pub struct DecodedChunk {
+    /// The decoded chunk. Block entities are not spawned, so their positions contain `BlockStateId::EMPTY`.
+    pub chunk: Chunk,
+    /// Block entity payloads, to be spawned and placed into `chunk` by the caller.
+    pub block_entities: Vec<EncodedBlockEntity>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ChunkDecodeError {
+    /// The data doesn't start with `CHUNK_FORMAT_MAGIC`.
+    InvalidMagic,
+    /// The data was encoded with a format version this build doesn't understand.
+    UnsupportedVersion(u16),
+    /// Version 1 data refers to a block type that isn't registered.
+    UnknownBlock(BlockId),
+    /// The data ended before the chunk was fully read.
+    UnexpectedEnd,
+    /// The block data is malformed, such as indices pointing outside the palette.
+    InvalidBlockData,
+    /// A block entity position lies outside the chunk.
+    InvalidBlockEntityPosition([u8; 3]),
+    /// There were leftover bytes after the chunk.
+    TrailingBytes(usize),
+}
+
+impl Display for ChunkDecodeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::InvalidMagic => write!(f, "data is not an encoded chunk"),
+            Self::UnsupportedVersion(version) => write!(f, "unsupported chunk format version {version}, newest supported is {CHUNK_FORMAT_VERSION}"),
+            Self::UnknownBlock(id) => write!(f, "unknown block {id:?} in the palette"),
+            Self::UnexpectedEnd => write!(f, "unexpected end of chunk data"),
+            Self::InvalidBlockData => write!(f, "invalid block data"),
+            Self::InvalidBlockEntityPosition(pos) => write!(f, "block entity position {pos:?} is outside the chunk"),
+            Self::TrailingBytes(amount) => write!(f, "{amount} trailing bytes after chunk data"),
+        }
+    }
+}
+
+impl std::error::Error for ChunkDecodeError {}
+
+/// Encodes a chunk without any block entity payloads. Block entities are stored as `BlockStateId::EMPTY`.
+pub fn encode_chunk(chunk: &Chunk) -> Vec<u8> {
+    encode_chunk_with_entities(chunk, |_| None)
+}
+
+/// Encodes a chunk, calling `entity_payload` for every block entity in it.
+/// If `entity_payload` returns `None`, the block entity is left out, and is stored as `BlockStateId::EMPTY`.
+pub fn encode_chunk_with_entities(chunk: &Chunk, mut entity_payload: impl FnMut(Entity) -> Option<Vec<u8>>) -> Vec<u8> {
+    let mut storage = chunk.get_internal_storage().clone();
+    storage.shrink_to_fit();
+
+    let mut bytes = Vec::with_capacity(32 + storage.palette().len() * 2 + storage.words().len() * 8);
+    bytes.extend(CHUNK_FORMAT_MAGIC);
+    bytes.extend(CHUNK_FORMAT_VERSION.to_le_bytes());
+    let position = chunk.get_position();
+    for axis in [position.x, position.y, position.z] {
+        bytes.extend(axis.to_le_bytes());
+    }
+
+    // Block data
+    bytes.push(storage.bits_per_block());
+    bytes.extend((storage.palette().len() as u16).to_le_bytes());
+    for value in storage.palette() {
+        let state = match value {
+            ChunkBlockInternal::Generic(state) => *state,
+            ChunkBlockInternal::Entity(_) => BlockStateId::EMPTY,
+        };
+        bytes.extend(state.0.to_le_bytes());
+    }
+    for word in storage.words() {
+        bytes.extend(word.to_le_bytes());
+    }
+
+    // Block entities
+    let mut entities = vec![];
+    if storage.palette().iter().any(|value| matches!(value, ChunkBlockInternal::Entity(_))) {
+        for x in 0..CHUNK_SIZE {
+            for y in 0..CHUNK_SIZE {
+                for z in 0..CHUNK_SIZE {
+                    if let ChunkBlockInternal::Entity(idx) = storage.get(x, y, z) {
+                        if let Some(payload) = entity_payload(chunk.get_entity_from_ent_idx(&idx)) {
+                            entities.push(([x as u8, y as u8, z as u8], payload));
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    bytes.extend((entities.len() as u16).to_le_bytes());
+    for (position, payload) in entities {
+        bytes.extend(position);
+        bytes.extend((payload.len() as u32).to_le_bytes());
+        bytes.extend(payload);
+    }
+
+    bytes
+}
+
+/// Decodes a chunk encoded by `encode_chunk` or `encode_chunk_with_entities`.
+pub fn decode_chunk(bytes: &[u8]) -> Result<DecodedChunk, ChunkDecodeError> {
+    let mut reader = Reader { bytes };
+
+    if reader.take(4)? != CHUNK_FORMAT_MAGIC {
+        return Err(ChunkDecodeError::InvalidMagic);
+    }
+    let version = reader.u16()?;
+    if version != CHUNK_FORMAT_VERSION {
+        return Err(ChunkDecodeError::UnsupportedVersion(version));
+    }
+    let position = ChunkPos::new(reader.i32()?, reader.i32()?, reader.i32()?);
+
+    // Block data
+    let bits = reader.u8()?;
+    if bits > 16 {
+        return Err(ChunkDecodeError::InvalidBlockData);
+    }
+    let palette_len = reader.u16()? as usize;
+    let mut palette = Vec::with_capacity(palette_len);
+    for _ in 0..palette_len {
+        palette.push(ChunkBlockInternal::Generic(BlockStateId(reader.u16()?)));
+    }
+    let word_count = word_count(bits);
+    let mut words = Vec::with_capacity(word_count);
+    for _ in 0..word_count {
+        words.push(reader.u64()?);
+    }
+    let storage = PalettedStorage::from_raw_parts(palette, bits, words)
+        .ok_or(ChunkDecodeError::InvalidBlockData)?;
+
+    // Block entities
+    let entity_count = reader.u16()? as usize;
+    let mut block_entities = Vec::with_capacity(entity_count);
+    for _ in 0..entity_count {
+        let position: [u8; 3] = reader.take(3)?.try_into().unwrap();
+        if position.iter().any(|axis| *axis as usize >= CHUNK_SIZE) {
+            return Err(ChunkDecodeError::InvalidBlockEntityPosition(position));
+        }
+        let length = reader.u32()? as usize;
+        let payload = reader.take(length)?.to_vec();
+        block_entities.push(EncodedBlockEntity { position, payload });
+    }
+
+    if !reader.bytes.is_empty() {
+        return Err(ChunkDecodeError::TrailingBytes(reader.bytes.len()));
+    }
+
+    Ok(DecodedChunk {
+        chunk: Chunk::from_storage(position, storage),
+        block_entities,
+    })
+}
+
+/// Upgrades an encoded chunk to `CHUNK_FORMAT_VERSION`, so it can be read by `decode_chunk`.
+/// Every block of a version 1 chunk becomes the default state of its block type. Data in the newest version is returned as-is.
+pub fn migrate_chunk(bytes: &[u8], registry: &BlockRegistryInternal) -> Result<Vec<u8>, ChunkDecodeError> {
+    let mut reader = Reader { bytes };
+
+    if reader.take(4)? != CHUNK_FORMAT_MAGIC {
+        return Err(ChunkDecodeError::InvalidMagic);
+    }
+    match reader.u16()? {
+        CHUNK_FORMAT_VERSION => return Ok(bytes.to_vec()),
+        1 => {},
+        version => return Err(ChunkDecodeError::UnsupportedVersion(version)),
+    }
+
+    // Only the palette changed, everything around it is copied over
+    let mut migrated = Vec::with_capacity(bytes.len());
+    migrated.extend(CHUNK_FORMAT_MAGIC);
+    migrated.extend(CHUNK_FORMAT_VERSION.to_le_bytes());
+    migrated.extend(reader.take(12 + 1)?);
+    let palette_len = reader.u16()?;
+    migrated.extend(palette_len.to_le_bytes());
+    for _ in 0..palette_len {
+        let id = BlockId(reader.u16()?);
+        let state = registry.get_default_state(id).ok_or(ChunkDecodeError::UnknownBlock(id))?;
+        migrated.extend(state.0.to_le_bytes());
+    }
+    migrated.extend(reader.bytes);
+
+    Ok(migrated)
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, amount: usize) -> Result<&'a [u8], ChunkDecodeError> {
+        if self.bytes.len() < amount {
+            return Err(ChunkDecodeError::UnexpectedEnd);
+        }
+        let (taken, rest) = self.bytes.split_at(amount);
+        self.bytes = rest;
+        Ok(taken)
+    }
+
+    fn u8(&mut self) -> Result<u8, ChunkDecodeError> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16, ChunkDecodeError> {
+        Ok(u16::from_le_bytes(self.take(2)?.try_into().unwrap()))
+    }
+
+    fn u32(&mut self) -> Result<u32, ChunkDecodeError> {
+        Ok(u32::from_le_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    fn i32(&mut self) -> Result<i32, ChunkDecodeError> {
+        Ok(i32::from_le_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    fn u64(&mut self) -> Result<u64, ChunkDecodeError> {
+        Ok(u64::from_le_bytes(self.take(8)?.try_into().unwrap()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bevy::prelude::Entity;
+    use crate::world::{block::{Block, data::BlockData, state::{BlockProperty, BlockStateId}}, chunk::{Chunk, CHUNK_SIZE, meshing::MeshingVisibility}};
+    use super::*;
+
+    fn assert_same_blocks(a: &Chunk, b: &Chunk) {
+        for x in 0..CHUNK_SIZE {
+            for y in 0..CHUNK_SIZE {
+                for z in 0..CHUNK_SIZE {
+                    match (a.get_block(x, y, z), b.get_block(x, y, z)) {
+                        (Block::Generic(a), Block::Generic(b)) => assert_eq!(a, b, "block at {x} {y} {z} differs"),
+                        _ => panic!("block at {x} {y} {z} should be generic"),
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn empty_chunk_round_trip() {
+        let chunk = Chunk::new(ChunkPos::new(-3, 7, 12));
+        let decoded = decode_chunk(&encode_chunk(&chunk)).unwrap();
+
+        assert_eq!(decoded.chunk.get_position(), ChunkPos::new(-3, 7, 12));
+        assert!(decoded.chunk.get_uniform_block().is_some());
+        assert!(decoded.block_entities.is_empty());
+        assert_same_blocks(&chunk, &decoded.chunk);
+    }
+
+    #[test]
+    fn mixed_chunk_round_trip() {
+        let mut chunk = Chunk::new(ChunkPos::new(1, -2, 3));
+        for x in 0..CHUNK_SIZE {
+            for y in 0..CHUNK_SIZE {
+                for z in 0..CHUNK_SIZE {
+                    chunk.set_block(x, y, z, Block::Generic(BlockStateId(((x * 7 + y * 3 + z) % 37) as u16)));
+                }
+            }
+        }
+
+        let decoded = decode_chunk(&encode_chunk(&chunk)).unwrap();
+        assert_eq!(decoded.chunk.get_position(), ChunkPos::new(1, -2, 3));
+        assert_same_blocks(&chunk, &decoded.chunk);
+    }
+
+    #[test]
+    fn block_entity_payloads_round_trip() {
+        let mut chunk = Chunk::new(ChunkPos::new(0, 0, 0));
+        chunk.set_block(1, 1, 1, Block::Generic(BlockStateId(4)));
+        chunk.set_block(2, 3, 4, Block::Entity(Entity::from_raw(9)));
+
+        let bytes = encode_chunk_with_entities(&chunk, |entity| Some(entity.index().to_le_bytes().to_vec()));
+        let decoded = decode_chunk(&bytes).unwrap();
+
+        assert_eq!(decoded.block_entities, vec![EncodedBlockEntity {
+            position: [2, 3, 4],
+            payload: 9u32.to_le_bytes().to_vec(),
+        }]);
+        assert!(matches!(decoded.chunk.get_block(2, 3, 4), Block::Generic(BlockStateId::EMPTY)));
+        assert!(matches!(decoded.chunk.get_block(1, 1, 1), Block::Generic(BlockStateId(4))));
+    }
+
+    #[test]
+    fn rejects_invalid_data() {
+        let bytes = encode_chunk(&Chunk::new(ChunkPos::new(0, 0, 0)));
+
+        assert_eq!(decode_chunk(b"nope").err(), Some(ChunkDecodeError::InvalidMagic));
+        assert_eq!(decode_chunk(&bytes[..bytes.len() - 1]).err(), Some(ChunkDecodeError::UnexpectedEnd));
+
+        let mut newer = bytes.clone();
+        newer[4..6].copy_from_slice(&(CHUNK_FORMAT_VERSION + 1).to_le_bytes());
+        assert_eq!(decode_chunk(&newer).err(), Some(ChunkDecodeError::UnsupportedVersion(CHUNK_FORMAT_VERSION + 1)));
+
+        // Version 1 palettes hold block types instead of states
+        let mut older = bytes.clone();
+        older[4..6].copy_from_slice(&1u16.to_le_bytes());
+        assert_eq!(decode_chunk(&older).err(), Some(ChunkDecodeError::UnsupportedVersion(1)));
+
+        let mut trailing = bytes.clone();
+        trailing.push(0);
+        assert_eq!(decode_chunk(&trailing).err(), Some(ChunkDecodeError::TrailingBytes(1)));
+    }
+
+    #[test]
+    fn version_1_blocks_become_default_states() {
+        let mut registry = BlockRegistryInternal::new();
+        let mut door = BlockData::new("door", MeshingVisibility::Opaque);
+        door.add_property(BlockProperty::bool("open"));
+        registry.add_block_type(door);
+        registry.add_block_type(BlockData::new("stone", MeshingVisibility::Opaque));
+        let stone = registry.get_default_state_by_string_id("stone").unwrap();
+        assert_ne!(stone, BlockStateId(2));
+
+        // Written like version 1 did, with the stone's `BlockId` in the palette
+        let mut chunk = Chunk::new(ChunkPos::new(1, 2, 3));
+        chunk.set_block(4, 5, 6, Block::Generic(BlockStateId(2)));
+        let mut older = encode_chunk(&chunk);
+        older[4..6].copy_from_slice(&1u16.to_le_bytes());
+
+        let migrated = migrate_chunk(&older, &registry).unwrap();
+        let decoded = decode_chunk(&migrated).unwrap();
+        assert_eq!(decoded.chunk.get_position(), ChunkPos::new(1, 2, 3));
+        assert!(matches!(decoded.chunk.get_block(4, 5, 6), Block::Generic(state) if state == stone));
+        assert!(matches!(decoded.chunk.get_block(0, 0, 0), Block::Generic(BlockStateId::EMPTY)));
+        assert_eq!(migrate_chunk(&migrated, &registry).unwrap(), migrated);
+
+        chunk.set_block(4, 5, 6, Block::Generic(BlockStateId(3)));
+        let mut unknown = encode_chunk(&chunk);
+        unknown[4..6].copy_from_slice(&1u16.to_le_bytes());
+        assert_eq!(migrate_chunk(&unknown, &registry).err(), Some(ChunkDecodeError::UnknownBlock(BlockId(3))));
+    }
+}
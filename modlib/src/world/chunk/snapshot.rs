@@ -0,0 +1,91 @@
+//! Immutable snapshots of chunks for use in asynchronous tasks.
+//!
+//! Taking a snapshot only clones a few `Arc`s, so it's cheap enough to do every frame. The chunk copies its data the
+//! first time it's changed while a snapshot is still alive, so snapshots never see changes made after they were taken.
+
+use std::sync::Arc;
+use bevy::{prelude::Entity, utils::HashMap};
+use crate::world::{block::Block, position::ChunkPos};
+use super::{Chunk, ChunkBlockInternal, light::{LightStorage, LightKind}, storage::PalettedStorage};
+
+/// The blocks and light of a chunk at the time the snapshot was taken. Can be sent to other threads.
+#[derive(Clone)]
+pub struct ChunkSnapshot {
+    position: ChunkPos,
+    blocks: Arc<PalettedStorage>,
+    entities: Arc<HashMap<u16, Entity>>,
+    light: Arc<LightStorage>,
+}
+
+impl ChunkSnapshot {
+    pub fn get_position(&self) -> ChunkPos {
+        self.position
+    }
+
+    /// Gets a `BlockStateId` or `Entity` from the snapshot. See `Chunk::get_block`.
+    pub fn get_block(&self, x: usize, y: usize, z: usize) -> Block {
+        self.to_block(self.blocks.get(x, y, z))
+    }
+
+    /// Returns the block that fills the entire chunk, if the chunk is uniform. See `Chunk::get_uniform_block`.
+    pub fn get_uniform_block(&self) -> Option<Block> {
+        Some(self.to_block(self.blocks.uniform_value()?))
+    }
+
+    /// Returns every block entity stored in the chunk.
+    pub fn get_block_entities(&self) -> impl Iterator<Item = Entity> + '_ {
+        self.entities.values().copied()
+    }
+
+    /// The skylight level of a block, from 0 to `MAX_LIGHT`.
+    pub fn get_sky_light(&self, x: usize, y: usize, z: usize) -> u8 {
+        self.light.get(LightKind::Sky, x, y, z)
+    }
+
+    /// The block light level of a block, from 0 to `MAX_LIGHT`.
+    pub fn get_block_light(&self, x: usize, y: usize, z: usize) -> u8 {
+        self.light.get(LightKind::Block, x, y, z)
+    }
+
+    pub fn get_light(&self) -> &LightStorage {
+        &self.light
+    }
+
+    fn to_block(&self, block: ChunkBlockInternal) -> Block {
+        match block {
+            ChunkBlockInternal::Generic(state) => Block::Generic(state),
+            ChunkBlockInternal::Entity(idx) => Block::Entity(*self.entities.get(&idx).expect("Entity index should have been in the map!")),
+        }
+    }
+}
+
+impl Chunk {
+    /// Takes an immutable snapshot of the chunk's blocks and light, without copying them.
+    pub fn snapshot(&self) -> ChunkSnapshot {
+        ChunkSnapshot {
+            position: self.position,
+            blocks: self.blocks.clone(),
+            entities: self.entities.clone(),
+            light: self.light.clone(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::world::block::{Block, state::BlockStateId};
+    use super::*;
+
+    #[test]
+    fn snapshots_do_not_see_later_changes() {
+        let mut chunk = Chunk::new(ChunkPos::new(0, 0, 0));
+        chunk.set_block(1, 2, 3, Block::Generic(BlockStateId(5)));
+        let snapshot = chunk.snapshot();
+        assert!(Arc::ptr_eq(&snapshot.blocks, &chunk.blocks));
+
+        chunk.set_block(1, 2, 3, Block::Generic(BlockStateId(6)));
+        assert_eq!(snapshot.get_block(1, 2, 3), Block::Generic(BlockStateId(5)));
+        assert_eq!(chunk.get_block(1, 2, 3), Block::Generic(BlockStateId(6)));
+        assert!(!Arc::ptr_eq(&snapshot.blocks, &chunk.blocks));
+    }
+}
@@ -0,0 +1,219 @@
+//! Spatial index over chunk positions, used by the spatial queries on `Chunks`.
+//!
+//! Chunks are grouped into cubic cells of `CELL_SIZE` chunks per axis, so queries only look at the cells they overlap
+//! instead of every chunk in the registry. Cells are stored in a hash map, so the world can be any size.
+
+use std::{cmp::{Ordering, Reverse}, collections::BinaryHeap};
+use bevy::{prelude::{IVec3, Vec3}, utils::HashMap};
+use crate::world::position::ChunkPos;
+use super::CHUNK_SIZE_F32;
+
+/// How many chunks a cell spans on each axis.
+const CELL_SIZE: i32 = 8;
+
+#[derive(Default)]
+pub(crate) struct SpatialIndex {
+    cells: HashMap<IVec3, Vec<ChunkPos>>,
+}
+
+fn cell_of(pos: ChunkPos) -> IVec3 {
+    IVec3::new(pos.x.div_euclid(CELL_SIZE), pos.y.div_euclid(CELL_SIZE), pos.z.div_euclid(CELL_SIZE))
+}
+
+impl SpatialIndex {
+    pub(crate) fn insert(&mut self, pos: ChunkPos) {
+        self.cells.entry(cell_of(pos)).or_default().push(pos);
+    }
+
+    pub(crate) fn remove(&mut self, pos: ChunkPos) {
+        let cell = cell_of(pos);
+        if let Some(chunks) = self.cells.get_mut(&cell) {
+            chunks.retain(|other| *other != pos);
+            if chunks.is_empty() {
+                self.cells.remove(&cell);
+            }
+        }
+    }
+
+    /// Every chunk from `min` to `max`, inclusive.
+    pub(crate) fn in_aabb(&self, min: ChunkPos, max: ChunkPos) -> impl Iterator<Item = ChunkPos> + '_ {
+        let (min_cell, max_cell) = (cell_of(min), cell_of(max));
+        let cell_count = (max_cell - min_cell + IVec3::ONE).max(IVec3::ZERO).as_dvec3().to_array().iter().product::<f64>();
+
+        // Huge boxes would visit lots of empty cells, so they go through the occupied cells instead
+        let cells: Vec<&Vec<ChunkPos>> = if cell_count > self.cells.len() as f64 {
+            self.cells.iter()
+                .filter(|(cell, _)| cell.cmpge(min_cell).all() && cell.cmple(max_cell).all())
+                .map(|(_, chunks)| chunks)
+                .collect()
+        } else {
+            (min_cell.x..=max_cell.x)
+                .flat_map(|x| (min_cell.y..=max_cell.y).flat_map(move |y| (min_cell.z..=max_cell.z).map(move |z| IVec3::new(x, y, z))))
+                .filter_map(|cell| self.cells.get(&cell))
+                .collect()
+        };
+
+        cells.into_iter().flatten().copied().filter(move |pos| {
+            (min.x..=max.x).contains(&pos.x) && (min.y..=max.y).contains(&pos.y) && (min.z..=max.z).contains(&pos.z)
+        })
+    }
+
+    /// Every chunk, ordered by the distance from `point` to the chunk's center.
+    pub(crate) fn by_distance(&self, point: Vec3) -> ChunksByDistance<'_> {
+        let queue = self.cells.keys()
+            .map(|cell| Reverse(Queued(cell_distance(*cell, point), QueuedItem::Cell(*cell))))
+            .collect();
+        ChunksByDistance { index: self, point, queue }
+    }
+}
+
+/// The distance from a point to the closest point of a cell, which no chunk in the cell can be closer than.
+fn cell_distance(cell: IVec3, point: Vec3) -> f32 {
+    let min = (cell * CELL_SIZE).as_vec3() * CHUNK_SIZE_F32;
+    let max = min + Vec3::splat(CELL_SIZE as f32 * CHUNK_SIZE_F32);
+    point.clamp(min, max).distance(point)
+}
+
+/// Iterator returned by `Chunks::by_distance`.
+///
+/// Cells are opened lazily, closest first, so taking only the nearest few chunks doesn't sort the whole registry.
+pub(crate) struct ChunksByDistance<'a> {
+    index: &'a SpatialIndex,
+    point: Vec3,
+    queue: BinaryHeap<Reverse<Queued>>,
+}
+
+impl Iterator for ChunksByDistance<'_> {
+    type Item = ChunkPos;
+
+    fn next(&mut self) -> Option<ChunkPos> {
+        loop {
+            let Reverse(Queued(_, item)) = self.queue.pop()?;
+            match item {
+                QueuedItem::Chunk(pos) => return Some(pos),
+                QueuedItem::Cell(cell) => {
+                    for pos in self.index.cells.get(&cell).into_iter().flatten() {
+                        self.queue.push(Reverse(Queued(pos.center().distance(self.point), QueuedItem::Chunk(*pos))));
+                    }
+                },
+            }
+        }
+    }
+}
+
+/// A cell or chunk waiting in `ChunksByDistance`, ordered by distance. Cells go before chunks at the same distance.
+struct Queued(f32, QueuedItem);
+
+enum QueuedItem {
+    Cell(IVec3),
+    Chunk(ChunkPos),
+}
+
+impl QueuedItem {
+    /// A consistent order for items at the same distance, since `IVec3` doesn't implement `Ord`.
+    fn key(&self) -> (u8, [i32; 3]) {
+        match self {
+            QueuedItem::Cell(cell) => (0, cell.to_array()),
+            QueuedItem::Chunk(pos) => (1, IVec3::from(*pos).to_array()),
+        }
+    }
+}
+
+impl PartialEq for Queued {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Queued {}
+
+impl PartialOrd for Queued {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Queued {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.0.total_cmp(&other.0).then_with(|| self.1.key().cmp(&other.1.key()))
+    }
+}
+
+/// Every chunk a ray passes through, in order, using a voxel traversal over the chunk grid.
+/// `direction` doesn't need to be normalized, and the ray stops after `max_distance` world units.
+pub(crate) fn chunks_along_ray(origin: Vec3, direction: Vec3, max_distance: f32) -> impl Iterator<Item = ChunkPos> {
+    let direction = direction.normalize_or_zero();
+    let mut current = ChunkPos::containing(origin);
+    let step = direction.signum().as_ivec3();
+
+    // How far along the ray the next chunk border on each axis is, and how far apart borders are
+    let next_border = |axis: usize| {
+        let position = IVec3::from(current)[axis] as f32 * CHUNK_SIZE_F32;
+        let border = if direction[axis] > 0.0 { position + CHUNK_SIZE_F32 } else { position };
+        if direction[axis] == 0.0 { f32::INFINITY } else { (border - origin[axis]) / direction[axis] }
+    };
+    let mut t_max = Vec3::new(next_border(0), next_border(1), next_border(2));
+    let t_delta = Vec3::splat(CHUNK_SIZE_F32) / direction.abs();
+    let mut done = direction == Vec3::ZERO;
+    let mut first = true;
+
+    std::iter::from_fn(move || {
+        if first {
+            first = false;
+            return Some(current);
+        }
+        if done { return None; }
+
+        // Step into the neighbouring chunk whose border is closest along the ray
+        let axis = if t_max.x <= t_max.y && t_max.x <= t_max.z { 0 } else if t_max.y <= t_max.z { 1 } else { 2 };
+        if t_max[axis] > max_distance {
+            done = true;
+            return None;
+        }
+        let mut offset = IVec3::ZERO;
+        offset[axis] = step[axis];
+        current = current + offset;
+        t_max[axis] += t_delta[axis];
+
+        Some(current)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn queries_match_brute_force() {
+        let mut index = SpatialIndex::default();
+        let mut all = vec![];
+        for i in 0..2000 {
+            let pos = ChunkPos::new((i * 37) % 61 - 30, (i * 11) % 13 - 6, (i * 53) % 47 - 23);
+            if !all.contains(&pos) {
+                index.insert(pos);
+                all.push(pos);
+            }
+        }
+        let removed = all.pop().unwrap();
+        index.remove(removed);
+
+        let (min, max) = (ChunkPos::new(-9, -2, -17), ChunkPos::new(12, 3, 1));
+        let mut found: Vec<ChunkPos> = index.in_aabb(min, max).collect();
+        let mut expected: Vec<ChunkPos> = all.iter().copied()
+            .filter(|pos| pos.x >= min.x && pos.x <= max.x && pos.y >= min.y && pos.y <= max.y && pos.z >= min.z && pos.z <= max.z)
+            .collect();
+        found.sort();
+        expected.sort();
+        assert_eq!(found, expected);
+
+        let point = Vec3::new(-70.0, 5.0, 130.0);
+        let distances: Vec<f32> = index.by_distance(point).map(|pos| pos.center().distance(point)).collect();
+        assert_eq!(distances.len(), all.len());
+        assert!(distances.windows(2).all(|pair| pair[0] <= pair[1]));
+
+        let ray: Vec<ChunkPos> = chunks_along_ray(Vec3::new(1.0, 1.0, 1.0), Vec3::new(1.0, 0.0, -1.0), 64.0).collect();
+        assert_eq!(ray.first(), Some(&ChunkPos::new(0, 0, 0)));
+        assert!(ray.contains(&ChunkPos::new(2, 0, -2)));
+        assert!(ray.windows(2).all(|pair| (IVec3::from(pair[1]) - IVec3::from(pair[0])).abs().to_array().iter().sum::<i32>() == 1));
+    }
+}
@@ -0,0 +1,263 @@
+//! Palette-compressed block storage for chunks.
+
+use super::{ChunkBlockInternal, CHUNK_SIZE};
+
+/// The amount of blocks in a single chunk.
+pub const CHUNK_VOLUME: usize = CHUNK_SIZE * CHUNK_SIZE * CHUNK_SIZE;
+
+const MAX_BITS: u8 = 16;
+
+/// Stores the contents of a chunk as a palette of unique values and a bit-packed array of indices into that palette.
+///
+/// Most chunks only contain a handful of block types, so storing a full `ChunkBlockInternal` for every block wastes a lot of memory.
+/// Indices grow from 1 bit up to 16 bits wide as more unique values are added to the palette.
+/// An index never straddles two words, so the last few bits of each word may go unused.
+///
+/// A storage where every block is the same value is 'uniform', and has no index array at all.
+/// It turns into a full array the first time a different value is set.
+#[derive(Clone)]
+pub(crate) struct PalettedStorage {
+    palette: Vec<ChunkBlockInternal>,
+    bits: u8,
+    words: Vec<u64>,
+}
+
+impl PalettedStorage {
+    /// Creates a new storage with every block set to `fill`.
+    pub fn new(fill: ChunkBlockInternal) -> Self {
+        Self {
+            palette: vec![fill],
+            bits: 0,
+            words: vec![],
+        }
+    }
+
+    /// Rebuilds a storage from the parts returned by `palette`, `bits_per_block` and `words`.
+    /// Returns `None` if the parts don't describe a valid storage.
+    pub fn from_raw_parts(palette: Vec<ChunkBlockInternal>, bits: u8, words: Vec<u64>) -> Option<Self> {
+        if palette.is_empty() || bits > MAX_BITS || words.len() != word_count(bits) { return None; }
+        if bits == 0 && palette.len() != 1 { return None; }
+
+        let storage = Self { palette, bits, words };
+        for index in 0..CHUNK_VOLUME {
+            if storage.get_index(index) >= storage.palette.len() { return None; }
+        }
+
+        Some(storage)
+    }
+
+    /// The values in the palette. Some of them may not be used by any block.
+    pub fn palette(&self) -> &[ChunkBlockInternal] {
+        &self.palette
+    }
+
+    /// How many bits wide each index is. Uniform storages have zero-width indices.
+    pub fn bits_per_block(&self) -> u8 {
+        self.bits
+    }
+
+    /// The bit-packed indices into the palette.
+    pub fn words(&self) -> &[u64] {
+        &self.words
+    }
+
+    /// Returns the value of every block if they're all the same, without an index array.
+    pub fn uniform_value(&self) -> Option<ChunkBlockInternal> {
+        match self.bits {
+            0 => Some(self.palette[0]),
+            _ => None,
+        }
+    }
+
+    pub fn get(&self, x: usize, y: usize, z: usize) -> ChunkBlockInternal {
+        self.palette[self.get_index(flatten(x, y, z))]
+    }
+
+    pub fn set(&mut self, x: usize, y: usize, z: usize, to: ChunkBlockInternal) {
+        let palette_index = match self.palette.iter().position(|value| *value == to) {
+            Some(index) => index,
+            None => self.push_palette_entry(to),
+        };
+
+        self.set_index(flatten(x, y, z), palette_index);
+    }
+
+    /// Approximate heap memory used by this storage, in bytes.
+    pub fn heap_size(&self) -> usize {
+        self.palette.capacity() * std::mem::size_of::<ChunkBlockInternal>()
+            + self.words.capacity() * std::mem::size_of::<u64>()
+    }
+
+    /// Drops unused palette entries and repacks the indices as narrow as possible.
+    /// If only one value is left, the storage becomes uniform and frees its index array.
+    pub fn shrink_to_fit(&mut self) {
+        self.compact();
+
+        let bits = bits_for(self.palette.len());
+        if bits < self.bits {
+            self.resize(bits);
+        }
+        self.palette.shrink_to_fit();
+    }
+
+    fn push_palette_entry(&mut self, value: ChunkBlockInternal) -> usize {
+        if self.palette.len() >= 1 << self.bits {
+            // Try to make room by throwing away unused entries before widening the indices
+            self.compact();
+            if self.palette.len() >= 1 << self.bits {
+                self.resize(self.bits + 1);
+            }
+        }
+
+        self.palette.push(value);
+        self.palette.len() - 1
+    }
+
+    /// Removes palette entries that aren't used by any block.
+    fn compact(&mut self) {
+        let mut used = vec![false; self.palette.len()];
+        for index in 0..CHUNK_VOLUME {
+            used[self.get_index(index)] = true;
+        }
+        if used.iter().all(|used| *used) { return; }
+
+        let mut remap = vec![0; self.palette.len()];
+        let mut palette = Vec::with_capacity(self.palette.len());
+        for (old, value) in self.palette.iter().enumerate() {
+            if !used[old] { continue; }
+            remap[old] = palette.len();
+            palette.push(*value);
+        }
+
+        for index in 0..CHUNK_VOLUME {
+            let old = self.get_index(index);
+            self.set_index(index, remap[old]);
+        }
+        self.palette = palette;
+    }
+
+    /// Repacks all indices with a new bit width.
+    fn resize(&mut self, bits: u8) {
+        assert!(bits <= MAX_BITS, "Palette grew past {MAX_BITS} bits per block");
+
+        let mut resized = Self {
+            palette: vec![],
+            bits,
+            words: vec![0; word_count(bits)],
+        };
+        for index in 0..CHUNK_VOLUME {
+            resized.set_index(index, self.get_index(index));
+        }
+
+        self.bits = bits;
+        self.words = resized.words;
+    }
+
+    fn get_index(&self, index: usize) -> usize {
+        if self.bits == 0 { return 0; }
+        let per_word = 64 / self.bits as usize;
+        let shift = (index % per_word) * self.bits as usize;
+        ((self.words[index / per_word] >> shift) & mask(self.bits)) as usize
+    }
+
+    fn set_index(&mut self, index: usize, value: usize) {
+        if self.bits == 0 {
+            debug_assert_eq!(value, 0);
+            return;
+        }
+        let per_word = 64 / self.bits as usize;
+        let shift = (index % per_word) * self.bits as usize;
+        let word = &mut self.words[index / per_word];
+        *word = (*word & !(mask(self.bits) << shift)) | ((value as u64) << shift);
+    }
+}
+
+fn flatten(x: usize, y: usize, z: usize) -> usize {
+    (x * CHUNK_SIZE + y) * CHUNK_SIZE + z
+}
+
+fn mask(bits: u8) -> u64 {
+    (1 << bits) - 1
+}
+
+/// The smallest index width that can address `len` palette entries.
+fn bits_for(len: usize) -> u8 {
+    (usize::BITS - (len.max(1) - 1).leading_zeros()) as u8
+}
+
+pub(crate) fn word_count(bits: u8) -> usize {
+    if bits == 0 { return 0; }
+    let per_word = 64 / bits as usize;
+    CHUNK_VOLUME.div_ceil(per_word)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::world::block::state::BlockStateId;
+    use super::*;
+
+    fn block(id: u16) -> ChunkBlockInternal {
+        ChunkBlockInternal::Generic(BlockStateId(id))
+    }
+
+    #[test]
+    fn palette_grows_and_widens_indices() {
+        let mut storage = PalettedStorage::new(block(0));
+        assert_eq!(storage.uniform_value(), Some(block(0)));
+        assert_eq!(storage.bits_per_block(), 0);
+
+        storage.set(1, 2, 3, block(1));
+        assert_eq!(storage.bits_per_block(), 1);
+        assert_eq!(storage.uniform_value(), None);
+
+        // 17 different values need 5 bits
+        for id in 2..=16 {
+            storage.set(id as usize % CHUNK_SIZE, 0, id as usize / CHUNK_SIZE, block(id));
+        }
+        assert_eq!(storage.bits_per_block(), 5);
+        assert_eq!(storage.palette().len(), 17);
+        assert_eq!(storage.words().len(), word_count(5));
+        assert_eq!(storage.get(1, 2, 3), block(1));
+        for id in 2..=16 {
+            assert_eq!(storage.get(id as usize % CHUNK_SIZE, 0, id as usize / CHUNK_SIZE), block(id));
+        }
+        assert_eq!(storage.get(5, 5, 5), block(0));
+    }
+
+    #[test]
+    fn unused_entries_are_reused_and_shrunk() {
+        let mut storage = PalettedStorage::new(block(0));
+        storage.set(0, 0, 0, block(1));
+        storage.set(0, 0, 0, block(0));
+        storage.set(0, 0, 0, block(2));
+        // The entry for 1 is unused, so 2 takes its place instead of widening the indices
+        assert_eq!(storage.bits_per_block(), 1);
+        assert_eq!(storage.palette(), &[block(0), block(2)]);
+        assert_eq!(storage.get(0, 0, 0), block(2));
+
+        storage.set(0, 0, 0, block(0));
+        storage.shrink_to_fit();
+        assert_eq!(storage.uniform_value(), Some(block(0)));
+        assert!(storage.words().is_empty());
+    }
+
+    #[test]
+    fn raw_parts_round_trip() {
+        let mut storage = PalettedStorage::new(block(0));
+        for (i, id) in [3, 7, 9].into_iter().enumerate() {
+            storage.set(i, i, i, block(id));
+        }
+        let copy = PalettedStorage::from_raw_parts(storage.palette().to_vec(), storage.bits_per_block(), storage.words().to_vec()).unwrap();
+        for x in 0..CHUNK_SIZE {
+            for y in 0..CHUNK_SIZE {
+                for z in 0..CHUNK_SIZE {
+                    assert_eq!(copy.get(x, y, z), storage.get(x, y, z));
+                }
+            }
+        }
+
+        // Indices past the end of the palette are rejected
+        assert!(PalettedStorage::from_raw_parts(vec![block(0)], 1, vec![u64::MAX; word_count(1)]).is_none());
+        assert!(PalettedStorage::from_raw_parts(vec![block(0), block(1)], 0, vec![]).is_none());
+    }
+}
@@ -0,0 +1,426 @@
+//! Chunk tickets, which keep areas of the world loaded.
+//!
+//! A ticket covers a sphere of chunks and has a `TicketLevel`, which decides what runs in those chunks.
+//! When a chunk is covered by more than one ticket, the highest level wins, and the chunk's entity gets the
+//! `BlockTicking` and `EntityTicking` markers its level allows, so systems that tick blocks or simulate entities only
+//! have to query for them. Chunks that no ticket covers are unloaded, including chunks loaded by sending a
+//! `LoadChunkMessage` directly, so anything that needs chunks to stay loaded has to add a ticket for them.
+//!
+//! `ChunkLoader`s add tickets around themselves. Scripts can add their own for things like the spawn area or forced
+//! chunks, and tasks can add temporary tickets that are removed automatically after a while.
+//!
+//! Chunks covered by a ticket that are unloaded by something else are loaded again straight away, and chunks that
+//! fail are retried after `FAILED_RETRY_DELAY`, so tickets that don't change don't leave holes behind.
+
+use std::time::Duration;
+use bevy::{prelude::*, utils::{HashSet, HashMap}};
+use crate::world::position::ChunkPos;
+use super::{events::{LoadChunkMessage, UnloadChunkMessage, RetryChunkMessage, ChunkStateChangedEvent, ChunkFailedEvent}, failure::ChunkFailure, registry::{Chunks, ChunkState}};
+
+/// How many chunks further than its radius a ticket keeps chunks loaded.
+/// This stops chunks on the edge of a ticket from being loaded and unloaded over and over as the ticket moves back and forth.
+pub const UNLOAD_HYSTERESIS: f32 = 2.0;
+
+/// How long a chunk covered by a ticket waits after failing before it's retried.
+/// Failures are often deterministic, so retrying straight away would just fail again every frame.
+pub const FAILED_RETRY_DELAY: Duration = Duration::from_secs(5);
+
+/// What runs in the chunks covered by a ticket. Each level includes everything from the levels below it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum TicketLevel {
+    /// The chunk is loaded and meshed, but nothing in it changes by itself.
+    Loaded,
+    /// Blocks in the chunk get random and scheduled ticks.
+    BlockTicking,
+    /// Entities in the chunk are simulated.
+    EntityTicking,
+}
+
+/// Marks a chunk covered by a ticket of at least `TicketLevel::BlockTicking`. Kept up to date by `chunk_ticket_system`.
+#[derive(Component)]
+pub struct BlockTicking;
+
+/// Marks a chunk covered by a ticket of `TicketLevel::EntityTicking`. Kept up to date by `chunk_ticket_system`.
+#[derive(Component)]
+pub struct EntityTicking;
+
+/// What added a ticket.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum TicketKind {
+    /// Added and moved by `chunk_streaming_system` for a `ChunkLoader`.
+    Loader(Entity),
+    /// Added by a script, like the spawn area or a forced chunk.
+    Script,
+    /// Added by a task that needs chunks loaded for a while.
+    Task,
+}
+
+/// Keeps a sphere of chunks loaded at a `TicketLevel`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ChunkTicket {
+    pub kind: TicketKind,
+    pub center: ChunkPos,
+    /// The radius of the sphere, in chunks.
+    pub radius: f32,
+    pub level: TicketLevel,
+    /// How long until the ticket is removed automatically. `None` for tickets that stay until they're removed.
+    pub expires_in: Option<Duration>,
+}
+
+impl ChunkTicket {
+    pub fn new(kind: TicketKind, center: ChunkPos, radius: f32, level: TicketLevel) -> Self {
+        Self { kind, center, radius, level, expires_in: None }
+    }
+
+    /// A `TicketKind::Task` ticket that is removed automatically after `duration`.
+    pub fn temporary(center: ChunkPos, radius: f32, level: TicketLevel, duration: Duration) -> Self {
+        Self { kind: TicketKind::Task, center, radius, level, expires_in: Some(duration) }
+    }
+
+    /// Calls `f` for every chunk the ticket keeps loaded, with its squared distance from the center and whether
+    /// the ticket loads it, or only keeps it from being unloaded because it's within `UNLOAD_HYSTERESIS`.
+    fn for_each_chunk(&self, mut f: impl FnMut(ChunkPos, i32, bool)) {
+        let load_distance_sq = self.radius * self.radius;
+        let keep_distance = self.radius + UNLOAD_HYSTERESIS;
+        let keep_distance_sq = keep_distance * keep_distance;
+        let radius = keep_distance.ceil() as i32;
+
+        for x in -radius..=radius {
+            for y in -radius..=radius {
+                for z in -radius..=radius {
+                    let distance_sq = x * x + y * y + z * z;
+                    if distance_sq as f32 > keep_distance_sq { continue; }
+                    f(self.center + IVec3::new(x, y, z), distance_sq, distance_sq as f32 <= load_distance_sq);
+                }
+            }
+        }
+    }
+}
+
+/// Identifies a ticket in `ChunkTickets`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct TicketId(u64);
+
+/// How many tickets cover a chunk.
+#[derive(Default)]
+struct Coverage {
+    /// Tickets that keep the chunk loaded, including tickets that only reach it with `UNLOAD_HYSTERESIS`.
+    keeping: u32,
+    /// Tickets of each `TicketLevel` that load the chunk.
+    loading: [u32; 3],
+}
+
+impl Coverage {
+    /// The highest level of any ticket loading the chunk.
+    fn level(&self) -> Option<TicketLevel> {
+        [TicketLevel::EntityTicking, TicketLevel::BlockTicking, TicketLevel::Loaded].into_iter()
+            .find(|level| self.loading[*level as usize] > 0)
+    }
+}
+
+/// Every chunk ticket, and the level of every chunk they cover.
+///
+/// Tickets never change after they're added, so `chunk_ticket_system` only has to add the chunks of new tickets to
+/// the count of tickets covering each chunk, and take away the chunks of removed ones.
+#[derive(Resource, Default)]
+pub struct ChunkTickets {
+    tickets: HashMap<TicketId, ChunkTicket>,
+    next_id: u64,
+    /// How many tickets cover each chunk. Chunks no ticket covers aren't in here.
+    coverage: HashMap<ChunkPos, Coverage>,
+    /// Failed chunks covered by a ticket, and how long until they're retried.
+    retrying: HashMap<ChunkPos, Duration>,
+    /// Tickets added since `chunk_ticket_system` last ran.
+    added: Vec<TicketId>,
+    /// Tickets removed since `chunk_ticket_system` last ran, that it had already added to `coverage`.
+    removed: Vec<ChunkTicket>,
+}
+
+impl ChunkTickets {
+    pub fn add(&mut self, ticket: ChunkTicket) -> TicketId {
+        let id = TicketId(self.next_id);
+        self.next_id += 1;
+        self.tickets.insert(id, ticket);
+        self.added.push(id);
+        id
+    }
+
+    /// Removes a ticket. Its chunks are unloaded the next time `chunk_ticket_system` runs, unless other tickets cover them.
+    pub fn remove(&mut self, id: TicketId) -> Option<ChunkTicket> {
+        let ticket = self.tickets.remove(&id)?;
+        match self.added.iter().position(|added| *added == id) {
+            Some(index) => { self.added.swap_remove(index); },
+            None => self.removed.push(ticket.clone()),
+        }
+        Some(ticket)
+    }
+
+    pub fn get(&self, id: TicketId) -> Option<&ChunkTicket> {
+        self.tickets.get(&id)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (TicketId, &ChunkTicket)> {
+        self.tickets.iter().map(|(id, ticket)| (*id, ticket))
+    }
+
+    /// The highest level of any ticket covering a chunk, or `None` if no ticket covers it.
+    /// Only updated when `chunk_ticket_system` runs.
+    pub fn get_level(&self, coord: ChunkPos) -> Option<TicketLevel> {
+        self.coverage.get(&coord)?.level()
+    }
+
+    /// Checks if blocks in a chunk should be ticked.
+    pub fn is_block_ticking(&self, coord: ChunkPos) -> bool {
+        self.get_level(coord).is_some_and(|level| level >= TicketLevel::BlockTicking)
+    }
+
+    /// Checks if entities in a chunk should be simulated.
+    pub fn is_entity_ticking(&self, coord: ChunkPos) -> bool {
+        self.get_level(coord).is_some_and(|level| level >= TicketLevel::EntityTicking)
+    }
+
+    /// Returns `true` if any ticket keeps the chunk loaded, including tickets that only reach it with `UNLOAD_HYSTERESIS`.
+    /// Only updated when `chunk_ticket_system` runs.
+    pub fn is_ticketed(&self, coord: ChunkPos) -> bool {
+        self.coverage.contains_key(&coord)
+    }
+}
+
+/// Removes expired tickets, and when tickets changed, loads the chunks that new tickets cover, nearest to a ticket first,
+/// and unloads chunks that no ticket covers anymore. Chunks that are loaded without a ticket are unloaded straight away.
+///
+/// Covered chunks that were unloaded are loaded again, and covered chunks that failed are retried after `FAILED_RETRY_DELAY`,
+/// including chunks that stayed `Ready` because only a remesh failed.
+#[allow(clippy::too_many_arguments)]
+pub(crate) fn chunk_ticket_system(
+    mut commands: Commands,
+    time: Res<Time>,
+    mut tickets: ResMut<ChunkTickets>,
+    chunk_registry: Res<Chunks>,
+    failed: Query<(), With<ChunkFailure>>,
+    mut state_events: EventReader<ChunkStateChangedEvent>,
+    mut failed_events: EventReader<ChunkFailedEvent>,
+    mut load_events: EventWriter<LoadChunkMessage>,
+    mut unload_events: EventWriter<UnloadChunkMessage>,
+    mut retry_events: EventWriter<RetryChunkMessage>,
+) {
+    let tickets = tickets.as_mut();
+
+    // Temporary tickets count down even when nothing else changes
+    let delta = time.delta();
+    let mut expired = vec![];
+    for (id, ticket) in tickets.tickets.iter_mut() {
+        if let Some(expires_in) = &mut ticket.expires_in {
+            *expires_in = expires_in.saturating_sub(delta);
+            if expires_in.is_zero() { expired.push(*id); }
+        }
+    }
+    for id in expired {
+        tickets.remove(id);
+    }
+
+    // Chunks unloaded by something other than the ticket system are loaded again if a ticket still covers them.
+    // Chunks that were just loaded are handled once the new tickets are covering their chunks.
+    let mut appeared: Vec<ChunkPos> = vec![];
+    for event in state_events.iter() {
+        if event.to == ChunkState::Absent && tickets.get_level(event.position).is_some() {
+            load_events.send(LoadChunkMessage(event.position));
+        }
+        if event.from == ChunkState::Absent {
+            appeared.push(event.position);
+        }
+    }
+
+    for event in failed_events.iter() {
+        if tickets.get_level(event.position).is_some() {
+            tickets.retrying.insert(event.position, FAILED_RETRY_DELAY);
+        }
+    }
+    let coverage = &tickets.coverage;
+    tickets.retrying.retain(|coord, wait| {
+        if coverage.get(coord).and_then(Coverage::level).is_none() { return false; }
+        *wait = wait.saturating_sub(delta);
+        if !wait.is_zero() { return true; }
+        if chunk_registry.get(*coord).entity().is_some_and(|entity| failed.contains(entity)) {
+            retry_events.send(RetryChunkMessage(*coord));
+        }
+        false
+    });
+
+    // Chunks that removed tickets were the last to keep loaded, and chunks whose level may have changed
+    let mut released: Vec<ChunkPos> = vec![];
+    let mut relevelled: HashSet<ChunkPos> = appeared.iter().copied().collect();
+    for ticket in std::mem::take(&mut tickets.removed) {
+        ticket.for_each_chunk(|coord, _, loads| {
+            let coverage = tickets.coverage.get_mut(&coord).expect("Chunks of added tickets should be covered");
+            coverage.keeping -= 1;
+            if loads {
+                coverage.loading[ticket.level as usize] -= 1;
+                relevelled.insert(coord);
+            }
+            if coverage.keeping == 0 {
+                tickets.coverage.remove(&coord);
+                released.push(coord);
+            }
+        });
+    }
+
+    // Chunks that new tickets started loading, with the squared distance to the nearest of their centers
+    let mut wanted: HashMap<ChunkPos, i32> = HashMap::new();
+    for id in std::mem::take(&mut tickets.added) {
+        let ticket = &tickets.tickets[&id];
+        ticket.for_each_chunk(|coord, distance_sq, loads| {
+            let coverage = tickets.coverage.entry(coord).or_default();
+            coverage.keeping += 1;
+            if !loads { return; }
+            coverage.loading[ticket.level as usize] += 1;
+            relevelled.insert(coord);
+            let nearest = wanted.entry(coord).or_insert(distance_sq);
+            *nearest = (*nearest).min(distance_sq);
+        });
+    }
+
+    // Unload chunks no ticket covers anymore, and chunks that were loaded without one
+    for coord in released.into_iter().chain(appeared) {
+        if tickets.coverage.contains_key(&coord) { continue; }
+        if let Some(entity) = chunk_registry.get(coord).entity() {
+            unload_events.send(UnloadChunkMessage(entity));
+        }
+    }
+
+    // Give chunks the markers of their level
+    for coord in relevelled {
+        let entity = match chunk_registry.get(coord) {
+            ChunkState::Absent | ChunkState::Unloading(_) => continue,
+            state => state.entity().unwrap(),
+        };
+        let level = tickets.get_level(coord);
+        let mut entity = commands.entity(entity);
+        match level {
+            Some(level) if level >= TicketLevel::BlockTicking => entity.insert(BlockTicking),
+            _ => entity.remove::<BlockTicking>(),
+        };
+        match level {
+            Some(TicketLevel::EntityTicking) => entity.insert(EntityTicking),
+            _ => entity.remove::<EntityTicking>(),
+        };
+    }
+
+    // Load new chunks, nearest first
+    let mut wanted: Vec<(ChunkPos, i32)> = wanted.into_iter()
+        .filter(|(coord, _)| chunk_registry.get(*coord) == ChunkState::Absent)
+        .collect();
+    wanted.sort_by_key(|(_, distance_sq)| *distance_sq);
+    for (coord, _) in wanted {
+        load_events.send(LoadChunkMessage(coord));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ticket_app() -> App {
+        let mut app = App::new();
+        app.insert_resource(Time::default());
+        app.insert_resource(Chunks::new());
+        app.init_resource::<ChunkTickets>();
+        app.add_event::<LoadChunkMessage>();
+        app.add_event::<UnloadChunkMessage>();
+        app.add_event::<RetryChunkMessage>();
+        app.add_event::<ChunkStateChangedEvent>();
+        app.add_event::<ChunkFailedEvent>();
+        app.add_system(chunk_ticket_system);
+        app
+    }
+
+    /// The chunks a ticket loads, leaving out the ones it only keeps loaded.
+    fn loaded_by(ticket: &ChunkTicket) -> HashSet<ChunkPos> {
+        let mut chunks = HashSet::new();
+        ticket.for_each_chunk(|coord, _, loads| if loads { chunks.insert(coord); });
+        chunks
+    }
+
+    #[test]
+    fn highest_level_wins() {
+        let mut app = ticket_app();
+        let spawn = ChunkTicket::new(TicketKind::Script, ChunkPos::new(0, 0, 0), 2.0, TicketLevel::Loaded);
+        let forced = ChunkTicket::new(TicketKind::Script, ChunkPos::new(2, 0, 0), 1.0, TicketLevel::EntityTicking);
+        // Chunks both tickets load are only asked for once
+        let requested = loaded_by(&spawn).union(&loaded_by(&forced)).count();
+        let mut tickets = app.world.resource_mut::<ChunkTickets>();
+        tickets.add(spawn);
+        let ticking = tickets.add(forced);
+        app.update();
+
+        let tickets = app.world.resource::<ChunkTickets>();
+        assert_eq!(tickets.get_level(ChunkPos::new(0, 0, 0)), Some(TicketLevel::Loaded));
+        assert_eq!(tickets.get_level(ChunkPos::new(2, 0, 0)), Some(TicketLevel::EntityTicking));
+        assert_eq!(tickets.get_level(ChunkPos::new(3, 0, 0)), Some(TicketLevel::EntityTicking));
+        assert_eq!(tickets.get_level(ChunkPos::new(5, 0, 0)), None);
+        assert!(!tickets.is_block_ticking(ChunkPos::new(-1, 0, 0)));
+        assert_eq!(app.world.resource::<Events<LoadChunkMessage>>().len(), requested);
+
+        app.world.resource_mut::<ChunkTickets>().remove(ticking);
+        app.update();
+        assert_eq!(app.world.resource::<ChunkTickets>().get_level(ChunkPos::new(3, 0, 0)), None);
+        assert!(app.world.resource::<ChunkTickets>().is_ticketed(ChunkPos::new(3, 0, 0)));
+
+        // A covered chunk that was unloaded by something else is asked for again, even though no ticket changed
+        app.world.resource_mut::<Events<LoadChunkMessage>>().clear();
+        let entity = Entity::from_raw(3);
+        app.world.send_event(ChunkStateChangedEvent { position: ChunkPos::new(1, 0, 0), from: ChunkState::Unloading(entity), to: ChunkState::Absent });
+        app.update();
+        let mut reader = app.world.resource::<Events<LoadChunkMessage>>().get_reader();
+        let loads: Vec<ChunkPos> = reader.iter(app.world.resource::<Events<LoadChunkMessage>>()).map(|message| message.0).collect();
+        assert_eq!(loads, vec![ChunkPos::new(1, 0, 0)]);
+
+        // Once the last ticket is gone, nothing is covered anymore
+        let remaining: Vec<TicketId> = app.world.resource::<ChunkTickets>().iter().map(|(id, _)| id).collect();
+        assert_eq!(remaining.len(), 1);
+        app.world.resource_mut::<ChunkTickets>().remove(remaining[0]);
+        app.update();
+        let tickets = app.world.resource::<ChunkTickets>();
+        assert!(tickets.coverage.is_empty());
+        assert!(!tickets.is_ticketed(ChunkPos::new(3, 0, 0)));
+    }
+
+    #[test]
+    fn chunks_follow_the_level_of_their_tickets() {
+        let mut app = ticket_app();
+        let block_ticking = app.world.resource_mut::<ChunkTickets>()
+            .add(ChunkTicket::new(TicketKind::Script, ChunkPos::new(0, 0, 0), 1.0, TicketLevel::BlockTicking));
+        app.update();
+
+        // Stands in for chunks being loaded, one of them without a ticket
+        let (covered, uncovered) = (app.world.spawn_empty().id(), app.world.spawn_empty().id());
+        for (entity, position) in [(covered, ChunkPos::new(0, 0, 0)), (uncovered, ChunkPos::new(9, 0, 0))] {
+            app.world.resource_mut::<Chunks>().transition(position, ChunkState::Queued(entity)).unwrap();
+            app.world.send_event(ChunkStateChangedEvent { position, from: ChunkState::Absent, to: ChunkState::Queued(entity) });
+        }
+        app.world.resource_mut::<Events<UnloadChunkMessage>>().clear();
+        app.update();
+        assert!(app.world.entity(covered).contains::<BlockTicking>());
+        assert!(!app.world.entity(covered).contains::<EntityTicking>());
+        let mut reader = app.world.resource::<Events<UnloadChunkMessage>>().get_reader();
+        let unloads: Vec<Entity> = reader.iter(app.world.resource::<Events<UnloadChunkMessage>>()).map(|message| message.0).collect();
+        assert_eq!(unloads, vec![uncovered]);
+
+        let entity_ticking = app.world.resource_mut::<ChunkTickets>()
+            .add(ChunkTicket::new(TicketKind::Script, ChunkPos::new(0, 0, 0), 1.0, TicketLevel::EntityTicking));
+        app.update();
+        assert!(app.world.entity(covered).contains::<EntityTicking>());
+
+        app.world.resource_mut::<ChunkTickets>().remove(entity_ticking);
+        app.update();
+        assert!(app.world.entity(covered).contains::<BlockTicking>());
+        assert!(!app.world.entity(covered).contains::<EntityTicking>());
+
+        app.world.resource_mut::<ChunkTickets>().remove(block_ticking);
+        app.world.resource_mut::<Events<UnloadChunkMessage>>().clear();
+        app.update();
+        let mut reader = app.world.resource::<Events<UnloadChunkMessage>>().get_reader();
+        let unloads: Vec<Entity> = reader.iter(app.world.resource::<Events<UnloadChunkMessage>>()).map(|message| message.0).collect();
+        assert_eq!(unloads, vec![covered]);
+    }
+}
@@ -1,7 +1,8 @@
 use std::{collections::BTreeMap, sync::{Arc, RwLock}, ops::Deref};
 use bevy::{prelude::*, utils::HashMap, render::once_cell::sync::Lazy};
-use crate::attributes::{AttributeKind, AttributeValue};
+use crate::{attributes::{AttributeKind, AttributeValue}, sync::RecoverPoison};
 use super::{BiomeId, scorer::BiomeSelectionScorer};
+use crate::world::position::ChunkPos;
 
 pub static BIOME_REGISTRY: Lazy<Arc<RwLock<BiomesInternal>>> = Lazy::new(||{Arc::new(RwLock::new(BiomesInternal::new()))});
 
@@ -14,15 +15,15 @@ impl Biomes {
             Some(display_name) => info!("Added biome {} ({:?})", name, display_name),
             None => info!("Added biome {}", name),
         }
-        self.0.write().unwrap().add_biome(name, biome);
+        self.0.write_or_recover().add_biome(name, biome);
     }
 
     pub fn add_biome_scorer(&self, scorer: impl BiomeSelectionScorer) {
-        self.0.write().unwrap().add_biome_scorer(scorer);
+        self.0.write_or_recover().add_biome_scorer(scorer);
     }
 
     fn get_biome_data(&self, id: BiomeId) -> Option<BiomeData> {
-        self.0.read().unwrap().get_biome_data(id).cloned()
+        self.0.read_or_recover().get_biome_data(id).cloned()
     }
 }
 
@@ -68,7 +69,7 @@ impl BiomesInternal {
         }
     }
 
-    pub fn calculate_biome_for_chunk(&self, pos: IVec3) -> BiomeId {
+    pub fn calculate_biome_for_chunk(&self, pos: ChunkPos) -> BiomeId {
         let mut biggest = (0.0, "none");
         for (id, biome) in &self.biomes {
             let mut current = 0.0;
@@ -1,7 +1,7 @@
 use std::{sync::{Arc, RwLock}, collections::BTreeSet, cmp::Ordering};
 use bevy::{prelude::*, render::once_cell::sync::Lazy, utils::HashMap};
 use dyn_clone::DynClone;
-use crate::world::{chunk::Chunk, block::registry::{BlockRegistryInternal, BLOCK_REGISTRY}};
+use crate::{sync::RecoverPoison, world::{chunk::{Chunk, failure::{ChunkFailure, ChunkTaskKind, catch_pass}}, block::registry::{BlockRegistryInternal, BLOCK_REGISTRY}, position::ChunkPos}};
 use super::noise::NoiseLayer;
 
 pub static WORLD_GENERATION: Lazy<Arc<RwLock<WorldGenerationInternal>>> = Lazy::new(||{Arc::new(RwLock::new(WorldGenerationInternal::new()))});
@@ -11,15 +11,15 @@ pub struct WorldGeneration(Arc<RwLock<WorldGenerationInternal>>);
 
 impl WorldGeneration {
     pub fn add_world_generator_pass(&self, pass: impl WorldGeneratorPass) {
-        self.0.write().unwrap().add_world_generator_pass(pass);
+        self.0.write_or_recover().add_world_generator_pass(pass);
     }
 
     pub fn add_noise_layer(&self, name: String, layer: impl NoiseLayer) {
-        self.0.write().unwrap().add_noise_layer(name, layer);
+        self.0.write_or_recover().add_noise_layer(name, layer);
     }
 
-    pub fn do_passes_on_chunk(&self, pos: IVec3, chunk: &mut Chunk) {
-        self.0.read().unwrap().do_passes_on_chunk(pos, chunk);
+    pub fn do_passes_on_chunk(&self, pos: ChunkPos, chunk: &mut Chunk) -> Result<(), ChunkFailure> {
+        self.0.read_or_recover().do_passes_on_chunk(pos, chunk)
     }
 }
 
@@ -55,11 +55,13 @@ impl WorldGenerationInternal {
         self.noise_layers.insert(name, Box::new(layer));
     }
 
-    pub fn do_passes_on_chunk(&self, pos: IVec3, chunk: &mut Chunk) {
-        let blocks = BLOCK_REGISTRY.read().unwrap();
+    /// Runs every pass on a chunk, in order. Stops at the first pass that panics, leaving the chunk partly generated.
+    pub fn do_passes_on_chunk(&self, pos: ChunkPos, chunk: &mut Chunk) -> Result<(), ChunkFailure> {
+        let blocks = BLOCK_REGISTRY.read_or_recover();
         for pass in &self.passes {
-            pass.0.chunk_pass(pos, &blocks, &self, chunk);
+            catch_pass(ChunkTaskKind::Generation, pass.0.name(), || pass.0.chunk_pass(pos, &blocks, &self, chunk))?;
         }
+        Ok(())
     }
 
     pub fn get_noise_layer(&self, name: &str) -> Option<&Box<dyn NoiseLayer>> {
@@ -94,7 +96,7 @@ pub trait WorldGeneratorPass: 'static + Send + Sync + DynClone {
     /// Checks if this generator pass supports a specific generation mode.
     fn supports_mode(&self, mode: WorldGenerationMode) -> bool;
     /// Does a pass over a given chunk.
-    fn chunk_pass(&self, pos: IVec3, blocks: &BlockRegistryInternal, gen: &WorldGenerationInternal, chunk: &mut Chunk);
+    fn chunk_pass(&self, pos: ChunkPos, blocks: &BlockRegistryInternal, gen: &WorldGenerationInternal, chunk: &mut Chunk);
 }
 dyn_clone::clone_trait_object!(WorldGeneratorPass);
 
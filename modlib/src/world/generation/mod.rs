@@ -17,14 +17,20 @@ use std::{
 };
 
 use super::{
-    block::{Block, BlockId},
+    block::{Block, BlockId, entity::BlockComponent, registry::BLOCK_REGISTRY},
     chunk::{
-        events::LoadChunkMessage,
-        meshing::RemeshChunkMarker,
+        events::{LoadChunkMessage, ChunkFailedEvent},
+        failure::{ChunkFailure, ChunkTaskKind, catch_pass},
+        loader::Unloading,
+        meshing::{RemeshChunkMarker, texture::{ChunkMaterial, ChunkMaterialHandle}},
         registry::{Chunks, ChunkState},
-        Chunk, CHUNK_SIZE, CHUNK_SIZE_F32, CHUNK_SIZE_I32,
+        region::{WorldSave, LoadedChunk},
+        scheduler::{ChunkTaskLimits, ChunkTaskPriorities, ChunkTaskQueue},
+        Chunk, CHUNK_SIZE, CHUNK_SIZE_I32,
     },
+    position::ChunkPos,
 };
+use crate::sync::RecoverPoison;
 
 pub mod biome;
 pub mod generator;
@@ -32,12 +38,31 @@ pub mod noise;
 
 #[derive(SystemLabel)]
 pub enum SystemLabels {
+    ChunkGenerationQueueSystem,
     ChunkGenerationDispatchSystem,
     ChunkGenerationPollingSystem,
 }
 
+/// This chunk is waiting to be generated, or has an ongoing asynchronous task generating it.
 #[derive(Component)]
-pub struct BeingGenerated(Task<Chunk>);
+pub struct BeingGenerated {
+    /// `None` while the chunk is still waiting in the queue, or after its task failed.
+    /// Generated chunks are returned as a `LoadedChunk` without any block entities.
+    task: Option<Task<Result<LoadedChunk, ChunkFailure>>>,
+    position: ChunkPos,
+}
+
+impl BeingGenerated {
+    /// The position of the chunk being generated.
+    pub fn get_position(&self) -> ChunkPos {
+        self.position
+    }
+
+    /// Returns `true` if the chunk is still waiting for a generation task to be started, or its last task failed.
+    pub fn is_queued(&self) -> bool {
+        self.task.is_none()
+    }
+}
 
 pub struct WorldGenPlugin;
 impl Plugin for WorldGenPlugin {
@@ -45,9 +70,12 @@ impl Plugin for WorldGenPlugin {
         app.init_resource::<Biomes>();
         app.init_resource::<WorldGeneration>();
 
-        app.add_startup_system(worldgen_setup_system);
+        app.add_system(generation_queue_system
+            .label(SystemLabels::ChunkGenerationQueueSystem)
+        );
         app.add_system(generation_dispatch_system
             .label(SystemLabels::ChunkGenerationDispatchSystem)
+            .after(SystemLabels::ChunkGenerationQueueSystem)
         );
         app.add_system(
             generation_polling_system
@@ -57,60 +85,131 @@ impl Plugin for WorldGenPlugin {
     }
 }
 
-#[derive(Resource)]
-struct ChunkMaterialHandle(Handle<StandardMaterial>);
+/// Spawns an entity for every chunk that should be loaded, and queues it for generation.
+fn generation_queue_system(
+    mut commands: Commands,
+    mut gen_events: EventReader<LoadChunkMessage>,
+    mut chunk_registry: ResMut<Chunks>,
+    chunk_mat: Res<ChunkMaterialHandle>,
+) {
+    for event in gen_events.iter() {
+        // Chunks that are already loaded or being loaded don't need to be loaded again
+        if chunk_registry.get(event.0) != ChunkState::Absent { continue; }
+
+        let mut bundle = MaterialMeshBundle::<ChunkMaterial>::default();
+        bundle.material = chunk_mat.0.clone();
+        bundle.transform.translation = event.0.translation();
 
-fn worldgen_setup_system(mut commands: Commands, mut assets: ResMut<Assets<StandardMaterial>>) {
-    commands.insert_resource(ChunkMaterialHandle(assets.add(StandardMaterial {
-        base_color: Color::WHITE,
-        ..default()
-    })));
+        let entity = commands.spawn((bundle, BeingGenerated { task: None, position: event.0 })).id();
+        chunk_registry.transition_or_warn(event.0, ChunkState::Queued(entity));
+    }
 }
 
+/// Chunks that are waiting for generation or being generated, and haven't failed or started unloading.
+type GeneratingChunks<'w, 's> = Query<'w, 's, (Entity, &'static mut BeingGenerated), (Without<Unloading>, Without<ChunkFailure>)>;
+
+/// Starts generation tasks for queued chunks, most important first, within the limits set by `ChunkTaskLimits`.
 fn generation_dispatch_system(
-    mut commands: Commands,
-    mut gen_events: EventReader<LoadChunkMessage>,
+    limits: Res<ChunkTaskLimits>,
+    priorities: ChunkTaskPriorities,
+    world_save: Option<Res<WorldSave>>,
     mut chunk_registry: ResMut<Chunks>,
-    chunk_mat: Res<ChunkMaterialHandle>,
+    mut query: GeneratingChunks,
 ) {
+    let in_flight = query.iter().filter(|(_, chunk)| !chunk.is_queued()).count();
+    let available = limits.available_generation_tasks(in_flight);
+    if available == 0 { return; }
+
+    let queued: ChunkTaskQueue<Entity> = query.iter()
+        .filter(|(_, chunk)| chunk.is_queued())
+        .map(|(entity, chunk)| (entity, priorities.get(chunk.position)))
+        .collect();
+
     let task_pool = AsyncComputeTaskPool::get();
-    for event in gen_events.iter() {
-        let chunk_position = event.0.clone();
+    for entity in queued.take(available) {
+        let mut chunk = query.get_mut(entity).unwrap().1;
+        let chunk_position = chunk.position;
+        let world_save = world_save.as_deref().cloned();
+        chunk_registry.transition_or_warn(chunk_position, ChunkState::Generating(entity));
 
         // Async task definition
-        let task: Task<Chunk> = task_pool.spawn(async move {
-            let mut chunk = Chunk::new(chunk_position.into());
-            WORLD_GENERATION.read().unwrap().do_passes_on_chunk(chunk_position, &mut chunk);
+        chunk.task = Some(task_pool.spawn(async move {
+            // Saved chunks are loaded instead of being generated again
+            if let Some(world_save) = world_save {
+                match world_save.load_chunk(chunk_position) {
+                    Ok(Some(mut loaded)) => {
+                        // Heightmaps and light aren't saved, since they can always be worked out from the blocks
+                        finish_chunk(&mut loaded.chunk)?;
+                        return Ok(loaded);
+                    },
+                    Ok(None) => {},
+                    Err(error) => error!("Failed to load chunk {chunk_position}, generating it instead: {error}"),
+                }
+            }
 
-            chunk
-        });
-
-        let mut pbr = PbrBundle::default();
-        pbr.material = chunk_mat.0.clone();
-        pbr.transform.translation = Vec3 {
-            x: CHUNK_SIZE_F32 * event.0.x as f32,
-            y: CHUNK_SIZE_F32 * event.0.y as f32,
-            z: CHUNK_SIZE_F32 * event.0.z as f32,
-        };
+            let mut chunk = Chunk::new(chunk_position);
+            chunk.set_recording_changes(false);
+            WORLD_GENERATION.read_or_recover().do_passes_on_chunk(chunk_position, &mut chunk)?;
+            chunk.shrink_storage();
+            // Generated chunks can be generated again, so they don't need saving until they're changed
+            chunk.clear_modified();
+            finish_chunk(&mut chunk)?;
 
-        commands.spawn((pbr, BeingGenerated(task)));
-        chunk_registry.set(event.0.into(), ChunkState::BeingGenerated);
+            Ok(LoadedChunk { chunk, block_entities: vec![] })
+        }));
     }
 }
 
+/// Works out the heightmaps and light of a generated or loaded chunk. Runs like a pass, so a panic only fails the chunk.
+fn finish_chunk(chunk: &mut Chunk) -> Result<(), ChunkFailure> {
+    let registry = BLOCK_REGISTRY.read_or_recover();
+    catch_pass(ChunkTaskKind::Generation, "engine_heightmaps", || chunk.update_heightmaps(&registry))?;
+    catch_pass(ChunkTaskKind::Generation, "engine_light", || chunk.init_light(&registry))
+}
+
 fn generation_polling_system(
     mut commands: Commands,
     mut chunk_registry: ResMut<Chunks>,
-    mut query: Query<(Entity, &mut BeingGenerated)>,
+    mut failed_events: EventWriter<ChunkFailedEvent>,
+    mut query: Query<(Entity, &mut BeingGenerated), Without<Unloading>>,
 ) {
-    for (entity, mut chunk) in query.iter_mut() {
-        if let Some(chunk) = future::block_on(future::poll_once(&mut chunk.0)) {
-            chunk_registry.set(chunk.get_position(), ChunkState::Present(entity));
-            commands
-                .entity(entity)
-                .remove::<BeingGenerated>()
-                .insert(chunk)
-                .insert(RemeshChunkMarker);
+    let registry = BLOCK_REGISTRY.read_or_recover();
+    for (entity, mut being_generated) in query.iter_mut() {
+        let task = match being_generated.task.as_mut() {
+            Some(task) => task,
+            None => continue,
+        };
+        match future::block_on(future::poll_once(task)) {
+            Some(Ok(LoadedChunk { mut chunk, block_entities })) => {
+                // Block entities from saved chunks are spawned again, and get their `BlockPosition` as they're placed.
+                // Nothing has seen the chunk yet, so placing them isn't sent as a block change.
+                chunk.set_recording_changes(false);
+                for (position, block) in block_entities {
+                    let block_entity = commands.spawn(BlockComponent(block)).id();
+                    let (x, y, z) = position.indices();
+                    chunk.set_block(x, y, z, Block::Entity(block_entity));
+                }
+                chunk.clear_modified();
+                chunk.set_recording_changes(true);
+                chunk.update_heightmaps(&registry);
+
+                chunk_registry.transition_or_warn(chunk.get_position(), ChunkState::Generated(entity));
+                commands
+                    .entity(entity)
+                    .remove::<BeingGenerated>()
+                    .insert(chunk)
+                    .insert(RemeshChunkMarker);
+            },
+            Some(Err(failure)) => {
+                // The entity keeps its `BeingGenerated`, so it can be queued again when it's retried
+                let position = being_generated.position;
+                being_generated.task = None;
+                error!("Failed to generate chunk {position}: {failure}");
+                chunk_registry.transition_or_warn(position, ChunkState::Failed(entity));
+                commands.entity(entity).insert(failure.clone());
+                failed_events.send(ChunkFailedEvent { position, entity, failure });
+            },
+            None => {},
         }
     }
 }
@@ -125,7 +224,7 @@ pub trait WorldGenExtensionFns {
 impl WorldGenExtensionFns for App {
     /// Adds a new biome type. Shorthand for
     /// ```rs
-    /// BIOME_REGISTRY.write().unwrap().add_biome()
+    /// BIOME_REGISTRY.write_or_recover().add_biome()
     /// ```
     fn add_biome(&mut self, name: BiomeId, biome: BiomeData) -> &mut Self {
         self.add_startup_system(move |biomes: Res<Biomes>| {
@@ -137,7 +236,7 @@ impl WorldGenExtensionFns for App {
 
     /// Adds a new `BiomeSelectionScorer` for biome selection. Shorthand for
     /// ```rs
-    /// BIOME_REGISTRY.write().unwrap().add_biome_scorer()
+    /// BIOME_REGISTRY.write_or_recover().add_biome_scorer()
     /// ```
     fn add_biome_scorer(&mut self, scorer: impl BiomeSelectionScorer) -> &mut Self {
         self.add_startup_system(move |biomes: Res<Biomes>| {
@@ -149,7 +248,7 @@ impl WorldGenExtensionFns for App {
 
     /// Adds a new `WorldGeneratorPass` for chunk generation. Shorthand for
     /// ```rs
-    /// WORLD_GENERATION.write().unwrap().add_world_generator_pass()
+    /// WORLD_GENERATION.write_or_recover().add_world_generator_pass()
     /// ```
     fn add_world_generator_pass(&mut self, pass: impl WorldGeneratorPass) -> &mut Self {
         self.add_startup_system(move |world_generation: Res<WorldGeneration>| {
@@ -161,7 +260,7 @@ impl WorldGenExtensionFns for App {
 
     /// Adds a new `NoiseLayer` to the chunk generation system. Shorthand for
     /// ```rs
-    /// WORLD_GENERATION.write().unwrap().add_noise_layer()
+    /// WORLD_GENERATION.write_or_recover().add_noise_layer()
     fn add_noise_layer(&mut self, key: String, layer: impl NoiseLayer) -> &mut Self {
         self.add_startup_system(move |world_generation: Res<WorldGeneration>| {
             world_generation.add_noise_layer(key.clone(), dyn_clone::clone(&layer));
@@ -1,13 +1,15 @@
 //! World map systems and traits.
 
+use std::collections::BTreeMap;
 use bevy::{prelude::*, ecs::system::SystemParam};
-use crate::debug::{DebugMenuOpen, AppendDebugMenuMessage};
+use crate::{sync::RecoverPoison, debug::{DebugMenuOpen, AppendDebugMenuMessage}};
 use self::{
-    chunk::{registry::{Chunks, ChunkCoordinate, ChunkState}, Chunk, meshing::BeingRemeshed},
+    chunk::{registry::Chunks, Chunk, snapshot::ChunkSnapshot, meshing::BeingRemeshed, events::BlockChangeCause, heightmap::HeightmapKind, CHUNK_SIZE_I32},
+    position::{BlockPos, ChunkPos, LocalPos},
     block::{
         entity::BlockComponent,
         Block,
-        registry::Blocks,
+        registry::{Blocks, BLOCK_REGISTRY},
     },
     generation::biome::registry::{BiomesInternal, Biomes},
 };
@@ -15,6 +17,7 @@ use self::{
 pub mod block;
 pub mod chunk;
 pub mod generation;
+pub mod position;
 
 /// Helpful tools and shortcuts for manipulating the world.
 #[derive(SystemParam)]
@@ -28,28 +31,160 @@ pub struct WorldMapHelpers<'w, 's> {
 
 impl WorldMapHelpers<'_, '_> {
     /// Gets a block from any coordinates in the world. Returns `Some` if the chunk is loaded, `None` if not.
-    pub fn get_block(&self, pos: IVec3) -> Option<Block> {
-        let chunk_offset = pos / 16;
-        let block_offset = (pos + IVec3::splat(16)) % 16;
+    pub fn get_block(&self, pos: BlockPos) -> Option<Block> {
+        let (chunk, local) = pos.split();
+        let (x, y, z) = local.indices();
 
-        let chunk = match self.chunk_registry.get(chunk_offset.into()) {
-            ChunkState::Present(entity) => entity,
-            _ => { return None }
-        };
+        let chunk = self.get_chunk(chunk)?;
+        Some(chunk.get_block(x, y, z).clone())
+    }
+
+    /// Gets the block entity at any coordinates in the world, if the chunk is loaded and there is one.
+    pub fn get_block_entity(&self, pos: BlockPos) -> Option<Entity> {
+        let (chunk, local) = pos.split();
+        let (x, y, z) = local.indices();
+
+        self.get_chunk(chunk)?.get_block_entity(x, y, z)
+    }
+
+    /// The world y coordinate of the highest block of a kind in a column, looking through every loaded chunk stacked in that column.
+    /// Returns `None` if no loaded chunk in the column has a matching block.
+    pub fn surface_height(&self, x: i32, z: i32, kind: HeightmapKind) -> Option<i32> {
+        let (column, local) = BlockPos::new(x, 0, z).split();
+        let registry = BLOCK_REGISTRY.read_or_recover();
+
+        self.chunk_registry.column(column.x, column.z)
+            .rev()
+            .find_map(|(coord, _)| {
+                let height = self.get_chunk(coord)?.get_height(&registry, kind, local.x as usize, local.z as usize)?;
+                Some(coord.origin().y + height as i32)
+            })
+    }
+
+    pub fn get_chunk(&self, coord: ChunkPos) -> Option<&Chunk> {
+        // The chunk component is only inserted once commands are applied, so it can be missing right after generation
+        let entity = self.chunk_registry.get(coord).loaded_entity()?;
+        self.chunks.get(entity).ok().map(|query_result| query_result.1)
+    }
+
+    /// Takes a snapshot of a loaded chunk, which can be moved into an asynchronous task.
+    /// Use `get_chunk_snapshots` for a chunk and its neighbours.
+    pub fn get_chunk_snapshot(&self, coord: ChunkPos) -> Option<ChunkSnapshot> {
+        self.get_chunk(coord).map(Chunk::snapshot)
+    }
+
+    /// Takes snapshots of a chunk and its six neighbours, in the order: the chunk itself, left, right, up, down, forward, back.
+    pub fn get_chunk_snapshots(&self, coord: ChunkPos) -> [Option<ChunkSnapshot>; 7] {
+        [IVec3::ZERO, IVec3::NEG_X, IVec3::X, IVec3::Y, IVec3::NEG_Y, IVec3::Z, IVec3::NEG_Z]
+            .map(|offset| self.get_chunk_snapshot(coord + offset))
+    }
+}
+
+/// Mutable counterpart to `WorldMapHelpers`, for changing blocks at any coordinates in the world.
+///
+/// Blocks in chunks that aren't loaded are skipped. Setting a block to what it already is doesn't count as a change,
+/// and chunks where nothing changed aren't marked as changed, so `ChunkModifiedEvent` is sent once for every chunk that was actually modified.
+/// Every changed block is also sent in a `BlockChangedEvent` with the given cause.
+#[derive(SystemParam)]
+pub struct WorldMapHelpersMut<'w, 's> {
+    pub chunk_registry: Res<'w, Chunks>,
+    pub chunks: Query<'w, 's, &'static mut Chunk>,
+}
+
+impl WorldMapHelpersMut<'_, '_> {
+    /// Gets a block from any coordinates in the world. Returns `Some` if the chunk is loaded, `None` if not.
+    pub fn get_block(&self, pos: BlockPos) -> Option<Block> {
+        let (chunk, local) = pos.split();
+        let (x, y, z) = local.indices();
 
-        let chunk = self.chunks.get(chunk).expect("Chunk was in registry but not in query!").1;
-        Some(chunk.get_block(block_offset.x as usize, block_offset.y as usize, block_offset.z as usize).clone())
+        let entity = self.chunk_registry.get(chunk).loaded_entity()?;
+        self.chunks.get(entity).ok().map(|chunk| chunk.get_block(x, y, z))
     }
 
-    pub fn get_chunk(&self, coord: ChunkCoordinate) -> Option<&Chunk> {
-        match self.chunk_registry.get(coord) {
-            ChunkState::Present(entity) => {
-                match self.chunks.get(entity) {
-                    Ok(query_result) => Some(query_result.1),
-                    Err(_) => None,
+    /// Sets a block at any coordinates in the world. Returns `true` if the block changed.
+    pub fn set_block(&mut self, pos: BlockPos, to: Block, cause: BlockChangeCause) -> bool {
+        self.set_many([(pos, to)], cause) == 1
+    }
+
+    /// Sets every block in the box between `min` and `max`, inclusive, to `to`. Returns how many blocks changed.
+    pub fn fill_region(&mut self, min: BlockPos, max: BlockPos, to: Block, cause: BlockChangeCause) -> usize {
+        self.edit_region(min, max, cause, |_| Some(to))
+    }
+
+    /// Replaces every `from` block in the box between `min` and `max`, inclusive, with `to`. Returns how many blocks changed.
+    pub fn replace_in_region(&mut self, min: BlockPos, max: BlockPos, from: Block, to: Block, cause: BlockChangeCause) -> usize {
+        self.edit_region(min, max, cause, |block| (block == from).then_some(to))
+    }
+
+    /// Sets many blocks at once, anywhere in the world. Returns how many blocks changed.
+    pub fn set_many(&mut self, blocks: impl IntoIterator<Item = (BlockPos, Block)>, cause: BlockChangeCause) -> usize {
+        let mut by_chunk: BTreeMap<ChunkPos, Vec<(LocalPos, Block)>> = BTreeMap::new();
+        for (pos, block) in blocks {
+            let (chunk, local) = pos.split();
+            by_chunk.entry(chunk).or_default().push((local, block));
+        }
+
+        let mut changed = 0;
+        for (chunk, blocks) in by_chunk {
+            let mut chunk = match self.get_chunk_mut(chunk) {
+                Some(chunk) => chunk,
+                None => continue,
+            };
+            for (local, block) in blocks {
+                let (x, y, z) = local.indices();
+                if chunk.get_block(x, y, z) == block { continue; }
+                chunk.set_block_with_cause(x, y, z, block, cause);
+                changed += 1;
+            }
+        }
+
+        changed
+    }
+
+    /// Calls `edit` for every block in the box between `min` and `max`, inclusive, and sets the block to what it returns, if anything.
+    fn edit_region(&mut self, min: BlockPos, max: BlockPos, cause: BlockChangeCause, mut edit: impl FnMut(Block) -> Option<Block>) -> usize {
+        let (min, max) = (IVec3::from(min).min(max.into()), IVec3::from(min).max(max.into()));
+        let (min_chunk, max_chunk) = (BlockPos::from(min).chunk(), BlockPos::from(max).chunk());
+
+        let mut changed = 0;
+        for cx in min_chunk.x..=max_chunk.x {
+            for cy in min_chunk.y..=max_chunk.y {
+                for cz in min_chunk.z..=max_chunk.z {
+                    let chunk_pos = ChunkPos::new(cx, cy, cz);
+                    let mut chunk = match self.get_chunk_mut(chunk_pos) {
+                        Some(chunk) => chunk,
+                        None => continue,
+                    };
+
+                    // The part of the box inside this chunk, in local coordinates
+                    let origin = IVec3::from(chunk_pos.origin());
+                    let local_min = (min - origin).max(IVec3::ZERO).as_uvec3();
+                    let local_max = (max - origin).min(IVec3::splat(CHUNK_SIZE_I32 - 1)).as_uvec3();
+
+                    for x in local_min.x..=local_max.x {
+                        for y in local_min.y..=local_max.y {
+                            for z in local_min.z..=local_max.z {
+                                let (x, y, z) = (x as usize, y as usize, z as usize);
+                                let block = chunk.get_block(x, y, z);
+                                let to = match edit(block) {
+                                    Some(to) if to != block => to,
+                                    _ => continue,
+                                };
+                                chunk.set_block_with_cause(x, y, z, to, cause);
+                                changed += 1;
+                            }
+                        }
+                    }
                 }
-            },
-            _ => None,
+            }
         }
+
+        changed
+    }
+
+    /// Gets a loaded chunk for editing. The chunk is only marked as changed once it's mutably dereferenced.
+    fn get_chunk_mut(&mut self, coord: ChunkPos) -> Option<Mut<'_, Chunk>> {
+        let entity = self.chunk_registry.get(coord).loaded_entity()?;
+        self.chunks.get_mut(entity).ok()
     }
-}
\ No newline at end of file
+}
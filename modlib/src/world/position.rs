@@ -0,0 +1,198 @@
+//! Coordinate types for blocks and chunks.
+//!
+//! There are three kinds of coordinates in the world:
+//! - `BlockPos` is the position of a block in the world.
+//! - `ChunkPos` is the position of a chunk, in chunks. Chunk `(1, 0, 0)` starts at block `(CHUNK_SIZE, 0, 0)`.
+//! - `LocalPos` is the position of a block inside its chunk, from `0` to `CHUNK_SIZE - 1` on each axis.
+//!
+//! Converting a block position to a chunk position uses floor division, so block `-1` is in chunk `-1` at local position `CHUNK_SIZE - 1`.
+
+use std::{fmt::Display, ops::{Add, Sub}};
+use bevy::prelude::{IVec3, Vec3};
+use super::chunk::{CHUNK_SIZE, CHUNK_SIZE_F32, CHUNK_SIZE_I32, CHUNK_SIZE_U8};
+
+/// The position of a block in the world.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct BlockPos {
+    pub x: i32,
+    pub y: i32,
+    pub z: i32,
+}
+
+impl BlockPos {
+    pub const fn new(x: i32, y: i32, z: i32) -> Self {
+        Self { x, y, z }
+    }
+
+    /// The block containing a point in world space.
+    pub fn containing(point: Vec3) -> Self {
+        point.floor().as_ivec3().into()
+    }
+
+    /// Creates a block position from the chunk it's in and its position inside that chunk.
+    pub fn from_parts(chunk: ChunkPos, local: LocalPos) -> Self {
+        chunk.origin() + IVec3::new(local.x as i32, local.y as i32, local.z as i32)
+    }
+
+    /// The chunk this block is in.
+    pub fn chunk(&self) -> ChunkPos {
+        ChunkPos::new(
+            self.x.div_euclid(CHUNK_SIZE_I32),
+            self.y.div_euclid(CHUNK_SIZE_I32),
+            self.z.div_euclid(CHUNK_SIZE_I32),
+        )
+    }
+
+    /// The position of this block inside its chunk.
+    pub fn local(&self) -> LocalPos {
+        LocalPos::new(
+            self.x.rem_euclid(CHUNK_SIZE_I32) as u8,
+            self.y.rem_euclid(CHUNK_SIZE_I32) as u8,
+            self.z.rem_euclid(CHUNK_SIZE_I32) as u8,
+        )
+    }
+
+    /// The chunk this block is in, and its position inside that chunk.
+    pub fn split(&self) -> (ChunkPos, LocalPos) {
+        (self.chunk(), self.local())
+    }
+}
+
+/// The position of a chunk, in chunks.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct ChunkPos {
+    pub x: i32,
+    pub y: i32,
+    pub z: i32,
+}
+
+impl ChunkPos {
+    pub const fn new(x: i32, y: i32, z: i32) -> Self {
+        Self { x, y, z }
+    }
+
+    /// The chunk containing a point in world space.
+    pub fn containing(point: Vec3) -> Self {
+        BlockPos::containing(point).chunk()
+    }
+
+    /// The position of the block at local position `(0, 0, 0)` in this chunk.
+    pub fn origin(&self) -> BlockPos {
+        BlockPos::new(self.x * CHUNK_SIZE_I32, self.y * CHUNK_SIZE_I32, self.z * CHUNK_SIZE_I32)
+    }
+
+    /// The position of a block in this chunk.
+    pub fn block(&self, local: LocalPos) -> BlockPos {
+        BlockPos::from_parts(*self, local)
+    }
+
+    /// The world space position of this chunk's corner, used as the translation of the chunk's entity.
+    pub fn translation(&self) -> Vec3 {
+        IVec3::from(*self).as_vec3() * CHUNK_SIZE_F32
+    }
+
+    /// The world space position of the center of this chunk.
+    pub fn center(&self) -> Vec3 {
+        self.translation() + Vec3::splat(CHUNK_SIZE_F32 * 0.5)
+    }
+}
+
+/// The position of a block inside a chunk. Each axis is less than `CHUNK_SIZE`.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct LocalPos {
+    pub x: u8,
+    pub y: u8,
+    pub z: u8,
+}
+
+impl LocalPos {
+    /// Creates a new local position. Panics if any axis is outside the chunk.
+    pub const fn new(x: u8, y: u8, z: u8) -> Self {
+        assert!(x < CHUNK_SIZE_U8 && y < CHUNK_SIZE_U8 && z < CHUNK_SIZE_U8, "local position outside of chunk");
+        Self { x, y, z }
+    }
+
+    /// Creates a new local position from the `usize` indices used by `Chunk`. Panics if any axis is outside the chunk.
+    pub fn from_indices(x: usize, y: usize, z: usize) -> Self {
+        assert!(x < CHUNK_SIZE && y < CHUNK_SIZE && z < CHUNK_SIZE, "local position outside of chunk");
+        Self { x: x as u8, y: y as u8, z: z as u8 }
+    }
+
+    /// The position as `usize` indices, as used by `Chunk::get_block` and `Chunk::set_block`.
+    pub fn indices(&self) -> (usize, usize, usize) {
+        (self.x as usize, self.y as usize, self.z as usize)
+    }
+}
+
+macro_rules! impl_world_pos {
+    ($type:ident) => {
+        impl From<IVec3> for $type {
+            fn from(value: IVec3) -> Self {
+                Self::new(value.x, value.y, value.z)
+            }
+        }
+
+        impl From<$type> for IVec3 {
+            fn from(value: $type) -> Self {
+                IVec3::new(value.x, value.y, value.z)
+            }
+        }
+
+        impl From<(i32, i32, i32)> for $type {
+            fn from(value: (i32, i32, i32)) -> Self {
+                Self::new(value.0, value.1, value.2)
+            }
+        }
+
+        impl Add<IVec3> for $type {
+            type Output = Self;
+
+            fn add(self, rhs: IVec3) -> Self {
+                Self::new(self.x + rhs.x, self.y + rhs.y, self.z + rhs.z)
+            }
+        }
+
+        impl Sub<IVec3> for $type {
+            type Output = Self;
+
+            fn sub(self, rhs: IVec3) -> Self {
+                Self::new(self.x - rhs.x, self.y - rhs.y, self.z - rhs.z)
+            }
+        }
+
+        impl Display for $type {
+            fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+                write!(f, "({}, {}, {})", self.x, self.y, self.z)
+            }
+        }
+    };
+}
+
+impl_world_pos!(BlockPos);
+impl_world_pos!(ChunkPos);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn negative_positions_use_floor_division() {
+        let size = CHUNK_SIZE_I32;
+        for (block, chunk, local) in [
+            (0, 0, 0),
+            (size - 1, 0, size - 1),
+            (size, 1, 0),
+            (-1, -1, size - 1),
+            (-size, -1, 0),
+            (-size - 1, -2, size - 1),
+            (-2 * size, -2, 0),
+        ] {
+            let pos = BlockPos::new(block, block, block);
+            assert_eq!(pos.chunk(), ChunkPos::new(chunk, chunk, chunk));
+            assert_eq!(pos.local(), LocalPos::new(local as u8, local as u8, local as u8));
+            assert_eq!(BlockPos::from_parts(pos.chunk(), pos.local()), pos);
+        }
+
+        assert_eq!(BlockPos::containing(Vec3::new(-0.5, 0.5, -16.5)), BlockPos::new(-1, 0, -17));
+    }
+}